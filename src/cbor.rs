@@ -0,0 +1,374 @@
+//! Minimal CBOR (RFC 8949) support for manifests and management protocols.
+//!
+//! Only definite length items are supported, which is what deterministic
+//! encoders produce. Decoding works on slices and never copies data.
+
+use core::convert::TryFrom;
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_NEGATIVE: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+const MAJOR_SIMPLE: u8 = 7;
+
+pub const SIMPLE_FALSE: u8 = 20;
+pub const SIMPLE_TRUE: u8 = 21;
+pub const SIMPLE_NULL: u8 = 22;
+
+/// Limits the nesting `skip` follows, protects the stack.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Item<'a>
+{
+    Unsigned(u64),
+    /// The (negative) value of a major type 1 item.
+    Negative(i64),
+    Bytes(&'a [u8]),
+    Text(&'a [u8]),
+    /// Number of elements, which follow the item.
+    Array(usize),
+    /// Number of key/value pairs, which follow the item.
+    Map(usize),
+    Tag(u64),
+    Simple(u8)
+}
+
+pub struct Decoder<'a>
+{
+    data: &'a [u8],
+    pos: usize
+}
+
+impl <'a> Decoder<'a>
+{
+    pub fn new(data: &'a [u8]) -> Self
+    {
+        Self { data, pos: 0 }
+    }
+
+    /// Offset of the next item in the data.
+    pub fn position(&self) -> usize
+    {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.pos >= self.data.len()
+    }
+
+    fn header(&mut self) -> Option<(u8, u64)>
+    {
+        let initial = *self.data.get(self.pos)?;
+        let major = initial >> 5;
+        let info = initial & 0x1F;
+        let arg_len = match info
+        {
+            0..=23 => 0,
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            // Reserved or indefinite length
+            _ => return None
+        };
+
+        let arg = match arg_len
+        {
+            0 => info as u64,
+            _ =>
+            {
+                let bytes = self.data.get(self.pos + 1..self.pos + 1 + arg_len)?;
+                bytes.iter().fold(0u64, |value, byte| value << 8 | *byte as u64)
+            }
+        };
+        self.pos += 1 + arg_len;
+        Some((major, arg))
+    }
+
+    /// Decodes the header of a byte string and advances past the header
+    /// only. Yields the length of the string, which may extend beyond the
+    /// end of the data (i.e. the string is not needed in memory).
+    pub fn bytes_header(&mut self) -> Option<usize>
+    {
+        let start = self.pos;
+        match self.header()?
+        {
+            (MAJOR_BYTES, len) => usize::try_from(len).ok(),
+            _ =>
+            {
+                self.pos = start;
+                None
+            }
+        }
+    }
+
+    /// Moves the decoder `len` bytes forward.
+    pub fn advance(&mut self, len: usize)
+    {
+        self.pos = self.pos.saturating_add(len);
+    }
+
+    pub fn item(&mut self) -> Option<Item<'a>>
+    {
+        let (major, arg) = self.header()?;
+        let item = match major
+        {
+            MAJOR_UNSIGNED => Item::Unsigned(arg),
+            MAJOR_NEGATIVE if arg <= i64::MAX as u64 => Item::Negative(-1 - arg as i64),
+            MAJOR_BYTES | MAJOR_TEXT =>
+            {
+                let len = usize::try_from(arg).ok()?;
+                let value = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+                self.pos += len;
+                if major == MAJOR_BYTES { Item::Bytes(value) } else { Item::Text(value) }
+            }
+            MAJOR_ARRAY => Item::Array(arg as usize),
+            MAJOR_MAP => Item::Map(arg as usize),
+            MAJOR_TAG => Item::Tag(arg),
+            MAJOR_SIMPLE if arg < 256 => Item::Simple(arg as u8),
+            _ => return None
+        };
+        Some(item)
+    }
+
+    /// Skips one complete item, including all nested items.
+    pub fn skip(&mut self) -> Option<()>
+    {
+        self.skip_nested(0)
+    }
+
+    fn skip_nested(&mut self, depth: usize) -> Option<()>
+    {
+        if depth > MAX_DEPTH
+        {
+            return None;
+        }
+
+        let children = match self.item()?
+        {
+            Item::Array(len) => len,
+            Item::Map(len) => len.checked_mul(2)?,
+            Item::Tag(_) => 1,
+            _ => 0
+        };
+        for _ in 0..children
+        {
+            self.skip_nested(depth + 1)?;
+        }
+        Some(())
+    }
+
+    pub fn unsigned(&mut self) -> Option<u64>
+    {
+        match self.item()?
+        {
+            Item::Unsigned(value) => Some(value),
+            _ => None
+        }
+    }
+
+    /// Decodes an integer of either sign.
+    pub fn integer(&mut self) -> Option<i64>
+    {
+        match self.item()?
+        {
+            Item::Unsigned(value) if value <= i64::MAX as u64 => Some(value as i64),
+            Item::Negative(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn bytes(&mut self) -> Option<&'a [u8]>
+    {
+        match self.item()?
+        {
+            Item::Bytes(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn text(&mut self) -> Option<&'a [u8]>
+    {
+        match self.item()?
+        {
+            Item::Text(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn array(&mut self) -> Option<usize>
+    {
+        match self.item()?
+        {
+            Item::Array(len) => Some(len),
+            _ => None
+        }
+    }
+
+    pub fn map(&mut self) -> Option<usize>
+    {
+        match self.item()?
+        {
+            Item::Map(len) => Some(len),
+            _ => None
+        }
+    }
+}
+
+/// Encodes into a caller provided buffer. Once an item does not fit, the
+/// encoder stays in the overflow state and `finish` yields None.
+pub struct Encoder<'a>
+{
+    buf: &'a mut [u8],
+    len: usize,
+    overflow: bool
+}
+
+impl <'a> Encoder<'a>
+{
+    pub fn new(buf: &'a mut [u8]) -> Self
+    {
+        Self { buf, len: 0, overflow: false }
+    }
+
+    fn put(&mut self, data: &[u8])
+    {
+        match self.buf.get_mut(self.len..self.len + data.len())
+        {
+            Some(destination) if !self.overflow =>
+            {
+                destination.copy_from_slice(data);
+                self.len += data.len();
+            }
+            _ => self.overflow = true
+        }
+    }
+
+    fn header(&mut self, major: u8, arg: u64) -> &mut Self
+    {
+        let major = major << 5;
+        match arg
+        {
+            0..=23 => self.put(&[major | arg as u8]),
+            24..=0xFF => self.put(&[major | 24, arg as u8]),
+            0x100..=0xFFFF => { self.put(&[major | 25]); self.put(&(arg as u16).to_be_bytes()) },
+            0x1_0000..=0xFFFF_FFFF => { self.put(&[major | 26]); self.put(&(arg as u32).to_be_bytes()) },
+            _ => { self.put(&[major | 27]); self.put(&arg.to_be_bytes()) }
+        }
+        self
+    }
+
+    pub fn unsigned(&mut self, value: u64) -> &mut Self
+    {
+        self.header(MAJOR_UNSIGNED, value)
+    }
+
+    pub fn integer(&mut self, value: i64) -> &mut Self
+    {
+        if value < 0
+        {
+            self.header(MAJOR_NEGATIVE, (-1 - value) as u64)
+        }
+        else
+        {
+            self.header(MAJOR_UNSIGNED, value as u64)
+        }
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self
+    {
+        self.header(MAJOR_BYTES, value.len() as u64);
+        self.put(value);
+        self
+    }
+
+    pub fn text(&mut self, value: &str) -> &mut Self
+    {
+        self.header(MAJOR_TEXT, value.len() as u64);
+        self.put(value.as_bytes());
+        self
+    }
+
+    pub fn array(&mut self, len: usize) -> &mut Self
+    {
+        self.header(MAJOR_ARRAY, len as u64)
+    }
+
+    pub fn map(&mut self, len: usize) -> &mut Self
+    {
+        self.header(MAJOR_MAP, len as u64)
+    }
+
+    pub fn tag(&mut self, tag: u64) -> &mut Self
+    {
+        self.header(MAJOR_TAG, tag)
+    }
+
+    pub fn bool(&mut self, value: bool) -> &mut Self
+    {
+        self.header(MAJOR_SIMPLE, if value { SIMPLE_TRUE } else { SIMPLE_FALSE } as u64)
+    }
+
+    /// Appends already encoded data.
+    pub fn raw(&mut self, data: &[u8]) -> &mut Self
+    {
+        self.put(data);
+        self
+    }
+
+    /// Yields the number of bytes written, None if the buffer was too small.
+    pub fn finish(&self) -> Option<usize>
+    {
+        if self.overflow { None } else { Some(self.len) }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[test]
+    fn can_decode_rfc8949_examples()
+    {
+        // [1, -16, h'0102', "a", {1: 1000000}, 23(5)]
+        let data = [0x86, 0x01, 0x2F, 0x42, 0x01, 0x02, 0x61, 0x61, 0xA1, 0x01, 0x1A, 0x00, 0x0F, 0x42, 0x40, 0xD7, 0x05];
+        let mut decoder = Decoder::new(&data);
+        assert_eq!(decoder.array(), Some(6));
+        assert_eq!(decoder.unsigned(), Some(1));
+        assert_eq!(decoder.integer(), Some(-16));
+        assert_eq!(decoder.bytes(), Some(&[1u8, 2][..]));
+        assert_eq!(decoder.text(), Some(&b"a"[..]));
+        let position = decoder.position();
+        assert_eq!(decoder.skip(), Some(()));
+        assert_eq!(decoder.position(), position + 7);
+        assert_eq!(decoder.item(), Some(Item::Tag(23)));
+        assert_eq!(decoder.unsigned(), Some(5));
+        assert!(decoder.is_empty());
+
+        // Truncated and indefinite length items
+        assert_eq!(Decoder::new(&[0x43, 0x01]).item(), None);
+        assert_eq!(Decoder::new(&[0x9F, 0xFF]).item(), None);
+        assert_eq!(Decoder::new(&[0x5A, 0x00, 0x01, 0x00, 0x00]).bytes_header(), Some(0x10000));
+    }
+
+    #[test]
+    fn encoder_output_decodes()
+    {
+        let mut buf = [0u8; 32];
+        let mut encoder = Encoder::new(&mut buf);
+        encoder.map(2).text("rc").unsigned(0).text("off").integer(-500).bool(true);
+        let len = encoder.finish().unwrap();
+        assert_eq!(buf[..len], [0xA2, 0x62, b'r', b'c', 0x00, 0x63, b'o', b'f', b'f', 0x39, 0x01, 0xF3, 0xF5]);
+
+        let mut small = [0u8; 2];
+        let mut encoder = Encoder::new(&mut small);
+        encoder.bytes(&[1, 2, 3]);
+        assert_eq!(encoder.finish(), None);
+    }
+}
@@ -0,0 +1,557 @@
+//! Updates consisting of several components.
+//!
+//! A multi-component update is an update_info whose metadata lists its
+//! components with TAG_COMPONENT entries. The staging area (update_start,
+//! update_len) holds the payloads of all components; the checksum (or
+//! digest) of the update_info covers the whole staging area. The value of a
+//! TAG_COMPONENT entry is (little endian):
+//!
+//! ```text
+//! | start: u32 | len: u32 | target: u32 | checksum: u32 | digest (optional) |
+//! ```
+//!
+//! start is the address of the payload in the staging area, checksum is its
+//! CRC-32. The optional digest has the same layout as a TAG_DIGEST value and
+//! replaces the CRC-32 check.
+//!
+//! Installation is all or nothing: all components are checked before
+//! anything is written, then the current content of every target is copied
+//! to a backup area and only then the components are installed. The
+//! progress is kept in a journal, so an interrupted installation continues
+//! where it stopped after a reset. If a component can't be installed, all
+//! targets are restored from the backup area and the update is not tried
+//! again until another one is staged. This relies on the staging area, the
+//! backup area and the journal staying intact, so targets must not overlap
+//! them or each other.
+
+use super::{update_info, Flasher, InstallError, WriteOptions, digest, image_installer, tlv};
+use super::crc::Checksum;
+use super::digest::ImageDigest;
+use super::status_record;
+
+pub const MAX_COMPONENTS: usize = 8;
+const COMPONENT_SIZE: usize = 16;
+
+const JOURNAL_MAGIC: [u8; 4] = *b"MUCJ";
+const JOURNAL_SIZE: usize = 20;
+/// A journal record followed by its CRC-32.
+const JOURNAL_RECORD_SIZE: usize = JOURNAL_SIZE + 4;
+
+/// Describes the flash areas used to install multi-component updates.
+#[derive(Debug, Clone, Copy)]
+pub struct ComponentLayout
+{
+    /// Addresses of the two journal records. They have to be located in
+    /// different erase units.
+    pub journal: [usize; 2],
+    /// Receives the previous content of all targets, has to hold the
+    /// components of an update back to back.
+    pub backup: usize,
+    pub backup_size: usize
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Component
+{
+    pub start: usize,
+    pub len: usize,
+    pub target: usize,
+    pub checksum: usize,
+    pub digest: Option<ImageDigest>
+}
+
+impl Component
+{
+    /// Decodes the value of a TAG_COMPONENT entry.
+    pub fn from_value(value: &[u8]) -> Option<Self>
+    {
+        if value.len() < COMPONENT_SIZE
+        {
+            return None;
+        }
+
+        let word = |offset: usize| u32::from_le_bytes([value[offset], value[offset + 1], value[offset + 2], value[offset + 3]]) as usize;
+        let digest = match value.len()
+        {
+            COMPONENT_SIZE => None,
+            _ => Some(ImageDigest::from_value(&value[COMPONENT_SIZE..])?)
+        };
+        Some(Self { start: word(0), len: word(4), target: word(8), checksum: word(12), digest })
+    }
+}
+
+/// Yields the number of components of the update_info at the given address,
+/// 0 for plain updates.
+pub fn component_count<T>(update_info_address: usize, flasher: &T) -> usize
+    where T: Flasher
+{
+    tlv::update_info_metadata(update_info_address, flasher)
+        .map(|area| area.filter(|entry| entry.tag == tlv::TAG_COMPONENT).count())
+        .unwrap_or(0)
+}
+
+/// Reads the component with the given index.
+pub fn read_component<T>(update_info_address: usize, index: usize, flasher: &T) -> Option<Component>
+    where T: Flasher
+{
+    let entry = tlv::update_info_metadata(update_info_address, flasher)?
+        .filter(|entry| entry.tag == tlv::TAG_COMPONENT)
+        .nth(index)?;
+
+    let mut buf = [0u8; COMPONENT_SIZE + 1 + digest::MAX_DIGEST_SIZE];
+    match entry.read_value(flasher, &mut buf)
+    {
+        Ok(len) if len == entry.len => Component::from_value(&buf[..len]),
+        _ => None
+    }
+}
+
+/// Areas that wrap around the end of the address space overlap everything.
+fn overlaps(a_start: usize, a_len: usize, b_start: usize, b_len: usize) -> bool
+{
+    match (a_start.checked_add(a_len), b_start.checked_add(b_len))
+    {
+        (Some(a_end), Some(b_end)) => a_start < b_end && b_start < a_end,
+        _ => true
+    }
+}
+
+/// Checks that all components are well formed and their payloads are intact.
+pub fn check_components<T, C>(data: &update_info, update_info_address: usize, flasher: &T, engine: &mut C) -> bool
+    where T: Flasher, C: Checksum
+{
+    let count = component_count(update_info_address, flasher);
+    if count == 0 || count > MAX_COMPONENTS
+    {
+        return false;
+    }
+
+    let staging_end = match data.update_start.checked_add(data.update_len)
+    {
+        Some(end) => end,
+        None => return false
+    };
+    for index in 0..count
+    {
+        let component = match read_component(update_info_address, index, flasher)
+        {
+            Some(component) => component,
+            None => return false
+        };
+
+        let end = component.start.checked_add(component.len);
+        if component.start < data.update_start || end.is_none_or(|end| end > staging_end) ||
+           overlaps(component.target, component.len, data.update_start, data.update_len)
+        {
+            return false;
+        }
+
+        if !digest::check_image(component.start, component.len, component.checksum, component.digest.as_ref(), flasher, engine)
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// Checks that the previous content of all targets fits into the backup
+/// area, and that the backup area is neither staging area, journal nor
+/// target.
+fn check_backup<T>(data: &update_info, update_info_address: usize, layout: &ComponentLayout, flasher: &T) -> bool
+    where T: Flasher
+{
+    if overlaps(layout.backup, layout.backup_size, data.update_start, data.update_len) ||
+       layout.journal.iter().any(|journal| overlaps(layout.backup, layout.backup_size, *journal, JOURNAL_RECORD_SIZE))
+    {
+        return false;
+    }
+
+    let mut total: usize = 0;
+    for index in 0..component_count(update_info_address, flasher)
+    {
+        let component = match read_component(update_info_address, index, flasher)
+        {
+            Some(component) => component,
+            None => return false
+        };
+        if overlaps(component.target, component.len, layout.backup, layout.backup_size)
+        {
+            return false;
+        }
+        total = match total.checked_add(component.len)
+        {
+            Some(total) => total,
+            None => return false
+        };
+    }
+    total <= layout.backup_size
+}
+
+/// Checks that no target overlaps another one or a journal record.
+fn check_targets<T>(update_info_address: usize, layout: &ComponentLayout, flasher: &T) -> bool
+    where T: Flasher
+{
+    let mut targets = [(0usize, 0usize); MAX_COMPONENTS];
+    let count = component_count(update_info_address, flasher);
+    if count > MAX_COMPONENTS
+    {
+        return false;
+    }
+
+    for index in 0..count
+    {
+        let component = match read_component(update_info_address, index, flasher)
+        {
+            Some(component) => component,
+            None => return false
+        };
+        if layout.journal.iter().any(|journal| overlaps(component.target, component.len, *journal, JOURNAL_RECORD_SIZE)) ||
+           targets[..index].iter().any(|(target, len)| overlaps(component.target, component.len, *target, *len))
+        {
+            return false;
+        }
+        targets[index] = (component.target, component.len);
+    }
+    true
+}
+
+/// Yields the component with the given index and the address of its
+/// backup.
+fn component_with_backup<T>(update_info_address: usize, index: usize, layout: &ComponentLayout, flasher: &T) -> Result<(Component, usize), InstallError>
+    where T: Flasher
+{
+    let mut backup = layout.backup;
+    for previous in 0..index
+    {
+        let len = read_component(update_info_address, previous, flasher).ok_or(InstallError::ChecksumMismatch)?.len;
+        backup = backup.checked_add(len).ok_or(InstallError::ChecksumMismatch)?;
+    }
+    let component = read_component(update_info_address, index, flasher).ok_or(InstallError::ChecksumMismatch)?;
+    Ok((component, backup))
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Phase
+{
+    /// The targets are copied to the backup area.
+    Backup = 0,
+    Install = 1,
+    /// A component failed, the targets are copied back.
+    Restore = 2,
+    Done = 3,
+    /// The targets were restored, the update is not tried again.
+    Restored = 4
+}
+
+impl Phase
+{
+    fn from_u32(value: u32) -> Option<Self>
+    {
+        match value
+        {
+            0 => Some(Phase::Backup),
+            1 => Some(Phase::Install),
+            2 => Some(Phase::Restore),
+            3 => Some(Phase::Done),
+            4 => Some(Phase::Restored),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Journal
+{
+    /// Checksum of the update_info being installed.
+    update_checksum: u32,
+    phase: Phase,
+    /// Index of the next component to handle in this phase.
+    next: u32,
+    sequence: u32
+}
+
+impl Journal
+{
+    fn to_bytes(self) -> [u8; JOURNAL_SIZE]
+    {
+        let mut bytes = [0u8; JOURNAL_SIZE];
+        bytes[0..4].copy_from_slice(&JOURNAL_MAGIC);
+        bytes[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.update_checksum.to_le_bytes());
+        bytes[12..16].copy_from_slice(&(self.phase as u32).to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.next.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; JOURNAL_SIZE]) -> Option<Self>
+    {
+        if bytes[0..4] != JOURNAL_MAGIC
+        {
+            return None;
+        }
+
+        let word = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        Some(Self { sequence: word(4), update_checksum: word(8), phase: Phase::from_u32(word(12))?, next: word(16) })
+    }
+}
+
+fn read_journal<T>(journal: &[usize; 2], flasher: &T) -> Option<Journal>
+    where T: Flasher
+{
+    let records = [status_record::read(journal[0], flasher).and_then(|bytes| Journal::from_bytes(&bytes)),
+                   status_record::read(journal[1], flasher).and_then(|bytes| Journal::from_bytes(&bytes))];
+    status_record::latest(records, |journal| journal.sequence)
+}
+
+fn write_journal<T>(addresses: &[usize; 2], journal: &mut Journal, flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    journal.sequence = journal.sequence.wrapping_add(1);
+    status_record::write(addresses, journal.sequence, &journal.to_bytes(), flasher, options)
+}
+
+/// Journals that the component `index` was handled, moving on to `next`
+/// after the last one.
+fn advance<T>(layout: &ComponentLayout, state: &mut Journal, index: usize, count: usize, next: Phase, flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    state.next = index as u32 + 1;
+    if state.next as usize >= count
+    {
+        state.phase = next;
+        state.next = 0;
+    }
+    write_journal(&layout.journal, state, flasher, options)
+}
+
+/// Installs all components of a multi-component update, resuming an
+/// interrupted installation. Yields false if the update was installed
+/// completely before. If a component fails, all targets are restored
+/// before the error is passed on, later calls for the same update yield
+/// `InstallError::Rejected` without writing anything.
+pub fn install_components<T, C>(data: &update_info, update_info_address: usize, layout: &ComponentLayout, flasher: &mut T, engine: &mut C, options: &WriteOptions) -> Result<bool, InstallError>
+    where T: Flasher, C: Checksum
+{
+    let count = component_count(update_info_address, flasher);
+    let mut state = match read_journal(&layout.journal, flasher)
+    {
+        Some(state) if state.update_checksum == data.checksum as u32 => state,
+        previous => Journal { update_checksum: data.checksum as u32, phase: Phase::Backup, next: 0, sequence: previous.map(|state| state.sequence).unwrap_or(0) }
+    };
+
+    match state.phase
+    {
+        Phase::Done => return Ok(false),
+        // Failed and restored before, only a new update is tried.
+        Phase::Restored => return Err(InstallError::Rejected),
+        // Interrupted while restoring, the update failed before.
+        Phase::Restore => return restore_components(update_info_address, layout, &mut state, count, flasher, options).and(Err(InstallError::Rejected)),
+        _ => {}
+    }
+
+    // Nothing is written unless all components are good.
+    if !check_components(data, update_info_address, flasher, engine) || !check_targets(update_info_address, layout, flasher) ||
+       !check_backup(data, update_info_address, layout, flasher)
+    {
+        return Err(InstallError::ChecksumMismatch);
+    }
+
+    while state.phase == Phase::Backup
+    {
+        let index = state.next as usize;
+        let (component, backup) = component_with_backup(update_info_address, index, layout, flasher)?;
+        image_installer::copy_area(component.target, component.len, backup, flasher, options)?;
+        advance(layout, &mut state, index, count, Phase::Install, flasher, options)?;
+    }
+
+    while state.phase == Phase::Install
+    {
+        let index = state.next as usize;
+        let component = read_component(update_info_address, index, flasher).ok_or(InstallError::ChecksumMismatch)?;
+        let result = image_installer::copy_area(component.start, component.len, component.target, flasher, options).and_then(|_|
+            match digest::check_image(component.target, component.len, component.checksum, component.digest.as_ref(), flasher, engine)
+            {
+                true => Ok(()),
+                false => Err(InstallError::ChecksumMismatch)
+            });
+
+        if let Err(error) = result
+        {
+            state.phase = Phase::Restore;
+            state.next = 0;
+            write_journal(&layout.journal, &mut state, flasher, options)?;
+            restore_components(update_info_address, layout, &mut state, count, flasher, options)?;
+            return Err(error);
+        }
+        advance(layout, &mut state, index, count, Phase::Done, flasher, options)?;
+    }
+    Ok(true)
+}
+
+/// Copies the previous content of all targets back from the backup area.
+fn restore_components<T>(update_info_address: usize, layout: &ComponentLayout, state: &mut Journal, count: usize, flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    while state.phase == Phase::Restore
+    {
+        let index = state.next as usize;
+        let (component, backup) = component_with_backup(update_info_address, index, layout, flasher)?;
+        image_installer::copy_area(backup, component.len, component.target, flasher, options)?;
+        advance(layout, state, index, count, Phase::Restored, flasher, options)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::crc::Crc32Table;
+    use crate::{update_info, tlv, InstallError, WriteOptions};
+    use super::*;
+
+    const LAYOUT: ComponentLayout = ComponentLayout { journal: [0x7000, 0x7400], backup: 0x5000, backup_size: 0x400 };
+
+    fn component(start: u32, len: u32, target: u32, checksum: u32) -> [u8; 16]
+    {
+        let mut value = [0u8; 16];
+        value[0..4].copy_from_slice(&start.to_le_bytes());
+        value[4..8].copy_from_slice(&len.to_le_bytes());
+        value[8..12].copy_from_slice(&target.to_le_bytes());
+        value[12..16].copy_from_slice(&checksum.to_le_bytes());
+        value
+    }
+
+    /// Two components with the same payload, staged at 0x1000 and 0x1080.
+    fn setup(fl: &mut FakeFlasher, second_checksum: u32) -> update_info
+    {
+        copy_to_flasher(fl, 0x1000, &test_image());
+        copy_to_flasher(fl, 0x1080, &test_image());
+        let app = component(0x1000, 128, 0x4000, 0x2279EFE7);
+        let radio = component(0x1080, 128, 0x6000, second_checksum);
+        write_metadata(fl, core::mem::size_of::<update_info>(), &[(tlv::TAG_COMPONENT, &app), (tlv::TAG_COMPONENT, &radio)]);
+
+        update_info {
+            magic: *b"MUUPD",
+            struct_ver: 1,
+            update_start: 0x1000,
+            update_len: 256,
+            target_adress: 0,
+            checksum: 0x1234
+        }
+    }
+
+    #[test]
+    fn installs_all_components_once()
+    {
+        let mut fl = FakeFlasher::new();
+        let update = setup(&mut fl, 0x2279EFE7);
+
+        assert_eq!(component_count(0, &fl), 2);
+        assert_eq!(install_components(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Ok(true));
+        assert!(fl.memory[0x4000..0x4080] == test_image());
+        assert!(fl.memory[0x6000..0x6080] == test_image());
+
+        let writes = fl.write_count;
+        assert_eq!(install_components(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Ok(false));
+        assert_eq!(fl.write_count, writes);
+    }
+
+    #[test]
+    fn nothing_is_written_if_a_component_is_bad()
+    {
+        let mut fl = FakeFlasher::new();
+        let update = setup(&mut fl, 0xBAD);
+
+        assert_eq!(install_components(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Err(InstallError::ChecksumMismatch));
+        assert_eq!(fl.write_count, 0);
+    }
+
+    #[test]
+    fn interrupted_installation_is_resumed()
+    {
+        let mut fl = FakeFlasher::new();
+        let update = setup(&mut fl, 0x2279EFE7);
+
+        // Power loss while copying the second component, after backing up
+        // both targets (2 chunks and a journal record each) and installing
+        // the first component.
+        fl.fail_after_writes = Some(3 + 3 + 3 + 1);
+        assert!(install_components(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()).is_err());
+        assert!(fl.memory[0x4000..0x4080] == test_image());
+        assert!(fl.memory[0x6000..0x6080] != test_image());
+
+        fl.fail_after_writes = None;
+        fl.write_count = 0;
+        assert_eq!(install_components(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Ok(true));
+        assert!(fl.memory[0x6000..0x6080] == test_image());
+        // Only the second component was copied (2 chunks) and journaled.
+        assert_eq!(fl.write_count, 2 + 1);
+    }
+
+    #[test]
+    fn failing_component_restores_all_targets()
+    {
+        let mut fl = FakeFlasher::new();
+        let update = setup(&mut fl, 0x2279EFE7);
+        fl.memory[0x4000..0x4080].fill(0xA5);
+        fl.memory[0x6000..0x6080].fill(0x5A);
+
+        // The second component never verifies.
+        fl.stuck_address = Some(0x6010);
+        assert_eq!(install_components(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Err(InstallError::ChecksumMismatch));
+        assert!(fl.memory[0x4000..0x4080].iter().all(|byte| *byte == 0xA5));
+        assert!(fl.memory[0x6000..0x6080].iter().all(|byte| *byte == 0x5A));
+
+        // The update is not tried again.
+        fl.stuck_address = None;
+        let writes = fl.write_count;
+        assert_eq!(install_components(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Err(InstallError::Rejected));
+        assert_eq!(fl.write_count, writes);
+        assert!(fl.memory[0x4000..0x4080].iter().all(|byte| *byte == 0xA5));
+
+        // A new update is.
+        let mut update = update;
+        update.checksum = 0x5678;
+        assert_eq!(install_components(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Ok(true));
+        assert!(fl.memory[0x4000..0x4080] == test_image());
+        assert!(fl.memory[0x6000..0x6080] == test_image());
+    }
+
+    #[test]
+    fn overlapping_areas_are_rejected()
+    {
+        let mut fl = FakeFlasher::new();
+        let update = setup(&mut fl, 0x2279EFE7);
+        let app = component(0x1000, 128, 0x4000, 0x2279EFE7);
+
+        // Targets overlapping each other, a journal record or the backup area.
+        for target in [0x4040, 0x7400 - 0x40, 0x5000]
+        {
+            let radio = component(0x1080, 128, target, 0x2279EFE7);
+            write_metadata(&mut fl, core::mem::size_of::<update_info>(), &[(tlv::TAG_COMPONENT, &app), (tlv::TAG_COMPONENT, &radio)]);
+            assert_eq!(install_components(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Err(InstallError::ChecksumMismatch));
+            assert_eq!(fl.write_count, 0);
+        }
+
+        // Backup area overlapping a journal record.
+        let radio = component(0x1080, 128, 0x6000, 0x2279EFE7);
+        write_metadata(&mut fl, core::mem::size_of::<update_info>(), &[(tlv::TAG_COMPONENT, &app), (tlv::TAG_COMPONENT, &radio)]);
+        let layout = ComponentLayout { backup: 0x7000 - 0x200, ..LAYOUT };
+        assert_eq!(install_components(&update, 0, &layout, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Err(InstallError::ChecksumMismatch));
+        assert_eq!(fl.write_count, 0);
+    }
+
+    #[test]
+    fn components_wrapping_around_are_rejected()
+    {
+        let mut fl = FakeFlasher::new();
+        let mut update = setup(&mut fl, 0x2279EFE7);
+        let wrapping = component(0x1000, 0xFFFF_FFF0, 0x4000, 0x2279EFE7);
+        write_metadata(&mut fl, core::mem::size_of::<update_info>(), &[(tlv::TAG_COMPONENT, &wrapping)]);
+        assert!(!check_components(&update, 0, &fl, &mut Crc32Table::new()));
+
+        update.update_len = usize::MAX;
+        assert!(!check_components(&update, 0, &fl, &mut Crc32Table::new()));
+    }
+}
@@ -1,57 +1,285 @@
-
 use super::Flasher;
 
-pub fn check_crc<T>(start_adr: usize, len: usize, checksum: usize, flasher: &T) -> bool
-    where T: Flasher
+const CRC32_POLYNOMIAL: u32 = 0xEDB88320;
+const CRC32C_POLYNOMIAL: u32 = 0x82F63B78;
+
+/// A streaming CRC engine. The engine passed to `muload_main` has to compute
+/// CRC-32 (IEEE 802.3, reflected, init and final xor 0xFFFFFFFF). Ports can
+/// implement this on top of the CRC peripheral of the MCU, the crate provides
+/// software implementations with different flash/speed tradeoffs.
+pub trait Checksum
 {
-    let mut crc = 0xFFFFFFFF as u32;
-    let mut bytes_left = len;
-    let mut index = 0;
-    while bytes_left > 0
+    /// Starts a new calculation.
+    fn reset(&mut self);
+    fn update(&mut self, data: &[u8]);
+    /// Yields the CRC of all data passed to `update` since the last `reset`.
+    fn finish(&mut self) -> u32;
+}
+
+/// Bit by bit implementation, no table at all but slow.
+pub struct Crc32Bitwise
+{
+    crc: u32
+}
+
+impl Crc32Bitwise
+{
+    pub fn new() -> Self
     {
-        let mut buf: [u8; 64] = [0;64];
-        if let Ok(num_bytes_read) = flasher.read(start_adr + index, &mut buf)
-        {
-            let num_bytes_to_process: usize;
+        Self { crc: 0xFFFFFFFF }
+    }
+}
 
-            if num_bytes_read > bytes_left
-            {
-                num_bytes_to_process = bytes_left;
-            }
-            else
-            {
-                num_bytes_to_process = num_bytes_read;
-            }
+impl Default for Crc32Bitwise
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Checksum for Crc32Bitwise
+{
+    fn reset(&mut self)
+    {
+        self.crc = 0xFFFFFFFF;
+    }
 
-            for b in 0..num_bytes_to_process
+    fn update(&mut self, data: &[u8])
+    {
+        for byte in data.iter()
+        {
+            let mut val = (self.crc ^ (*byte as u32)) & 0xFF;
+            for _ in 0..8
             {
-                let mut val = ((crc ^ (buf[b] as u32)) & 0xFF) as u32;
-                for _ in 0..8
+                if val & 1 != 0
+                {
+                    val = (val >> 1) ^ CRC32_POLYNOMIAL;
+                }
+                else
                 {
-                    if val & 1 != 0
-                    {
-                        val = (val >> 1) ^ (0xEDB88320 as u32);
-                    }
-                    else
-                    {
-                        val = val >> 1;
-                    }
+                    val >>= 1;
                 }
-                crc = val ^ crc >> 8;
             }
+            self.crc = val ^ self.crc >> 8;
+        }
+    }
+
+    fn finish(&mut self) -> u32
+    {
+        self.crc ^ 0xFFFFFFFF
+    }
+}
+
+const fn make_table<const N: usize>(polynomial: u32) -> [u32; N]
+{
+    // N is either 16 (one entry per nibble) or 256 (one entry per byte)
+    let bits = if N == 16 { 4 } else { 8 };
+    let mut table = [0u32; N];
+    let mut index = 0;
+    while index < N
+    {
+        let mut val = index as u32;
+        let mut bit = 0;
+        while bit < bits
+        {
+            val = if val & 1 != 0 { (val >> 1) ^ polynomial } else { val >> 1 };
+            bit += 1;
+        }
+        table[index] = val;
+        index += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = make_table::<256>(CRC32_POLYNOMIAL);
+static CRC32_NIBBLE_TABLE: [u32; 16] = make_table::<16>(CRC32_POLYNOMIAL);
+static CRC32C_TABLE: [u32; 256] = make_table::<256>(CRC32C_POLYNOMIAL);
+
+/// Byte wise table driven implementation, the fastest software
+/// implementation at the cost of a 1 KiB table.
+pub struct Crc32Table
+{
+    crc: u32
+}
+
+impl Crc32Table
+{
+    pub fn new() -> Self
+    {
+        Self { crc: 0xFFFFFFFF }
+    }
+}
+
+impl Default for Crc32Table
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Checksum for Crc32Table
+{
+    fn reset(&mut self)
+    {
+        self.crc = 0xFFFFFFFF;
+    }
+
+    fn update(&mut self, data: &[u8])
+    {
+        for byte in data.iter()
+        {
+            self.crc = CRC32_TABLE[((self.crc ^ *byte as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    fn finish(&mut self) -> u32
+    {
+        self.crc ^ 0xFFFFFFFF
+    }
+}
+
+/// Nibble wise table driven implementation, a compromise for parts
+/// with very little flash (the table takes up 64 bytes).
+pub struct Crc32Nibble
+{
+    crc: u32
+}
+
+impl Crc32Nibble
+{
+    pub fn new() -> Self
+    {
+        Self { crc: 0xFFFFFFFF }
+    }
+}
+
+impl Default for Crc32Nibble
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Checksum for Crc32Nibble
+{
+    fn reset(&mut self)
+    {
+        self.crc = 0xFFFFFFFF;
+    }
+
+    fn update(&mut self, data: &[u8])
+    {
+        for byte in data.iter()
+        {
+            let mut crc = self.crc ^ *byte as u32;
+            crc = CRC32_NIBBLE_TABLE[(crc & 0x0F) as usize] ^ (crc >> 4);
+            crc = CRC32_NIBBLE_TABLE[(crc & 0x0F) as usize] ^ (crc >> 4);
+            self.crc = crc;
+        }
+    }
+
+    fn finish(&mut self) -> u32
+    {
+        self.crc ^ 0xFFFFFFFF
+    }
+}
+
+/// Byte wise table driven CRC-32C (Castagnoli), used as image digest.
+/// This is NOT a replacement for the CRC-32 engine passed to `muload_main`.
+pub struct Crc32cTable
+{
+    crc: u32
+}
+
+impl Crc32cTable
+{
+    pub fn new() -> Self
+    {
+        Self { crc: 0xFFFFFFFF }
+    }
+}
+
+impl Default for Crc32cTable
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Checksum for Crc32cTable
+{
+    fn reset(&mut self)
+    {
+        self.crc = 0xFFFFFFFF;
+    }
+
+    fn update(&mut self, data: &[u8])
+    {
+        for byte in data.iter()
+        {
+            self.crc = CRC32C_TABLE[((self.crc ^ *byte as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    fn finish(&mut self) -> u32
+    {
+        self.crc ^ 0xFFFFFFFF
+    }
+}
+
+/// Reads `len` bytes starting at `start_adr` in small chunks and passes
+/// each chunk to `process`. Yields false if reading failed.
+pub fn read_chunks<T, F>(start_adr: usize, len: usize, flasher: &T, mut process: F) -> bool
+    where T: Flasher, F: FnMut(&[u8])
+{
+    let mut bytes_left = len;
+    let mut index = 0;
+    while bytes_left > 0
+    {
+        let mut buf: [u8; 64] = [0;64];
+        if let Ok(num_bytes_read @ 1..) = flasher.read(start_adr + index, &mut buf)
+        {
+            let num_bytes_to_process = if num_bytes_read > bytes_left { bytes_left } else { num_bytes_read };
+
+            process(&buf[..num_bytes_to_process]);
 
             bytes_left -= num_bytes_to_process;
             index += num_bytes_read;
         }
         else
         {
-            // Read failure, we assume a bad crc in this case.
+            // Failed or no progress at all.
             return false;
         }
     }
+    true
+}
 
-    let actual_crc = crc as usize ^ 0xFFFFFFFF as usize;
-    return checksum == actual_crc;
+/// Continues a CRC-16/XMODEM (CCITT polynomial 0x1021, not reflected,
+/// init 0) over `data`. Used by the serial protocols, which carry a CRC-16
+/// per frame.
+pub fn crc16_xmodem(crc: u16, data: &[u8]) -> u16
+{
+    data.iter().fold(crc, |crc, byte| {
+        (0..8).fold(crc ^ (*byte as u16) << 8, |crc, _| if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 })
+    })
+}
+
+pub fn check_crc<T, C>(start_adr: usize, len: usize, checksum: usize, flasher: &T, engine: &mut C) -> bool
+    where T: Flasher, C: Checksum
+{
+    engine.reset();
+    if !read_chunks(start_adr, len, flasher, |chunk| engine.update(chunk))
+    {
+        // Read failure, we assume a bad crc in this case.
+        return false;
+    }
+
+    checksum == engine.finish() as usize
 }
 
 
@@ -59,13 +287,45 @@ pub fn check_crc<T>(start_adr: usize, len: usize, checksum: usize, flasher: &T)
 mod test
 {
     use crate::testhelpers::*;
-    use super::check_crc;
+    use super::*;
 
     #[test]
     fn can_calc_crc()
     {
         let mut fl = FakeFlasher::new();
         copy_to_flasher(&mut fl, 0, &[0xAA,0xBB,0xCC,0xDD,0xEE,0xFF,0x11,0x22]);        
-        assert!(true == check_crc(0, 8, 0x65133A42, &mut fl))
+        assert!(check_crc(0, 8, 0x65133A42, &fl, &mut Crc32Table::new()))
+    }
+
+    #[test]
+    fn can_calc_crc16()
+    {
+        assert_eq!(crc16_xmodem(0, b"123456789"), 0x31C3);
+        assert_eq!(crc16_xmodem(crc16_xmodem(0, b"1234"), b"56789"), 0x31C3);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn all_engines_yield_the_same_crc()
+    {
+        let image = test_image();
+        let engines: [&mut dyn Checksum; 3] = [&mut Crc32Bitwise::new(), &mut Crc32Table::new(), &mut Crc32Nibble::new()];
+        for engine in engines
+        {
+            engine.update(&image[..50]);
+            engine.update(&image[50..]);
+            assert_eq!(engine.finish(), 0x2279EFE7);
+
+            engine.reset();
+            engine.update(b"123456789");
+            assert_eq!(engine.finish(), 0xCBF43926);
+        }
+    }
+
+    #[test]
+    fn can_calc_crc32c()
+    {
+        let mut crc = Crc32cTable::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xE3069283);
+    }
+}
@@ -0,0 +1,224 @@
+//! Data partitions.
+//!
+//! Besides the application the loader manages partitions holding data that
+//! is never executed (calibration tables, fonts, models, ...). A data
+//! partition consists of a `data_info` struct (followed by a metadata area,
+//! just like bin_info) and the area holding the data. Both are described by
+//! a `DataPartition`, which the port passes to the loader and which the
+//! application uses to locate the data.
+//!
+//! Data updates are ordinary updates carrying the name of their partition
+//! (TAG_PARTITION_NAME) and targeting the start of that partition. The
+//! installer invalidates the data_info, copies the data, verifies it and
+//! writes the new data_info (including the metadata of the update) last.
+
+use super::{update_info, Flasher, InstallError, WriteOptions, digest, image_installer, load_info_struct_from_address, tlv};
+use super::crc::Checksum;
+use super::partition_table::{PartitionTable, MAX_NAME_SIZE};
+
+/// A data partition, as configured by the port.
+#[derive(Debug, Clone, Copy)]
+pub struct DataPartition
+{
+    pub name: &'static str,
+    /// Address of the data_info struct, outside of the data area.
+    pub info_address: usize,
+    /// Start and size of the data area.
+    pub start: usize,
+    pub size: usize
+}
+
+#[repr(C)]
+pub struct data_info
+{
+    magic: [u8;5],
+    struct_ver: u8,
+    data_start: usize,
+    data_len: usize,
+    checksum: usize
+}
+
+const DATA_INFO_MAGIC: [u8; 5] = *b"MUDAT";
+
+/// Valid data found in a partition.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PartitionData
+{
+    pub start: usize,
+    pub len: usize
+}
+
+/// Checks whether the update_info at the given address belongs to a data partition.
+pub fn is_data_update<T>(update_info_address: usize, flasher: &T) -> bool
+    where T: Flasher
+{
+    tlv::update_info_metadata(update_info_address, flasher)
+        .and_then(|area| area.find(tlv::TAG_PARTITION_NAME))
+        .is_some()
+}
+
+/// Reads the partition name of the update_info at the given address into
+/// `name`, yields its length.
+fn partition_name<T>(update_info_address: usize, flasher: &T, name: &mut [u8; MAX_NAME_SIZE]) -> Option<usize>
+    where T: Flasher
+{
+    let entry = tlv::update_info_metadata(update_info_address, flasher)?.find(tlv::TAG_PARTITION_NAME)?;
+    match entry.read_value(flasher, name)
+    {
+        Ok(len) if len == entry.len => Some(len),
+        _ => None
+    }
+}
+
+/// Yields the partition named in the metadata of the update_info at the
+/// given address. None for updates without a partition name and for
+/// unknown partitions.
+pub fn find_partition<'p, T>(partitions: &'p [DataPartition], update_info_address: usize, flasher: &T) -> Option<&'p DataPartition>
+    where T: Flasher
+{
+    let mut name = [0u8; MAX_NAME_SIZE];
+    let len = partition_name(update_info_address, flasher, &mut name)?;
+    partitions.iter().find(|partition| partition.name.as_bytes() == &name[..len])
+}
+
+/// Like `find_partition`, but looks the partition up in the partition table.
+pub fn partition_from_table<T>(table: &PartitionTable, update_info_address: usize, flasher: &T) -> Option<DataPartition>
+    where T: Flasher
+{
+    let mut name = [0u8; MAX_NAME_SIZE];
+    let len = partition_name(update_info_address, flasher, &mut name)?;
+    table.data_partition(&name[..len], flasher)
+}
+
+/// Checks the partition and yields the location of its data. This is what
+/// the application uses before it accesses the data.
+pub fn validate<T, C>(partition: &DataPartition, flasher: &T, engine: &mut C) -> Option<PartitionData>
+    where T: Flasher, C: Checksum
+{
+    let info = load_info_struct_from_address::<data_info, T>(partition.info_address, flasher).ok()?;
+    if info.magic != DATA_INFO_MAGIC || info.struct_ver != 1 ||
+       info.data_start != partition.start || info.data_len > partition.size
+    {
+        return None;
+    }
+
+    let metadata = tlv::read_area(partition.info_address + core::mem::size_of::<data_info>(), flasher);
+    let image_digest = digest::ImageDigest::from_metadata(metadata, flasher);
+    if !digest::check_image(info.data_start, info.data_len, info.checksum, image_digest.as_ref(), flasher, engine)
+    {
+        return None;
+    }
+    Some(PartitionData { start: info.data_start, len: info.data_len })
+}
+
+fn write_info<T>(partition: &DataPartition, info: &data_info, flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    let num_bytes = core::mem::size_of::<data_info>();
+    let data_slice = unsafe {core::slice::from_raw_parts((info as *const data_info) as *const u8, num_bytes)};
+    super::write_chunk(flasher, partition.info_address, data_slice, options)
+        .map_err(|_| InstallError::WriteFailed { address: partition.info_address })
+}
+
+/// Installs a data update into its partition. Yields false if the
+/// partition already holds the update.
+pub fn install_data<T, C>(data: &update_info, update_info_address: usize, partition: &DataPartition, flasher: &mut T, engine: &mut C, options: &WriteOptions) -> Result<bool, InstallError>
+    where T: Flasher, C: Checksum
+{
+    if data.target_adress != partition.start || data.update_len > partition.size
+    {
+        return Err(InstallError::WriteFailed { address: data.target_adress });
+    }
+
+    let installed = load_info_struct_from_address::<data_info, T>(partition.info_address, flasher).map(|info| info.checksum);
+    if installed == Ok(data.checksum) && validate(partition, flasher, engine).is_some()
+    {
+        return Ok(false);
+    }
+
+    // The partition is invalid until the new data is complete.
+    let mut info = data_info { magic: [0; 5], struct_ver: 0, data_start: 0, data_len: 0, checksum: 0 };
+    write_info(partition, &info, flasher, options)?;
+    flasher.flush();
+
+    image_installer::install_binary(data, update_info_address, flasher, engine, options)?;
+
+    info = data_info { magic: DATA_INFO_MAGIC, struct_ver: 1, data_start: partition.start, data_len: data.update_len, checksum: data.checksum };
+    write_info(partition, &info, flasher, options)?;
+
+    // The metadata (name, digest, version, ...) goes along with the data.
+    let metadata_address = update_info_address + core::mem::size_of::<update_info>();
+    if let Some(size) = tlv::stored_area_size(metadata_address, flasher)
+    {
+        image_installer::copy_area(metadata_address, size, partition.info_address + core::mem::size_of::<data_info>(), flasher, options)?;
+    }
+    flasher.flush();
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::crc::Crc32Table;
+    use crate::{update_info, tlv, WriteOptions};
+    use super::*;
+
+    const PARTITIONS: [DataPartition; 2] = [
+        DataPartition { name: "calib", info_address: 0x3000, start: 0x3100, size: 0x100 },
+        DataPartition { name: "fonts", info_address: 0x5000, start: 0x5100, size: 0x1000 }
+    ];
+
+    fn data_update(fl: &mut FakeFlasher) -> update_info
+    {
+        copy_to_flasher(fl, 0x1000, &test_image());
+        write_metadata(fl, core::mem::size_of::<update_info>(), &[(tlv::TAG_PARTITION_NAME, b"fonts")]);
+        update_info {
+            magic: *b"MUUPD",
+            struct_ver: 1,
+            update_start: 0x1000,
+            update_len: 128,
+            target_adress: 0x5100,
+            checksum: 0x2279EFE7
+        }
+    }
+
+    #[test]
+    fn can_install_and_validate_data()
+    {
+        let mut fl = FakeFlasher::new();
+        let update = data_update(&mut fl);
+
+        let partition = find_partition(&PARTITIONS, 0, &fl).unwrap();
+        assert_eq!(partition.name, "fonts");
+        assert_eq!(validate(partition, &fl, &mut Crc32Table::new()), None);
+
+        assert_eq!(install_data(&update, 0, partition, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Ok(true));
+        assert_eq!(validate(partition, &fl, &mut Crc32Table::new()), Some(PartitionData { start: 0x5100, len: 128 }));
+        // The name is kept in the metadata of the partition.
+        let name = tlv::read_area(0x5000 + core::mem::size_of::<data_info>(), &fl).unwrap().find(tlv::TAG_PARTITION_NAME);
+        assert_eq!(name.map(|entry| entry.len), Some(5));
+
+        assert_eq!(install_data(&update, 0, partition, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Ok(false));
+
+        fl.memory[0x5110] ^= 0x01;
+        assert_eq!(validate(partition, &fl, &mut Crc32Table::new()), None);
+    }
+
+    #[test]
+    fn data_update_must_fit_its_partition()
+    {
+        let mut fl = FakeFlasher::new();
+        let mut update = data_update(&mut fl);
+
+        // Targets the fonts partition
+        assert!(install_data(&update, 0, &PARTITIONS[0], &mut fl, &mut Crc32Table::new(), &WriteOptions::default()).is_err());
+        update.target_adress = 0x3100;
+        update.update_len = 0x200;
+        assert!(install_data(&update, 0, &PARTITIONS[0], &mut fl, &mut Crc32Table::new(), &WriteOptions::default()).is_err());
+        assert_eq!(fl.write_count, 0);
+
+        write_metadata(&mut fl, core::mem::size_of::<update_info>(), &[(tlv::TAG_PARTITION_NAME, b"model")]);
+        assert!(find_partition(&PARTITIONS, 0, &fl).is_none());
+    }
+}
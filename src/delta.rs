@@ -0,0 +1,487 @@
+//! Differential updates.
+//!
+//! A delta update (`UpdateEncoding::Delta`) does not contain the new image
+//! but a patch against the image currently installed at the target address
+//! (the base). The base is identified by its length and CRC-32, stored with
+//! TAG_DELTA_BASE in the metadata of the update. The new image is built
+//! in the staging area behind the patch (at the next 4 KiB boundary) and
+//! copied to the target address once it is complete, so the staging area
+//! has to hold both. Updates whose patch or new image would not fit into
+//! the staging area of the `DeltaLayout` are rejected before anything is
+//! written.
+//!
+//! Patch layout (little endian):
+//!
+//! ```text
+//! | 'M' 'U' 'D' 'L' | new_len: u32 | new_crc: u32 | command ... | END |
+//!
+//! COPY   (0x01) | src: u32 | len: u32 |              out = base[src..src + len]
+//! ADD    (0x02) | src: u32 | len: u32 | diff[len] |  out = base[src..src + len] + diff (bytewise, wrapping)
+//! INSERT (0x03) | len: u32 | data[len] |             out = data
+//! END    (0x00)
+//! ```
+//!
+//! The new image is built page by page in RAM, a page is only written once
+//! it is complete. The base is not touched until the new image is complete
+//! and matches new_crc. A reset while building starts over on the next
+//! boot, a reset while copying copies the built image again.
+
+use super::{Flasher, InstallError, update_info, tlv};
+use super::crc::{self, Checksum};
+
+pub const DELTA_MAGIC: [u8; 4] = *b"MUDL";
+const DELTA_HEADER_SIZE: usize = 12;
+
+const CMD_END: u8 = 0x00;
+const CMD_COPY: u8 = 0x01;
+const CMD_ADD: u8 = 0x02;
+const CMD_INSERT: u8 = 0x03;
+
+/// Largest page size supported for delta updates.
+pub const MAX_DELTA_PAGE_SIZE: usize = 4096;
+
+/// Where delta updates are staged and built.
+#[derive(Debug, Clone, Copy)]
+pub struct DeltaLayout
+{
+    /// Staging area, holds the patch and the new image behind it.
+    pub staging: usize,
+    pub staging_size: usize,
+    /// Erase unit of the target area, the new image is built in a RAM
+    /// buffer of this size (1 to MAX_DELTA_PAGE_SIZE bytes). The buffer is
+    /// always MAX_DELTA_PAGE_SIZE bytes on the stack, whatever the page
+    /// size, which has to be accounted for on targets with little RAM.
+    pub page_size: usize
+}
+
+impl DeltaLayout
+{
+    /// Takes the staging area from the staging partition of a partition
+    /// table, if there is one.
+    pub fn with_table<T>(self, table: Option<&super::partition_table::PartitionTable>, flasher: &T) -> Self
+        where T: Flasher
+    {
+        match table.and_then(|table| table.find_kind(super::partition_table::KIND_STAGING, flasher))
+        {
+            Some(entry) => Self { staging: entry.start, staging_size: entry.size, ..self },
+            None => self
+        }
+    }
+
+    /// Checks that the patch and the new image of `new_len` bytes built
+    /// at `output` lie within the staging area.
+    fn contains(&self, data: &update_info, output: usize, new_len: usize) -> bool
+    {
+        let end = match self.staging.checked_add(self.staging_size)
+        {
+            Some(end) => end,
+            None => return false
+        };
+        data.update_start >= self.staging &&
+            data.update_start.checked_add(data.update_len).is_some_and(|patch_end| patch_end <= end) &&
+            output.checked_add(new_len).is_some_and(|output_end| output_end <= end)
+    }
+}
+
+/// The image a delta update was built against.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DeltaBase
+{
+    pub len: u32,
+    pub crc: u32
+}
+
+impl DeltaBase
+{
+    /// Reads the base from the metadata of the update_info at the given address.
+    pub fn from_metadata<T>(update_info_address: usize, flasher: &T) -> Option<Self>
+        where T: Flasher
+    {
+        let entry = tlv::update_info_metadata(update_info_address, flasher)?.find(tlv::TAG_DELTA_BASE)?;
+        let mut value = [0u8; 8];
+        match entry.read_value(flasher, &mut value)
+        {
+            Ok(8) => Some(Self
+            {
+                len: u32::from_le_bytes([value[0], value[1], value[2], value[3]]),
+                crc: u32::from_le_bytes([value[4], value[5], value[6], value[7]])
+            }),
+            _ => None
+        }
+    }
+}
+
+/// Checks that the image at the target address is the base the
+/// update was built against.
+pub fn check_base<T, C>(data: &update_info, update_info_address: usize, flasher: &T, engine: &mut C) -> bool
+    where T: Flasher, C: Checksum
+{
+    match DeltaBase::from_metadata(update_info_address, flasher)
+    {
+        Some(base) => crc::check_crc(data.target_adress, base.len as usize, base.crc as usize, flasher, engine),
+        None => false
+    }
+}
+
+/// Address the new image is built at, behind the patch.
+fn output_address(data: &update_info) -> Option<usize>
+{
+    data.update_start.checked_add(data.update_len)?.checked_next_multiple_of(MAX_DELTA_PAGE_SIZE)
+}
+
+/// Yields length and CRC-32 of the new image from the patch header.
+fn read_header<T>(data: &update_info, flasher: &T) -> Result<(usize, u32), InstallError>
+    where T: Flasher
+{
+    let mut reader = PatchReader { flasher, address: data.update_start, end: data.update_start.saturating_add(data.update_len) };
+    let mut header = [0u8; DELTA_HEADER_SIZE];
+    reader.read(&mut header)?;
+    if header[0..4] != DELTA_MAGIC
+    {
+        return Err(InstallError::ChecksumMismatch);
+    }
+    Ok((u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize,
+        u32::from_le_bytes([header[8], header[9], header[10], header[11]])))
+}
+
+/// Checks whether the new image was completely built in the staging area,
+/// i.e. an interrupted installation only has to copy it.
+pub fn is_built<T, C>(data: &update_info, flasher: &T, engine: &mut C) -> bool
+    where T: Flasher, C: Checksum
+{
+    match (output_address(data), read_header(data, flasher))
+    {
+        (Some(address), Ok((new_len, new_crc))) => crc::check_crc(address, new_len, new_crc as usize, flasher, engine),
+        _ => false
+    }
+}
+
+/// Reads the patch sequentially from the staging area.
+struct PatchReader<'a, T: Flasher>
+{
+    flasher: &'a T,
+    address: usize,
+    end: usize
+}
+
+impl <'a, T: Flasher> PatchReader<'a, T>
+{
+    fn read(&mut self, destination: &mut [u8]) -> Result<(), InstallError>
+    {
+        if self.address + destination.len() > self.end
+        {
+            return Err(InstallError::ReadFailed { address: self.address });
+        }
+
+        match self.flasher.read(self.address, destination)
+        {
+            Ok(num_bytes) if num_bytes == destination.len() =>
+            {
+                self.address += num_bytes;
+                Ok(())
+            }
+            _ => Err(InstallError::ReadFailed { address: self.address })
+        }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, InstallError>
+    {
+        let mut buf = [0u8; 1];
+        self.read(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, InstallError>
+    {
+        let mut buf = [0u8; 4];
+        self.read(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Command
+{
+    Copy { src: usize },
+    Add { src: usize },
+    Insert
+}
+
+/// Applies the delta update described by `data` to the image at its target
+/// address, building the new image in the staging area of `layout`.
+pub fn install_delta<T, C>(data: &update_info, update_info_address: usize, layout: &DeltaLayout, flasher: &mut T, engine: &mut C, options: &super::WriteOptions) -> Result<(), InstallError>
+    where T: Flasher, C: Checksum
+{
+    if layout.page_size == 0 || layout.page_size > MAX_DELTA_PAGE_SIZE
+    {
+        return Err(InstallError::InvalidConfig);
+    }
+
+    let (new_len, new_crc) = read_header(data, flasher)?;
+    let output = output_address(data).ok_or(InstallError::ChecksumMismatch)?;
+    if !layout.contains(data, output, new_len)
+    {
+        return Err(InstallError::Rejected);
+    }
+    if !is_built(data, flasher, engine)
+    {
+        if !check_base(data, update_info_address, flasher, engine)
+        {
+            // Not the image this delta was built for.
+            return Err(InstallError::ChecksumMismatch);
+        }
+        build_image(data, update_info_address, output, layout.page_size, flasher, options)?;
+        if !crc::check_crc(output, new_len, new_crc as usize, flasher, engine)
+        {
+            return Err(InstallError::ChecksumMismatch);
+        }
+    }
+
+    super::image_installer::copy_area(output, new_len, data.target_adress, flasher, options)?;
+
+    if !crc::check_crc(data.target_adress, new_len, new_crc as usize, flasher, engine)
+    {
+        return Err(InstallError::ChecksumMismatch);
+    }
+    Ok(())
+}
+
+/// Applies the patch to the base at the target address, writing the new
+/// image to `output`.
+fn build_image<T>(data: &update_info, update_info_address: usize, output: usize, page_size: usize, flasher: &mut T, options: &super::WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    let base = DeltaBase::from_metadata(update_info_address, flasher).ok_or(InstallError::ChecksumMismatch)?;
+    let (new_len, _) = read_header(data, flasher)?;
+    let patch_end = data.update_start + data.update_len;
+
+    let mut page = [0u8; MAX_DELTA_PAGE_SIZE];
+    let page = &mut page[..page_size];
+
+    let mut patch_address = data.update_start + DELTA_HEADER_SIZE;
+    let mut command = Command::Insert;
+    let mut command_left = 0;
+    let mut out_pos = 0;
+
+    while out_pos < new_len
+    {
+        let page_len = core::cmp::min(page_size, new_len - out_pos);
+        let mut fill = 0;
+
+        while fill < page_len
+        {
+            let mut reader = PatchReader { flasher: &*flasher, address: patch_address, end: patch_end };
+
+            if command_left == 0
+            {
+                let (next, len) = match reader.read_u8()?
+                {
+                    CMD_COPY => (Command::Copy { src: reader.read_u32()? as usize }, reader.read_u32()? as usize),
+                    CMD_ADD => (Command::Add { src: reader.read_u32()? as usize }, reader.read_u32()? as usize),
+                    CMD_INSERT => (Command::Insert, reader.read_u32()? as usize),
+                    // END before the image is complete, or garbage
+                    _ => return Err(InstallError::ChecksumMismatch)
+                };
+                command = next;
+                command_left = len;
+            }
+
+            let num_bytes = core::cmp::min(command_left, page_len - fill);
+            let out = &mut page[fill..fill + num_bytes];
+            match command
+            {
+                Command::Copy { src } | Command::Add { src } =>
+                {
+                    if src.checked_add(num_bytes).is_none_or(|end| end > base.len as usize)
+                    {
+                        return Err(InstallError::ChecksumMismatch);
+                    }
+                    match flasher.read(data.target_adress + src, out)
+                    {
+                        Ok(len) if len == num_bytes => {},
+                        _ => return Err(InstallError::ReadFailed { address: data.target_adress + src })
+                    }
+
+                    if let Command::Add { .. } = command
+                    {
+                        let mut diff = [0u8; 32];
+                        for chunk in out.chunks_mut(diff.len())
+                        {
+                            reader.read(&mut diff[..chunk.len()])?;
+                            for (byte, delta) in chunk.iter_mut().zip(diff.iter())
+                            {
+                                *byte = byte.wrapping_add(*delta);
+                            }
+                        }
+                    }
+                    command = match command
+                    {
+                        Command::Copy { src } => Command::Copy { src: src + num_bytes },
+                        _ => Command::Add { src: src + num_bytes }
+                    };
+                }
+                Command::Insert => reader.read(out)?
+            }
+
+            patch_address = reader.address;
+            command_left -= num_bytes;
+            fill += num_bytes;
+        }
+
+        match super::write_chunk(flasher, output + out_pos, &page[..page_len], options)
+        {
+            Ok(()) => {},
+            Err(super::WriteError::VerifyFailed { address }) => return Err(InstallError::VerifyFailed { address }),
+            Err(_) => return Err(InstallError::WriteFailed { address: output + out_pos })
+        }
+        out_pos += page_len;
+    }
+
+    let mut reader = PatchReader { flasher: &*flasher, address: patch_address, end: patch_end };
+    if command_left != 0 || reader.read_u8()? != CMD_END
+    {
+        return Err(InstallError::ChecksumMismatch);
+    }
+
+    flasher.flush();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::crc::{Checksum, Crc32Table};
+    use crate::{update_info, tlv, InstallError, WriteOptions};
+    use super::*;
+
+    const LAYOUT: DeltaLayout = DeltaLayout { staging: 0x1000, staging_size: 0x2000, page_size: 0x100 };
+
+    fn crc_of(data: &[u8]) -> u32
+    {
+        let mut engine = Crc32Table::new();
+        engine.update(data);
+        engine.finish()
+    }
+
+    /// Base: 0..=255 repeated, the new image moves the second page to
+    /// the front, patches a few bytes and appends some data.
+    fn setup(fl: &mut FakeFlasher, expected: &mut [u8; 0x210]) -> update_info
+    {
+        for (index, byte) in fl.memory[0x4000..0x4200].iter_mut().enumerate()
+        {
+            *byte = index as u8;
+        }
+
+        expected[..0x100].copy_from_slice(&fl.memory[0x4100..0x4200]);
+        expected[0x100..0x200].copy_from_slice(&fl.memory[0x4100..0x4200]);
+        expected[0x110] = expected[0x110].wrapping_add(5);
+        expected[0x200..].fill(0xEE);
+
+        let mut patch = [0u8; 128];
+        let mut len = 0;
+        let mut push = |data: &[u8]| { patch[len..len + data.len()].copy_from_slice(data); len += data.len(); };
+        push(&DELTA_MAGIC);
+        push(&0x210u32.to_le_bytes());
+        push(&crc_of(expected).to_le_bytes());
+        push(&[CMD_COPY]); push(&0x100u32.to_le_bytes()); push(&0x100u32.to_le_bytes());
+        push(&[CMD_COPY]); push(&0x100u32.to_le_bytes()); push(&0x10u32.to_le_bytes());
+        push(&[CMD_ADD]); push(&0x110u32.to_le_bytes()); push(&2u32.to_le_bytes()); push(&[5, 0]);
+        push(&[CMD_COPY]); push(&0x112u32.to_le_bytes()); push(&0xEEu32.to_le_bytes());
+        push(&[CMD_INSERT]); push(&0x10u32.to_le_bytes()); push(&[0xEE; 0x10]);
+        push(&[CMD_END]);
+        copy_to_flasher(fl, 0x1000, &patch[..len]);
+
+        let base_crc = crc_of(&fl.memory[0x4000..0x4200]);
+        let mut base = [0u8; 8];
+        base[..4].copy_from_slice(&0x200u32.to_le_bytes());
+        base[4..].copy_from_slice(&base_crc.to_le_bytes());
+        write_metadata(fl, core::mem::size_of::<update_info>(), &[(tlv::TAG_DELTA_BASE, &base)]);
+
+        update_info {
+            magic: *b"MUUPD",
+            struct_ver: 1,
+            update_start: 0x1000,
+            update_len: len,
+            target_adress: 0x4000,
+            checksum: crc_of(&patch[..len]) as usize
+        }
+    }
+
+    #[test]
+    fn can_apply_delta()
+    {
+        let mut fl = FakeFlasher::new();
+        let mut expected = [0u8; 0x210];
+        let update = setup(&mut fl, &mut expected);
+
+        assert_eq!(install_delta(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Ok(()));
+        assert!(fl.memory[0x4000..0x4210] == expected);
+        // The new image is built behind the patch.
+        assert!(fl.memory[0x2000..0x2210] == expected);
+
+        // The base is gone now, a patch against another base is refused.
+        let mut other = [0u8; 8];
+        other[..4].copy_from_slice(&0x200u32.to_le_bytes());
+        write_metadata(&mut fl, core::mem::size_of::<update_info>(), &[(tlv::TAG_DELTA_BASE, &other)]);
+        fl.memory[0x2000] ^= 0xFF;
+        assert_eq!(install_delta(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Err(InstallError::ChecksumMismatch));
+        assert!(fl.memory[0x4000..0x4210] == expected);
+    }
+
+    #[test]
+    fn rejects_unsupported_page_size()
+    {
+        let mut fl = FakeFlasher::new();
+        let mut expected = [0u8; 0x210];
+        let update = setup(&mut fl, &mut expected);
+        for page_size in [0, MAX_DELTA_PAGE_SIZE + 1].iter()
+        {
+            let layout = DeltaLayout { page_size: *page_size, ..LAYOUT };
+            assert_eq!(install_delta(&update, 0, &layout, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Err(InstallError::InvalidConfig));
+        }
+        assert!(fl.memory[0x2000..0x2210].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn delta_resumes_after_reset_while_copying()
+    {
+        let mut fl = FakeFlasher::new();
+        let mut expected = [0u8; 0x210];
+        let update = setup(&mut fl, &mut expected);
+        assert_eq!(install_delta(&update, 0, &DeltaLayout { page_size: 0x200, ..LAYOUT }, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Ok(()));
+
+        // A reset halfway through copying the new image left neither the
+        // base nor the new image at the target.
+        fl.memory[0x4100..0x4210].fill(0xFF);
+        assert!(!check_base(&update, 0, &fl, &mut Crc32Table::new()));
+        assert!(is_built(&update, &fl, &mut Crc32Table::new()));
+
+        assert_eq!(install_delta(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Ok(()));
+        assert!(fl.memory[0x4000..0x4210] == expected);
+    }
+
+    #[test]
+    fn rejects_output_beyond_staging()
+    {
+        let mut fl = FakeFlasher::new();
+        let mut expected = [0u8; 0x210];
+        let update = setup(&mut fl, &mut expected);
+        fl.memory[0x2000..0x3000].fill(0xA5);
+
+        // The new image would be built at 0x2000..0x2210
+        let layout = DeltaLayout { staging_size: 0x1200, ..LAYOUT };
+        assert_eq!(install_delta(&update, 0, &layout, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Err(InstallError::Rejected));
+
+        // A patch outside the staging area
+        let layout = DeltaLayout { staging: 0x1800, ..LAYOUT };
+        assert_eq!(install_delta(&update, 0, &layout, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Err(InstallError::Rejected));
+
+        // A huge new_len in the patch header
+        copy_to_flasher(&mut fl, 0x1004, &u32::MAX.to_le_bytes());
+        assert_eq!(install_delta(&update, 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Err(InstallError::Rejected));
+
+        assert!(fl.memory[0x2000..0x3000].iter().all(|byte| *byte == 0xA5));
+        assert!(fl.memory[0x4000..0x4200].iter().enumerate().all(|(index, byte)| *byte == index as u8));
+    }
+}
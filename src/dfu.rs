@@ -0,0 +1,508 @@
+//! USB DFU 1.1 (Device Firmware Upgrade), so boards with USB can be updated
+//! with `dfu-util`:
+//!
+//! ```text
+//! dfu-util -a 0 -D app.bin
+//! ```
+//!
+//! `DfuDevice` is the class state machine of the DFU mode interface. The
+//! port's USB stack passes the class specific control requests to
+//! `control_out` (DETACH, DNLOAD, CLRSTATUS, ABORT) and `control_in`
+//! (UPLOAD, GETSTATUS, GETSTATE) and calls `poll` from its main loop, which
+//! does the flash work outside of the control transfers. A stall yielded by
+//! a callback has to be forwarded to the host.
+//!
+//! Downloaded blocks are written to the staging area, the CRC-32 is
+//! calculated on the fly. The zero length DNLOAD that ends the download
+//! starts the manifestation: the staged image is checked like the
+//! installer does (MCUboot hash or CRC-32) and the update_info is written,
+//! which installs the image to the target slot, along with the board id of
+//! MCUboot images that carry one. The device is manifestation tolerant, the
+//! host gets the final status and the port resets once `is_manifested` is
+//! set. UPLOAD reads the target slot, it is stalled unless enabled with
+//! `with_upload`, as the slot may hold secrets.
+
+use super::{Flasher, StagingLayout, WriteError, WriteOptions, image_installer, mcuboot, update_info};
+use super::crc::Checksum;
+
+/// Largest block of a DNLOAD or UPLOAD (wTransferSize).
+pub const TRANSFER_SIZE: usize = 1024;
+
+pub const REQUEST_DETACH: u8 = 0;
+pub const REQUEST_DNLOAD: u8 = 1;
+pub const REQUEST_UPLOAD: u8 = 2;
+pub const REQUEST_GETSTATUS: u8 = 3;
+pub const REQUEST_CLRSTATUS: u8 = 4;
+pub const REQUEST_GETSTATE: u8 = 5;
+pub const REQUEST_ABORT: u8 = 6;
+
+const DESCRIPTOR_TYPE_DFU_FUNCTIONAL: u8 = 0x21;
+const ATTRIBUTE_CAN_DOWNLOAD: u8 = 0x01;
+const ATTRIBUTE_CAN_UPLOAD: u8 = 0x02;
+const ATTRIBUTE_MANIFESTATION_TOLERANT: u8 = 0x04;
+const DETACH_TIMEOUT_MS: u16 = 1000;
+const DFU_VERSION: u16 = 0x0110;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum State
+{
+    AppIdle = 0,
+    AppDetach = 1,
+    DfuIdle = 2,
+    DownloadSync = 3,
+    DownloadBusy = 4,
+    DownloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    ManifestWaitReset = 8,
+    UploadIdle = 9,
+    Error = 10
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Status
+{
+    Ok = 0x00,
+    ErrTarget = 0x01,
+    ErrFile = 0x02,
+    ErrWrite = 0x03,
+    ErrErase = 0x04,
+    ErrCheckErased = 0x05,
+    ErrProg = 0x06,
+    ErrVerify = 0x07,
+    ErrAddress = 0x08,
+    ErrNotDone = 0x09,
+    ErrFirmware = 0x0A,
+    ErrVendor = 0x0B,
+    ErrUsbReset = 0x0C,
+    ErrPowerOnReset = 0x0D,
+    ErrUnknown = 0x0E,
+    ErrStalledPacket = 0x0F
+}
+
+/// The request has to be answered with a stall.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Stall;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Pending
+{
+    None,
+    /// The block in the buffer has to be written.
+    Write,
+    Manifest
+}
+
+pub struct DfuDevice<'a, T: Flasher, C: Checksum>
+{
+    flasher: &'a mut T,
+    checksum: &'a mut C,
+    layout: StagingLayout,
+    update_info_address: usize,
+    write_options: WriteOptions,
+    poll_timeout: u32,
+    upload_enabled: bool,
+    state: State,
+    status: Status,
+    pending: Pending,
+    buffer: [u8; TRANSFER_SIZE],
+    buffer_len: usize,
+    /// Bytes downloaded (or uploaded) so far.
+    offset: usize,
+    manifested: bool
+}
+
+impl<'a, T: Flasher, C: Checksum> DfuDevice<'a, T, C>
+{
+    pub fn new(flasher: &'a mut T, checksum: &'a mut C, layout: StagingLayout, update_info_address: usize) -> Self
+    {
+        Self
+        {
+            flasher,
+            checksum,
+            layout,
+            update_info_address,
+            write_options: WriteOptions::default(),
+            poll_timeout: 10,
+            upload_enabled: false,
+            state: State::DfuIdle,
+            status: Status::Ok,
+            pending: Pending::None,
+            buffer: [0; TRANSFER_SIZE],
+            buffer_len: 0,
+            offset: 0,
+            manifested: false
+        }
+    }
+
+    pub fn with_write_options(mut self, write_options: WriteOptions) -> Self
+    {
+        self.write_options = write_options;
+        self
+    }
+
+    /// Time (in ms) the host waits before asking for the status of a busy
+    /// device again. Should cover writing a block.
+    pub fn with_poll_timeout(mut self, poll_timeout: u32) -> Self
+    {
+        self.poll_timeout = poll_timeout;
+        self
+    }
+
+    /// Lets the host read the target slot with UPLOAD, which is off by
+    /// default.
+    pub fn with_upload(mut self, upload: bool) -> Self
+    {
+        self.upload_enabled = upload;
+        self
+    }
+
+    /// The DFU functional descriptor, to be placed behind the interface
+    /// descriptor of the DFU mode interface.
+    pub fn functional_descriptor(&self) -> [u8; 9]
+    {
+        let can_upload = if self.upload_enabled { ATTRIBUTE_CAN_UPLOAD } else { 0 };
+        let detach_timeout = DETACH_TIMEOUT_MS.to_le_bytes();
+        let transfer_size = (TRANSFER_SIZE as u16).to_le_bytes();
+        let version = DFU_VERSION.to_le_bytes();
+        [
+            9,
+            DESCRIPTOR_TYPE_DFU_FUNCTIONAL,
+            ATTRIBUTE_CAN_DOWNLOAD | can_upload | ATTRIBUTE_MANIFESTATION_TOLERANT,
+            detach_timeout[0], detach_timeout[1],
+            transfer_size[0], transfer_size[1],
+            version[0], version[1]
+        ]
+    }
+
+    pub fn state(&self) -> State
+    {
+        self.state
+    }
+
+    /// Set once an update_info was written, the port should reset.
+    pub fn is_manifested(&self) -> bool
+    {
+        self.manifested
+    }
+
+    /// Handles a host to device request. `value` is wValue (the block
+    /// number for DNLOAD), `data` the data stage.
+    pub fn control_out(&mut self, request: u8, _value: u16, data: &[u8]) -> Result<(), Stall>
+    {
+        match (request, self.state)
+        {
+            // Already in DFU mode, the host resets us anyway.
+            (REQUEST_DETACH, _) => Ok(()),
+            (REQUEST_DNLOAD, State::DfuIdle) | (REQUEST_DNLOAD, State::DownloadIdle) => self.download(data),
+            (REQUEST_CLRSTATUS, State::Error) =>
+            {
+                self.reset(Status::Ok);
+                Ok(())
+            },
+            (REQUEST_ABORT, State::DfuIdle) | (REQUEST_ABORT, State::DownloadSync) | (REQUEST_ABORT, State::DownloadIdle) |
+            (REQUEST_ABORT, State::ManifestSync) | (REQUEST_ABORT, State::UploadIdle) =>
+            {
+                self.reset(Status::Ok);
+                Ok(())
+            },
+            _ => self.fail(Status::ErrStalledPacket)
+        }
+    }
+
+    /// Handles a device to host request, yields the length of the data
+    /// stage written to `data`.
+    pub fn control_in(&mut self, request: u8, _value: u16, data: &mut [u8]) -> Result<usize, Stall>
+    {
+        match (request, self.state)
+        {
+            (REQUEST_GETSTATUS, _) if data.len() >= 6 => Ok(self.get_status(data)),
+            (REQUEST_GETSTATE, _) if !data.is_empty() =>
+            {
+                data[0] = self.state as u8;
+                Ok(1)
+            },
+            (REQUEST_UPLOAD, State::DfuIdle) | (REQUEST_UPLOAD, State::UploadIdle) if self.upload_enabled => self.upload(data),
+            _ => self.fail(Status::ErrStalledPacket)
+        }
+    }
+
+    /// Does the flash work requested by the last DNLOAD. Call it from the
+    /// main loop.
+    pub fn poll(&mut self)
+    {
+        match self.pending
+        {
+            Pending::None => {},
+            Pending::Write =>
+            {
+                self.pending = Pending::None;
+                let data = &self.buffer[..self.buffer_len];
+                match super::write_chunk(self.flasher, self.layout.staging + self.offset, data, &self.write_options)
+                {
+                    Ok(()) =>
+                    {
+                        self.checksum.update(data);
+                        self.offset += data.len();
+                        if self.state == State::DownloadBusy
+                        {
+                            self.state = State::DownloadSync;
+                        }
+                    },
+                    Err(WriteError::VerifyFailed { .. }) => self.set_error(Status::ErrVerify),
+                    Err(_) => self.set_error(Status::ErrWrite)
+                }
+            },
+            Pending::Manifest =>
+            {
+                self.pending = Pending::None;
+                match self.manifest()
+                {
+                    Ok(()) =>
+                    {
+                        self.manifested = true;
+                        if self.state == State::Manifest
+                        {
+                            self.state = State::ManifestSync;
+                        }
+                    },
+                    Err(status) => self.set_error(status)
+                }
+            }
+        }
+    }
+
+    fn download(&mut self, data: &[u8]) -> Result<(), Stall>
+    {
+        if data.is_empty()
+        {
+            // Ends the download, there is nothing to manifest in idle.
+            if self.state != State::DownloadIdle
+            {
+                return self.fail(Status::ErrNotDone);
+            }
+            self.pending = Pending::Manifest;
+            self.state = State::ManifestSync;
+            return Ok(());
+        }
+
+        if self.state == State::DfuIdle
+        {
+            self.offset = 0;
+            self.checksum.reset();
+        }
+        if data.len() > TRANSFER_SIZE || self.offset + data.len() > self.layout.slot_size
+        {
+            return self.fail(Status::ErrAddress);
+        }
+        self.buffer[..data.len()].copy_from_slice(data);
+        self.buffer_len = data.len();
+        self.pending = Pending::Write;
+        self.state = State::DownloadSync;
+        Ok(())
+    }
+
+    fn upload(&mut self, data: &mut [u8]) -> Result<usize, Stall>
+    {
+        if self.state == State::DfuIdle
+        {
+            self.offset = 0;
+        }
+        let len = core::cmp::min(core::cmp::min(data.len(), TRANSFER_SIZE), self.layout.slot_size - self.offset);
+        if len > 0 && self.flasher.read(self.layout.target + self.offset, &mut data[..len]) != Ok(len)
+        {
+            return self.fail(Status::ErrUnknown);
+        }
+        self.offset += len;
+        // A short packet ends the upload.
+        self.state = if len < data.len() { State::DfuIdle } else { State::UploadIdle };
+        Ok(len)
+    }
+
+    fn get_status(&mut self, data: &mut [u8]) -> usize
+    {
+        let busy = self.pending != Pending::None;
+        self.state = match self.state
+        {
+            State::DownloadSync if busy => State::DownloadBusy,
+            State::DownloadSync => State::DownloadIdle,
+            State::ManifestSync if busy => State::Manifest,
+            State::ManifestSync => State::DfuIdle,
+            state => state
+        };
+
+        let poll_timeout = if busy { self.poll_timeout } else { 0 }.to_le_bytes();
+        data[0] = self.status as u8;
+        data[1..4].copy_from_slice(&poll_timeout[..3]);
+        data[4] = self.state as u8;
+        data[5] = 0;
+        6
+    }
+
+    /// Checks the staged image and writes its update_info.
+    fn manifest(&mut self) -> Result<(), Status>
+    {
+        self.flasher.flush();
+        let checksum = self.checksum.finish();
+        let info = update_info
+        {
+            magic: *b"MUUPD",
+            struct_ver: 1,
+            update_start: self.layout.staging,
+            update_len: self.offset,
+            target_adress: self.layout.target,
+            checksum: checksum as usize
+        };
+        // Catches MCUboot images with a bad hash and flash that changed
+        // after writing.
+        if !image_installer::check_staged_image(self.layout.staging, &info, self.update_info_address, self.flasher, self.checksum)
+        {
+            return Err(Status::ErrVerify);
+        }
+        let board_id = mcuboot::board_id(self.layout.staging, self.flasher);
+        self.layout.write_update_info(self.update_info_address, self.offset, checksum, board_id, self.flasher)
+            .map_err(|_| Status::ErrWrite)
+    }
+
+    fn reset(&mut self, status: Status)
+    {
+        self.pending = Pending::None;
+        self.status = status;
+        self.state = State::DfuIdle;
+    }
+
+    fn set_error(&mut self, status: Status)
+    {
+        self.pending = Pending::None;
+        self.status = status;
+        self.state = State::Error;
+    }
+
+    fn fail<R>(&mut self, status: Status) -> Result<R, Stall>
+    {
+        self.set_error(status);
+        Err(Stall)
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::crc::Crc32Table;
+    use super::*;
+
+    const LAYOUT: StagingLayout = StagingLayout { staging: 0x2000, target: 0x4000, slot_size: 0x100 };
+
+    /// The host side of a GETSTATUS request, yields (status, state).
+    fn get_status<T: Flasher, C: Checksum>(device: &mut DfuDevice<T, C>) -> (u8, u8)
+    {
+        let mut data = [0u8; 6];
+        assert_eq!(device.control_in(REQUEST_GETSTATUS, 0, &mut data), Ok(6));
+        (data[0], data[4])
+    }
+
+    #[test]
+    fn can_download_image()
+    {
+        let image = test_image();
+        let mut fl = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+        let mut device = DfuDevice::new(&mut fl, &mut crc, LAYOUT, 0x1000);
+
+        for (block, chunk) in image.chunks(100).enumerate()
+        {
+            assert_eq!(device.control_out(REQUEST_DNLOAD, block as u16, chunk), Ok(()));
+            assert_eq!(get_status(&mut device), (Status::Ok as u8, State::DownloadBusy as u8));
+            device.poll();
+            assert_eq!(get_status(&mut device), (Status::Ok as u8, State::DownloadIdle as u8));
+        }
+
+        assert_eq!(device.control_out(REQUEST_DNLOAD, 2, &[]), Ok(()));
+        assert_eq!(get_status(&mut device), (Status::Ok as u8, State::Manifest as u8));
+        device.poll();
+        assert_eq!(get_status(&mut device), (Status::Ok as u8, State::DfuIdle as u8));
+        assert!(device.is_manifested());
+
+        assert!(fl.memory[0x2000..0x2080] == image);
+        let info: crate::update_info = crate::load_info_struct_from_address(0x1000, &fl).unwrap();
+        assert_eq!((info.update_start, info.update_len, info.target_adress), (0x2000, 0x80, 0x4000));
+        let mut engine = Crc32Table::new();
+        assert!(crate::image_installer::check_update_integrity(&info, 0x1000, None, &fl, &mut engine));
+        // Plain images carry no board id.
+        assert!(crate::tlv::update_info_metadata(0x1000, &fl).is_none());
+    }
+
+    #[test]
+    fn board_id_is_taken_from_mcuboot_image()
+    {
+        let mut fl = FakeFlasher::new();
+        let len = write_mcuboot_image_for_board(&mut fl, 0x6000, (1, 2, 3), Some(TEST_BOARD_ID));
+        let mut image = [0u8; 0x100];
+        image[..len].copy_from_slice(&fl.memory[0x6000..0x6000 + len]);
+
+        let mut crc = Crc32Table::new();
+        let mut device = DfuDevice::new(&mut fl, &mut crc, LAYOUT, 0x1000);
+        assert_eq!(device.control_out(REQUEST_DNLOAD, 0, &image[..len]), Ok(()));
+        device.poll();
+        assert_eq!(get_status(&mut device), (Status::Ok as u8, State::DownloadIdle as u8));
+        assert_eq!(device.control_out(REQUEST_DNLOAD, 1, &[]), Ok(()));
+        device.poll();
+        get_status(&mut device);
+        assert_eq!(get_status(&mut device), (Status::Ok as u8, State::DfuIdle as u8));
+
+        let board_id = crate::tlv::update_info_metadata(0x1000, &fl).unwrap().find(crate::tlv::TAG_BOARD_ID).unwrap();
+        assert_eq!(board_id.read_u32(&fl), Some(TEST_BOARD_ID));
+    }
+
+    #[test]
+    fn reports_errors_and_uploads()
+    {
+        let image = test_image();
+        let mut fl = FakeFlasher::new();
+        copy_to_flasher(&mut fl, 0x4000, &image);
+        let mut crc = Crc32Table::new();
+        let mut device = DfuDevice::new(&mut fl, &mut crc, LAYOUT, 0x1000).with_upload(true);
+        assert_eq!(device.functional_descriptor()[2], ATTRIBUTE_CAN_DOWNLOAD | ATTRIBUTE_CAN_UPLOAD | ATTRIBUTE_MANIFESTATION_TOLERANT);
+
+        // Ending a download that never started
+        assert_eq!(device.control_out(REQUEST_DNLOAD, 0, &[]), Err(Stall));
+        assert_eq!(get_status(&mut device), (Status::ErrNotDone as u8, State::Error as u8));
+        assert_eq!(device.control_out(REQUEST_DNLOAD, 0, &image), Err(Stall));
+        assert_eq!(device.control_out(REQUEST_CLRSTATUS, 0, &[]), Ok(()));
+
+        // Beyond the slot
+        assert_eq!(device.control_out(REQUEST_DNLOAD, 0, &image), Ok(()));
+        device.poll();
+        assert_eq!(get_status(&mut device), (Status::Ok as u8, State::DownloadIdle as u8));
+        assert_eq!(device.control_out(REQUEST_DNLOAD, 1, &image), Ok(()));
+        device.poll();
+        assert_eq!(get_status(&mut device), (Status::Ok as u8, State::DownloadIdle as u8));
+        assert_eq!(device.control_out(REQUEST_DNLOAD, 2, &image[..1]), Err(Stall));
+        assert_eq!(get_status(&mut device), (Status::ErrAddress as u8, State::Error as u8));
+        assert_eq!(device.control_out(REQUEST_CLRSTATUS, 0, &[]), Ok(()));
+
+        let mut data = [0u8; 0xC0];
+        assert_eq!(device.control_in(REQUEST_UPLOAD, 0, &mut data), Ok(0xC0));
+        assert_eq!(device.state(), State::UploadIdle);
+        assert!(data[..0x80] == image);
+        assert_eq!(device.control_in(REQUEST_UPLOAD, 1, &mut data), Ok(0x40));
+        assert_eq!(device.state(), State::DfuIdle);
+        assert!(!device.is_manifested());
+    }
+
+    #[test]
+    fn upload_is_off_by_default()
+    {
+        let mut fl = FakeFlasher::new();
+        copy_to_flasher(&mut fl, 0x4000, &test_image());
+        let mut crc = Crc32Table::new();
+        let mut device = DfuDevice::new(&mut fl, &mut crc, LAYOUT, 0x1000);
+        assert_eq!(device.functional_descriptor()[2], ATTRIBUTE_CAN_DOWNLOAD | ATTRIBUTE_MANIFESTATION_TOLERANT);
+
+        let mut data = [0u8; 0x80];
+        assert_eq!(device.control_in(REQUEST_UPLOAD, 0, &mut data), Err(Stall));
+        assert_eq!(get_status(&mut device), (Status::ErrStalledPacket as u8, State::Error as u8));
+        assert!(data.iter().all(|byte| *byte == 0));
+    }
+}
@@ -0,0 +1,181 @@
+//! Image digests besides the CRC-32 in the `checksum` field of
+//! `bin_info`/`update_info`.
+//!
+//! An image selects its digest by carrying a TAG_DIGEST entry in its
+//! metadata area. The value of the entry is the algorithm id followed by the
+//! expected digest (CRC-32C little endian, SHA-256 as produced by the hash).
+//! Images without a TAG_DIGEST entry are checked using the CRC-32 in the
+//! `checksum` field, as before.
+
+use super::{Flasher, crc, tlv};
+use super::crc::{Checksum, Crc32cTable};
+use super::sha256::{Sha256, SHA256_DIGEST_SIZE};
+
+pub const DIGEST_CRC32C: u8 = 0x01;
+pub const DIGEST_SHA256: u8 = 0x02;
+
+pub const MAX_DIGEST_SIZE: usize = SHA256_DIGEST_SIZE;
+
+/// The expected digest of an image.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ImageDigest
+{
+    pub algorithm: u8,
+    value: [u8; MAX_DIGEST_SIZE],
+    len: usize
+}
+
+impl ImageDigest
+{
+    /// Decodes the value of a TAG_DIGEST entry. Unknown algorithms are
+    /// kept, they never match anything.
+    pub fn from_value(value: &[u8]) -> Option<Self>
+    {
+        if value.is_empty() || value.len() > MAX_DIGEST_SIZE + 1
+        {
+            return None;
+        }
+
+        let mut digest = Self { algorithm: value[0], value: [0; MAX_DIGEST_SIZE], len: value.len() - 1 };
+        digest.value[..digest.len].copy_from_slice(&value[1..]);
+        Some(digest)
+    }
+
+    /// Looks up the digest of an image in its metadata area in flash.
+    pub fn from_metadata<T>(area: Option<tlv::TlvIter<'_, T>>, flasher: &T) -> Option<Self>
+        where T: Flasher
+    {
+        let entry = area?.find(tlv::TAG_DIGEST)?;
+        let mut buf = [0u8; MAX_DIGEST_SIZE + 1];
+        match entry.read_value(flasher, &mut buf)
+        {
+            Ok(len) if len == entry.len => Some(Self::from_value(&buf[..len]).unwrap_or(Self::invalid())),
+            _ => Some(Self::invalid())
+        }
+    }
+
+    /// A digest that never matches. Used for broken TAG_DIGEST entries, such
+    /// that the image is rejected instead of falling back to the CRC-32.
+    pub fn invalid() -> Self
+    {
+        Self { algorithm: 0xFF, value: [0; MAX_DIGEST_SIZE], len: 0 }
+    }
+
+    pub fn value(&self) -> &[u8]
+    {
+        &self.value[..self.len]
+    }
+}
+
+/// Streaming state for any of the supported digests.
+pub enum DigestEngine
+{
+    Crc32c(Crc32cTable),
+    Sha256(Sha256)
+}
+
+impl DigestEngine
+{
+    pub fn new(algorithm: u8) -> Option<Self>
+    {
+        match algorithm
+        {
+            DIGEST_CRC32C => Some(DigestEngine::Crc32c(Crc32cTable::new())),
+            DIGEST_SHA256 => Some(DigestEngine::Sha256(Sha256::new())),
+            _ => None
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8])
+    {
+        match self
+        {
+            DigestEngine::Crc32c(engine) => engine.update(data),
+            DigestEngine::Sha256(engine) => engine.update(data)
+        }
+    }
+
+    /// Finishes the calculation and compares the result with `expected`.
+    pub fn matches(&mut self, expected: &ImageDigest) -> bool
+    {
+        match self
+        {
+            DigestEngine::Crc32c(engine) => expected.algorithm == DIGEST_CRC32C && expected.value() == engine.finish().to_le_bytes(),
+            DigestEngine::Sha256(engine) => expected.algorithm == DIGEST_SHA256 && expected.value() == engine.finish()
+        }
+    }
+}
+
+/// Checks `len` bytes starting at `start_adr` against `digest`.
+pub fn check_digest<T>(start_adr: usize, len: usize, digest: &ImageDigest, flasher: &T) -> bool
+    where T: Flasher
+{
+    let mut engine = match DigestEngine::new(digest.algorithm)
+    {
+        Some(engine) => engine,
+        None => return false
+    };
+
+    if !crc::read_chunks(start_adr, len, flasher, |chunk| engine.update(chunk))
+    {
+        return false;
+    }
+
+    engine.matches(digest)
+}
+
+/// Checks an image either against its digest (if it has one) or
+/// against the CRC-32 `checksum`.
+pub fn check_image<T, C>(start_adr: usize, len: usize, checksum: usize, digest: Option<&ImageDigest>, flasher: &T, engine: &mut C) -> bool
+    where T: Flasher, C: Checksum
+{
+    match digest
+    {
+        Some(digest) => check_digest(start_adr, len, digest, flasher),
+        None => crc::check_crc(start_adr, len, checksum, flasher, engine)
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::crc::Crc32Table;
+    use super::*;
+
+    const SHA256_123456789: [u8; 32] = [0x15, 0xe2, 0xb0, 0xd3, 0xc3, 0x38, 0x91, 0xeb, 0xb0, 0xf1, 0xef, 0x60, 0x9e, 0xc4, 0x19, 0x42,
+                                        0x0c, 0x20, 0xe3, 0x20, 0xce, 0x94, 0xc6, 0x5f, 0xbc, 0x8c, 0x33, 0x12, 0x44, 0x8e, 0xb2, 0x25];
+
+    #[test]
+    fn can_check_sha256_and_crc32c_digests()
+    {
+        let mut fl = FakeFlasher::new();
+        copy_to_flasher(&mut fl, 0x1000, b"123456789");
+
+        let mut value = [0u8; 33];
+        value[0] = DIGEST_SHA256;
+        value[1..].copy_from_slice(&SHA256_123456789);
+        assert!(check_digest(0x1000, 9, &ImageDigest::from_value(&value).unwrap(), &fl));
+        assert!(!check_digest(0x1000, 8, &ImageDigest::from_value(&value).unwrap(), &fl));
+
+        let crc32c = ImageDigest::from_value(&[DIGEST_CRC32C, 0x83, 0x92, 0x06, 0xE3]).unwrap();
+        assert!(check_digest(0x1000, 9, &crc32c, &fl));
+    }
+
+    #[test]
+    fn digest_from_metadata_overrides_checksum()
+    {
+        let mut fl = FakeFlasher::new();
+        copy_to_flasher(&mut fl, 0x1000, b"123456789");
+        write_metadata(&mut fl, 0x100, &[(tlv::TAG_DIGEST, &[DIGEST_CRC32C, 0x83, 0x92, 0x06, 0xE3])]);
+
+        let digest = ImageDigest::from_metadata(tlv::read_area(0x100, &fl), &fl);
+        assert!(check_image(0x1000, 9, 0xBAD, digest.as_ref(), &fl, &mut Crc32Table::new()));
+
+        // Unknown algorithms must not fall back to the CRC-32.
+        write_metadata(&mut fl, 0x100, &[(tlv::TAG_DIGEST, &[0x7F, 0x00])]);
+        let digest = ImageDigest::from_metadata(tlv::read_area(0x100, &fl), &fl);
+        assert!(!check_image(0x1000, 9, 0xCBF43926, digest.as_ref(), &fl, &mut Crc32Table::new()));
+        assert!(check_image(0x1000, 9, 0xCBF43926, None, &fl, &mut Crc32Table::new()));
+    }
+}
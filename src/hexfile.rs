@@ -0,0 +1,527 @@
+//! Intel HEX and Motorola S-record input, for build pipelines that emit
+//! `.hex`/`.srec` files (possibly with gaps) instead of raw binaries.
+//!
+//! ```text
+//! Intel HEX: :LLAAAATT<data>CC    CC: two's complement of the sum of all bytes
+//! S-record:  STLLAAAA<data>CC     CC: one's complement of the sum of LL, address and data
+//! ```
+//!
+//! Intel HEX record types 00 (data), 01 (end of file), 02 (extended segment
+//! address) and 04 (extended linear address) are supported, 03 and 05
+//! (start address) are ignored. S-records S1/S2/S3 carry data with 16, 24
+//! and 32 bit addresses, S7/S8/S9 end the file, S0/S5/S6 are ignored.
+//!
+//! `RecordDecoder` is fed byte by byte and checks every record as soon as
+//! its line is complete. `HexReceiver` writes the records of a file sent
+//! as plain text over the UART (e.g. `cat app.hex > /dev/ttyUSB0`) to the
+//! staging area: addresses are image addresses within the target slot,
+//! they have to ascend, gaps are filled with 0xFF. The end record writes
+//! the update_info, which installs the image to the target slot. Its board
+//! id is taken from the `mcuboot::TLV_BOARD_ID` of the image, other images
+//! get none.
+
+use super::{Flasher, StagingLayout, WriteOptions, mcuboot};
+use super::crc::Checksum;
+use super::transport::Transport;
+
+/// Largest record: count, 4 address bytes, 255 data bytes and checksum
+/// (the count covers address and checksum for S-records).
+const MAX_RECORD_SIZE: usize = 1 + 4 + 255 + 1;
+
+/// Sent before the receiver writes to flash, hosts sending plain text
+/// rely on software flow control.
+pub const XOFF: u8 = 0x13;
+pub const XON: u8 = 0x11;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HexError
+{
+    /// A character that is not a hex digit, an odd number of digits or a
+    /// length that does not match the count.
+    BadSyntax,
+    BadChecksum,
+    UnsupportedRecord,
+    /// The record does not fit into the target slot.
+    AddressOutOfRange,
+    /// The record overlaps data that was already written.
+    OutOfOrder,
+    WriteFailed,
+    /// The file ended without any data.
+    Empty
+}
+
+impl HexError
+{
+    /// The text sent to the host, after "ERR ".
+    pub fn message(&self) -> &'static str
+    {
+        match self
+        {
+            HexError::BadSyntax => "bad syntax",
+            HexError::BadChecksum => "bad checksum",
+            HexError::UnsupportedRecord => "unsupported record",
+            HexError::AddressOutOfRange => "address out of range",
+            HexError::OutOfOrder => "address out of order",
+            HexError::WriteFailed => "write failed",
+            HexError::Empty => "no data"
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Record<'a>
+{
+    Data { address: usize, data: &'a [u8] },
+    /// End of file.
+    End,
+    /// A valid record without data (header, count, start address, ...).
+    Other
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Format
+{
+    Intel,
+    /// Holds the record type digit.
+    SRecord(u8)
+}
+
+pub struct RecordDecoder
+{
+    /// Set while a line is received.
+    format: Option<Format>,
+    /// Set after 'S' until the type digit arrived.
+    srecord_type_pending: bool,
+    bytes: [u8; MAX_RECORD_SIZE],
+    len: usize,
+    high_nibble: Option<u8>,
+    /// Set by a bad character, the rest of the line is dropped.
+    bad_syntax: bool,
+    /// Extended segment/linear address of Intel HEX.
+    base_address: usize
+}
+
+impl Default for RecordDecoder
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl RecordDecoder
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            format: None,
+            srecord_type_pending: false,
+            bytes: [0; MAX_RECORD_SIZE],
+            len: 0,
+            high_nibble: None,
+            bad_syntax: false,
+            base_address: 0
+        }
+    }
+
+    /// Forgets the extended address, for the next file.
+    pub fn reset(&mut self)
+    {
+        *self = Self::new();
+    }
+
+    /// Feeds the next character. Yields the record once its line is
+    /// complete. Anything between records (blank lines, console output) is
+    /// ignored.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Record<'_>, HexError>>
+    {
+        let format = match self.format
+        {
+            Some(format) => format,
+            None =>
+            {
+                match byte
+                {
+                    b':' => self.start(Format::Intel),
+                    b'S' =>
+                    {
+                        self.start(Format::SRecord(0));
+                        self.srecord_type_pending = true;
+                    },
+                    _ => {}
+                }
+                return None;
+            }
+        };
+
+        if byte == b'\r' || byte == b'\n'
+        {
+            self.format = None;
+            if self.bad_syntax || self.srecord_type_pending || self.high_nibble.is_some()
+            {
+                return Some(Err(HexError::BadSyntax));
+            }
+            return Some(match format
+            {
+                Format::Intel => self.intel_record(),
+                Format::SRecord(kind) => self.srecord(kind)
+            });
+        }
+
+        let digit = match (byte as char).to_digit(16)
+        {
+            Some(digit) if !self.bad_syntax => digit as u8,
+            _ =>
+            {
+                self.bad_syntax = true;
+                return None;
+            }
+        };
+        if self.srecord_type_pending
+        {
+            self.srecord_type_pending = false;
+            self.format = Some(Format::SRecord(digit));
+            return None;
+        }
+        match self.high_nibble.take()
+        {
+            None => self.high_nibble = Some(digit),
+            Some(_) if self.len == MAX_RECORD_SIZE => self.bad_syntax = true,
+            Some(high) =>
+            {
+                self.bytes[self.len] = (high << 4) | digit;
+                self.len += 1;
+            }
+        }
+        None
+    }
+
+    fn start(&mut self, format: Format)
+    {
+        self.format = Some(format);
+        self.len = 0;
+        self.high_nibble = None;
+        self.bad_syntax = false;
+    }
+
+    fn intel_record(&mut self) -> Result<Record<'_>, HexError>
+    {
+        let bytes = &self.bytes[..self.len];
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5
+        {
+            return Err(HexError::BadSyntax);
+        }
+        if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0
+        {
+            return Err(HexError::BadChecksum);
+        }
+
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3]
+        {
+            0x00 => Ok(Record::Data { address: self.base_address + offset, data }),
+            0x01 => Ok(Record::End),
+            0x02 | 0x04 if data.len() == 2 =>
+            {
+                let shift = if bytes[3] == 0x02 { 4 } else { 16 };
+                self.base_address = (u16::from_be_bytes([data[0], data[1]]) as usize) << shift;
+                Ok(Record::Other)
+            },
+            0x03 | 0x05 => Ok(Record::Other),
+            _ => Err(HexError::UnsupportedRecord)
+        }
+    }
+
+    fn srecord(&self, kind: u8) -> Result<Record<'_>, HexError>
+    {
+        let address_len = match kind
+        {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(HexError::UnsupportedRecord)
+        };
+        let bytes = &self.bytes[..self.len];
+        if bytes.len() < 2 + address_len || bytes.len() != bytes[0] as usize + 1
+        {
+            return Err(HexError::BadSyntax);
+        }
+        if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xFF
+        {
+            return Err(HexError::BadChecksum);
+        }
+
+        let address = bytes[1..1 + address_len].iter().fold(0usize, |address, byte| (address << 8) | *byte as usize);
+        match kind
+        {
+            1..=3 => Ok(Record::Data { address, data: &bytes[1 + address_len..bytes.len() - 1] }),
+            7..=9 => Ok(Record::End),
+            _ => Ok(Record::Other)
+        }
+    }
+}
+
+pub struct HexReceiver<'a, T: Flasher, U: Transport, C: Checksum>
+{
+    flasher: &'a mut T,
+    uart: &'a mut U,
+    checksum: &'a mut C,
+    layout: StagingLayout,
+    write_options: WriteOptions,
+    /// Number of bytes written to the staging area.
+    len: usize
+}
+
+impl <'a, T: Flasher, U: Transport, C: Checksum> HexReceiver<'a, T, U, C>
+{
+    pub fn new(flasher: &'a mut T, uart: &'a mut U, checksum: &'a mut C, layout: StagingLayout) -> Self
+    {
+        Self
+        {
+            flasher,
+            uart,
+            checksum,
+            layout,
+            write_options: WriteOptions::default(),
+            len: 0
+        }
+    }
+
+    pub fn with_write_options(mut self, write_options: WriteOptions) -> Self
+    {
+        self.write_options = write_options;
+        self
+    }
+
+    /// Receives files until one was written completely. A bad record is
+    /// reported with "ERR <reason>", the rest of that file is dropped.
+    /// A complete file is answered with "OK".
+    pub fn execute(mut self, update_info_address: usize)
+    {
+        let mut decoder = RecordDecoder::new();
+        let mut failed = false;
+        loop
+        {
+            let byte = self.get_byte();
+            let result = match decoder.push(byte)
+            {
+                None => continue,
+                Some(Ok(Record::End)) if failed =>
+                {
+                    failed = false;
+                    self.restart(&mut decoder);
+                    continue;
+                },
+                Some(_) if failed => continue,
+                Some(Ok(Record::Data { address, data })) => self.write_record(address, data),
+                Some(Ok(Record::Other)) => Ok(()),
+                Some(Ok(Record::End)) => match self.finish(update_info_address)
+                {
+                    Ok(()) =>
+                    {
+                        self.send(b"OK\r\n");
+                        return;
+                    },
+                    Err(error) =>
+                    {
+                        // There is nothing left of this file to drop.
+                        self.report(error);
+                        self.restart(&mut decoder);
+                        continue;
+                    }
+                },
+                Some(Err(error)) => Err(error)
+            };
+
+            if let Err(error) = result
+            {
+                self.report(error);
+                failed = true;
+            }
+        }
+    }
+
+    fn report(&mut self, error: HexError)
+    {
+        self.uart.write_all(b"ERR ");
+        self.uart.write_all(error.message().as_bytes());
+        self.send(b"\r\n");
+    }
+
+    fn finish(&mut self, update_info_address: usize) -> Result<(), HexError>
+    {
+        if self.len == 0
+        {
+            return Err(HexError::Empty);
+        }
+        self.flasher.flush();
+        let board_id = mcuboot::board_id(self.layout.staging, self.flasher);
+        self.layout.write_update_info(update_info_address, self.len, self.checksum.finish(), board_id, self.flasher)
+            .map_err(|_| HexError::WriteFailed)
+    }
+
+    fn restart(&mut self, decoder: &mut RecordDecoder)
+    {
+        decoder.reset();
+        self.checksum.reset();
+        self.len = 0;
+    }
+
+    fn get_byte(&mut self) -> u8
+    {
+        loop
+        {
+            if let Some(byte) = self.uart.read_byte()
+            {
+                return byte;
+            }
+        }
+    }
+
+    fn send(&mut self, text: &[u8])
+    {
+        self.uart.write_all(text);
+        self.uart.flush();
+    }
+
+    fn write_record(&mut self, address: usize, data: &[u8]) -> Result<(), HexError>
+    {
+        let offset = match address.checked_sub(self.layout.target)
+        {
+            Some(offset) if offset + data.len() <= self.layout.slot_size => offset,
+            _ => return Err(HexError::AddressOutOfRange)
+        };
+        if offset < self.len
+        {
+            return Err(HexError::OutOfOrder);
+        }
+
+        self.send(&[XOFF]);
+        let result = self.fill_gap(offset)
+            .and_then(|_| super::write_chunk(self.flasher, self.layout.staging + offset, data, &self.write_options));
+        self.send(&[XON]);
+        result.map_err(|_| HexError::WriteFailed)?;
+
+        self.checksum.update(data);
+        self.len = offset + data.len();
+        Ok(())
+    }
+
+    /// Fills the staging area up to `offset` with erased bytes, so the
+    /// checksum covers a contiguous image.
+    fn fill_gap(&mut self, offset: usize) -> Result<(), super::WriteError>
+    {
+        let erased = [0xFFu8; 64];
+        while self.len < offset
+        {
+            let chunk = &erased[..core::cmp::min(erased.len(), offset - self.len)];
+            super::write_chunk(self.flasher, self.layout.staging + self.len, chunk, &self.write_options)?;
+            self.checksum.update(chunk);
+            self.len += chunk.len();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::crc::Crc32Table;
+    use super::*;
+
+    fn push_line<'a>(decoder: &'a mut RecordDecoder, line: &[u8]) -> Option<Result<Record<'a>, HexError>>
+    {
+        for byte in line
+        {
+            assert_eq!(decoder.push(*byte), None);
+        }
+        decoder.push(b'\n')
+    }
+
+    #[test]
+    fn can_decode_records()
+    {
+        let mut decoder = RecordDecoder::new();
+        assert_eq!(push_line(&mut decoder, b":020000040001F9"), Some(Ok(Record::Other)));
+        assert_eq!(push_line(&mut decoder, b":04400000DEADBEEF84"), Some(Ok(Record::Data { address: 0x14000, data: &[0xDE, 0xAD, 0xBE, 0xEF] })));
+        assert_eq!(push_line(&mut decoder, b":04400000DEADBEEF85"), Some(Err(HexError::BadChecksum)));
+        assert_eq!(push_line(&mut decoder, b":04400000DEADBEEF"), Some(Err(HexError::BadSyntax)));
+        assert_eq!(push_line(&mut decoder, b":0440000XDEADBEEF84"), Some(Err(HexError::BadSyntax)));
+        assert_eq!(push_line(&mut decoder, b":0400000500014000B6"), Some(Ok(Record::Other)));
+        assert_eq!(push_line(&mut decoder, b":00000001FF"), Some(Ok(Record::End)));
+
+        assert_eq!(push_line(&mut decoder, b"S0060000617070B8"), Some(Ok(Record::Other)));
+        assert_eq!(push_line(&mut decoder, b"S30900004000DEADBEEF7E"), Some(Ok(Record::Data { address: 0x4000, data: &[0xDE, 0xAD, 0xBE, 0xEF] })));
+        assert_eq!(push_line(&mut decoder, b"S206004010CAFEE1"), Some(Ok(Record::Data { address: 0x4010, data: &[0xCA, 0xFE] })));
+        assert_eq!(push_line(&mut decoder, b"S206004010CAFEE2"), Some(Err(HexError::BadChecksum)));
+        assert_eq!(push_line(&mut decoder, b"S40500004000BA"), Some(Err(HexError::UnsupportedRecord)));
+        assert_eq!(push_line(&mut decoder, b"S70500004000BA"), Some(Ok(Record::End)));
+    }
+
+    #[test]
+    fn can_receive_files()
+    {
+        let layout = StagingLayout { staging: 0x2000, target: 0x4000, slot_size: 0x1000 };
+        let mut fl = FakeFlasher::new();
+        let mut uart = FakeUart::new();
+        // The bad record drops the first file.
+        copy_to_uart(&mut uart, b":020000040000FA\r\n:04400000DEADBEEF85\r\n:02401000CAFEE6\r\n:00000001FF\r\n");
+        copy_to_uart(&mut uart, b"S0060000617070B8\r\nS30900004000DEADBEEF7E\r\nS206004010CAFEE1\r\nS70500004000BA\r\n");
+
+        let mut crc = Crc32Table::new();
+        HexReceiver::new(&mut fl, &mut uart, &mut crc, layout).execute(0x1000);
+
+        assert_eq!(&uart.out_buf[..uart.write_index], b"ERR bad checksum\r\n\x13\x11\x13\x11OK\r\n");
+        let mut image = [0xFFu8; 0x12];
+        image[..4].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        image[0x10..].copy_from_slice(&[0xCA, 0xFE]);
+        assert!(fl.memory[0x2000..0x2012] == image);
+
+        let info: crate::update_info = crate::load_info_struct_from_address(0x1000, &fl).unwrap();
+        assert_eq!((info.update_start, info.update_len, info.target_adress), (0x2000, 0x12, 0x4000));
+        let mut engine = Crc32Table::new();
+        engine.update(&image);
+        assert_eq!(info.checksum, engine.finish() as usize);
+        // Plain images carry no board id.
+        assert!(crate::tlv::update_info_metadata(0x1000, &fl).is_none());
+    }
+
+    #[test]
+    fn board_id_is_taken_from_mcuboot_image()
+    {
+        let layout = StagingLayout { staging: 0x2000, target: 0x4000, slot_size: 0x1000 };
+        let mut fl = FakeFlasher::new();
+        let len = write_mcuboot_image_for_board(&mut fl, 0x6000, (1, 2, 3), Some(TEST_BOARD_ID));
+
+        let mut uart = FakeUart::new();
+        for (index, chunk) in fl.memory[0x6000..0x6000 + len].chunks(16).enumerate()
+        {
+            let address = 0x4000 + index as u16 * 16;
+            let mut record = [0u8; 21];
+            record[0] = chunk.len() as u8;
+            record[1..3].copy_from_slice(&address.to_be_bytes());
+            record[4..4 + chunk.len()].copy_from_slice(chunk);
+            record[4 + chunk.len()] = record[..4 + chunk.len()].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+            let mut line = [0u8; 44];
+            line[0] = b':';
+            for (position, byte) in record[..5 + chunk.len()].iter().enumerate()
+            {
+                line[1 + position * 2] = b"0123456789ABCDEF"[(byte >> 4) as usize];
+                line[2 + position * 2] = b"0123456789ABCDEF"[(byte & 0x0F) as usize];
+            }
+            copy_to_uart(&mut uart, &line[..11 + chunk.len() * 2]);
+            copy_to_uart(&mut uart, b"\r\n");
+        }
+        copy_to_uart(&mut uart, b":00000001FF\r\n");
+
+        let mut crc = Crc32Table::new();
+        HexReceiver::new(&mut fl, &mut uart, &mut crc, layout).execute(0x1000);
+
+        assert!(fl.memory[0x2000..0x2000 + len] == fl.memory[0x6000..0x6000 + len]);
+        let board_id = crate::tlv::update_info_metadata(0x1000, &fl).unwrap().find(crate::tlv::TAG_BOARD_ID).unwrap();
+        assert_eq!(board_id.read_u32(&fl), Some(TEST_BOARD_ID));
+    }
+}
@@ -1,8 +1,205 @@
-use super::{update_info, Flasher, UpdateEncoding, crc};
+use super::{update_info, Flasher, InstallError, SecurityCounter, UpdateEncoding, WriteError, WriteOptions, delta, digest, mcuboot, tlv};
+use super::crc::{self, Checksum};
+use super::sha256::{Sha256, SHA256_DIGEST_SIZE};
 
+const MAX_SIGNATURE_SIZE: usize = 128;
 
-pub fn check_update<T>(data: &update_info, flasher: &T) -> bool
+/// Yields the version in the header of a staged MCUboot image, which is
+/// covered by its hash, or otherwise the version stored in the metadata of
+/// the update_info at the given address. Images without a version are
+/// treated as version 0.
+pub fn update_version<T>(update_info_address: usize, flasher: &T) -> u32
 where T: Flasher
+{
+    super::load_info_struct_from_address::<update_info, T>(update_info_address, flasher).ok()
+        .and_then(|data| mcuboot::ImageHeader::read(data.update_start, flasher))
+        .map(|header| header.image_version())
+        .or_else(|| tlv::update_info_metadata(update_info_address, flasher)
+            .and_then(|area| area.find(tlv::TAG_IMAGE_VERSION))
+            .and_then(|entry| entry.read_u32(flasher)))
+        .unwrap_or(0)
+}
+
+/// Yields the encoding of the update_info at the given address. Images
+/// without TAG_ENCODING are raw, unknown encodings yield None.
+pub fn update_encoding<T>(update_info_address: usize, flasher: &T) -> Option<UpdateEncoding>
+where T: Flasher
+{
+    match tlv::update_info_metadata(update_info_address, flasher).and_then(|area| area.find(tlv::TAG_ENCODING))
+    {
+        Some(entry) => entry.read_u32(flasher).and_then(UpdateEncoding::from_u32),
+        None => Some(UpdateEncoding::Raw)
+    }
+}
+
+/// Yields the flags (TAG_FLAGS) of the update_info at the given address.
+pub fn update_flags<T>(update_info_address: usize, flasher: &T) -> u32
+where T: Flasher
+{
+    tlv::update_info_metadata(update_info_address, flasher)
+        .and_then(|area| area.find(tlv::TAG_FLAGS))
+        .and_then(|entry| entry.read_u32(flasher))
+        .unwrap_or(0)
+}
+
+fn check_version<T, S>(data: &update_info, update_info_address: usize, flasher: &T, counter: &S) -> bool
+where T: Flasher, S: SecurityCounter
+{
+    if update_version(update_info_address, flasher) >= counter.min_version()
+    {
+        return true;
+    }
+
+    // This is a downgrade, which is only allowed if the image
+    // explicitly asks for it and is signed.
+    if update_flags(update_info_address, flasher) & tlv::FLAG_DOWNGRADE_PERMITTED == 0
+    {
+        return false;
+    }
+
+    check_signature(data, update_info_address, flasher, |hash, signature| counter.verify_downgrade(hash, signature))
+}
+
+/// Yields the SHA-256 a signature (TAG_SIGNATURE) of the update_info at the
+/// given address is made over:
+///
+/// ```text
+/// | target_adress: u64 | update_len: u64 | tag: u16 | len: u16 | value[len] | tag ... |
+/// ```
+///
+/// i.e. the target and the length of the update followed by all entries of
+/// its metadata area except TAG_SIGNATURE, as stored (little endian). The
+/// entries have to include a SHA-256 TAG_DIGEST that matches the staged
+/// image, so the signature covers the image through it. Yields None for
+/// updates without such a digest.
+pub fn signed_hash<T>(data: &update_info, update_info_address: usize, flasher: &T) -> Option<[u8; SHA256_DIGEST_SIZE]>
+where T: Flasher
+{
+    // A CRC-32 can be forged, anything signed has to be bound to the image
+    // by a cryptographic digest.
+    let image_digest = digest::ImageDigest::from_metadata(tlv::update_info_metadata(update_info_address, flasher), flasher)?;
+    if image_digest.algorithm != digest::DIGEST_SHA256 || !digest::check_digest(data.update_start, data.update_len, &image_digest, flasher)
+    {
+        return None;
+    }
+
+    let mut sha = Sha256::new();
+    sha.update(&(data.target_adress as u64).to_le_bytes());
+    sha.update(&(data.update_len as u64).to_le_bytes());
+    for entry in tlv::update_info_metadata(update_info_address, flasher)?.filter(|entry| entry.tag != tlv::TAG_SIGNATURE)
+    {
+        sha.update(&entry.tag.to_le_bytes());
+        sha.update(&(entry.len as u16).to_le_bytes());
+        if !crc::read_chunks(entry.value_address, entry.len, flasher, |chunk| sha.update(chunk))
+        {
+            return None;
+        }
+    }
+    Some(sha.finish())
+}
+
+/// Passes the signed hash (see `signed_hash`) and the signature of the
+/// update_info at the given address to `verify`, updates without a
+/// signature or a SHA-256 digest are rejected.
+fn check_signature<T, F>(data: &update_info, update_info_address: usize, flasher: &T, verify: F) -> bool
+where T: Flasher, F: FnOnce(&[u8; SHA256_DIGEST_SIZE], &[u8]) -> bool
+{
+    let signature = match tlv::update_info_metadata(update_info_address, flasher).and_then(|area| area.find(tlv::TAG_SIGNATURE))
+    {
+        Some(entry) => entry,
+        None => return false
+    };
+    let hash = match signed_hash(data, update_info_address, flasher)
+    {
+        Some(hash) => hash,
+        None => return false
+    };
+
+    let mut buf = [0u8; MAX_SIGNATURE_SIZE];
+    match signature.read_value(flasher, &mut buf)
+    {
+        Ok(len) if len == signature.len => verify(&hash, &buf[..len]),
+        _ => false
+    }
+}
+
+/// Checks that an update which changes the device configuration (e.g. the
+/// partition table) is signed by someone the port trusts.
+pub fn check_authorization<T, S>(data: &update_info, update_info_address: usize, flasher: &T, counter: &S) -> bool
+where T: Flasher, S: SecurityCounter
+{
+    check_signature(data, update_info_address, flasher, |hash, signature| counter.verify_privileged(hash, signature))
+}
+
+/// Raises the security counter to the version of the update, should
+/// be called once the update was installed successfully.
+pub fn commit_version<T, S>(update_info_address: usize, flasher: &T, counter: &mut S)
+where T: Flasher, S: SecurityCounter
+{
+    let version = update_version(update_info_address, flasher);
+    if version > counter.min_version()
+    {
+        counter.set_min_version(version);
+    }
+}
+
+/// Images must declare the board they were built for, anything
+/// without a matching board id might brick the device. Without a board id
+/// of our own every image passes.
+fn check_board_id<T>(update_info_address: usize, flasher: &T, board_id: Option<u32>) -> bool
+where T: Flasher
+{
+    let board_id = match board_id
+    {
+        Some(board_id) => board_id,
+        None => return true
+    };
+
+    let image_board_id = tlv::update_info_metadata(update_info_address, flasher)
+        .and_then(|area| area.find(tlv::TAG_BOARD_ID))
+        .and_then(|entry| entry.read_u32(flasher));
+
+    image_board_id == Some(board_id)
+}
+
+pub fn check_update<T, S, C>(data: &update_info, update_info_address: usize, board_id: Option<u32>, flasher: &T, counter: &S, engine: &mut C) -> bool
+where T: Flasher, S: SecurityCounter, C: Checksum
+{
+    check_version(data, update_info_address, flasher, counter) &&
+        check_update_integrity(data, update_info_address, board_id, flasher, engine) &&
+        check_mcuboot_signature(data, flasher, counter)
+}
+
+/// Lets the port verify the signature of a staged MCUboot image, other
+/// images pass.
+fn check_mcuboot_signature<T, S>(data: &update_info, flasher: &T, counter: &S) -> bool
+where T: Flasher, S: SecurityCounter
+{
+    match mcuboot::check_image(data.update_start, data.update_len, flasher)
+    {
+        Some(image) => mcuboot::check_signature(&image, flasher, |kind, signature| counter.verify_mcuboot_signature(&image.hash, kind, signature)),
+        None => !mcuboot::is_image(data.update_start, flasher)
+    }
+}
+
+/// Checks an image either as MCUboot image (against its SHA-256 TLV) or
+/// against the checksum/digest of the update_info.
+pub fn check_staged_image<T, C>(address: usize, data: &update_info, update_info_address: usize, flasher: &T, engine: &mut C) -> bool
+where T: Flasher, C: Checksum
+{
+    if mcuboot::is_image(address, flasher)
+    {
+        return mcuboot::check_image(address, data.update_len, flasher).is_some();
+    }
+
+    let image_digest = digest::ImageDigest::from_metadata(tlv::update_info_metadata(update_info_address, flasher), flasher);
+    digest::check_image(address, data.update_len, data.checksum, image_digest.as_ref(), flasher, engine)
+}
+
+/// Like `check_update`, but without the anti-rollback check. Used for
+/// updates whose version is not tracked by the security counter.
+pub fn check_update_integrity<T, C>(data: &update_info, update_info_address: usize, board_id: Option<u32>, flasher: &T, engine: &mut C) -> bool
+where T: Flasher, C: Checksum
 {
     let magic = b"MUUPD";
 
@@ -16,70 +213,118 @@ where T: Flasher
         return false;
     }
 
-    // if data.update_encoding != UpdateEncoding::Raw
-    // {
-    //     return false;
-    // }
+    match update_encoding(update_info_address, flasher)
+    {
+        Some(UpdateEncoding::Raw) => {},
+        // A delta is only installable on top of the image it was built
+        // against, or if the new image was built before a reset.
+        Some(UpdateEncoding::Delta) if delta::check_base(data, update_info_address, flasher, engine) ||
+                                       delta::is_built(data, flasher, engine) => {},
+        // The manifest is checked by suit::process.
+        Some(UpdateEncoding::Suit) => {},
+        _ => return false
+    }
 
-    return crc::check_crc(data.update_start, data.update_len, data.checksum, flasher);
+    if !check_board_id(update_info_address, flasher, board_id)
+    {
+        return false;
+    }
+
+    check_staged_image(data.update_start, data, update_info_address, flasher, engine)
 }
 
-pub fn install_binary<T>(data: &update_info, flasher: &mut T) -> bool
+fn read_chunk<T>(flasher: &T, address: usize, buff: &mut [u8], options: &WriteOptions) -> Result<usize, InstallError>
 where T: Flasher
 {
-    const BUF_SIZE: usize = 64;
-    let mut buff: [u8; BUF_SIZE] = [0; BUF_SIZE];
-    let mut bytes_left = data.update_len as usize;
-    let mut bytes_written: usize = 0;
-    while bytes_left > 0
+    for _ in 0..=options.io_retries
     {
-        if let Ok(result) = flasher.read(data.update_start + bytes_written, &mut buff)
+        // A read that yields no data would never finish the installation.
+        if let Ok(num_bytes @ 1..) = flasher.read(address, buff)
         {
-            let bytes_to_copy = if result > bytes_left { bytes_left } else { result };
-            let dst_slice = &buff[0..bytes_to_copy];
+            return Ok(num_bytes);
+        }
+    }
+    Err(InstallError::ReadFailed { address })
+}
 
-            if let Ok(()) = flasher.write(data.target_adress + bytes_written, dst_slice)
-            {
-                if bytes_left > result
-                {
-                    bytes_left = bytes_left - result;
-                }
-                else
-                {
-                    bytes_left = 0;
-                }
-                bytes_written = bytes_written + result;
-            }
-            else
+fn copy_chunk<T>(flasher: &mut T, address: usize, data: &[u8], options: &WriteOptions) -> Result<(), InstallError>
+where T: Flasher
+{
+    let mut attempts = 0;
+    loop
+    {
+        match super::write_chunk(flasher, address, data, options)
+        {
+            Ok(()) => return Ok(()),
+            Err(WriteError::VerifyFailed { address }) =>
             {
-                // Failed to write.
+                // write_chunk already retried, the flash is broken.
+                return Err(InstallError::VerifyFailed { address });
             }
+            Err(_) if attempts >= options.io_retries => return Err(InstallError::WriteFailed { address }),
+            Err(_) => attempts += 1
         }
-        else
-        {
-            // failed to read!
-        }
-        
+    }
+}
+
+/// Copies `len` bytes from `source` to `destination` and flushes the flasher.
+pub fn copy_area<T>(source: usize, len: usize, destination: usize, flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+where T: Flasher
+{
+    const BUF_SIZE: usize = 64;
+    let mut buff: [u8; BUF_SIZE] = [0; BUF_SIZE];
+    let mut bytes_left = len;
+    let mut bytes_written: usize = 0;
+    while bytes_left > 0
+    {
+        let result = read_chunk(flasher, source + bytes_written, &mut buff, options)?;
+        let bytes_to_copy = if result > bytes_left { bytes_left } else { result };
+
+        copy_chunk(flasher, destination + bytes_written, &buff[0..bytes_to_copy], options)?;
+
+        bytes_left -= bytes_to_copy;
+        bytes_written += bytes_to_copy;
     }
     
     flasher.flush();
+    Ok(())
+}
+
+pub fn install_binary<T, C>(data: &update_info, update_info_address: usize, flasher: &mut T, engine: &mut C, options: &WriteOptions) -> Result<(), InstallError>
+where T: Flasher, C: Checksum
+{
+    copy_area(data.update_start, data.update_len, data.target_adress, flasher, options)?;
 
     // ToDo: Write Bin_Info with data from update_info 
 
-    return crc::check_crc(data.target_adress, data.update_len, data.checksum, flasher);
+    if !check_staged_image(data.target_adress, data, update_info_address, flasher, engine)
+    {
+        return Err(InstallError::ChecksumMismatch);
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod test
 {
-    use crate::{update_info, testhelpers::FakeFlasher, testhelpers::copy_to_flasher};
-    use super::{check_update, install_binary};
+    use crate::{update_info, tlv, InstallError, SecurityCounter, WriteOptions, crc::Crc32Table, testhelpers::*};
+    use super::{check_authorization, check_update, commit_version, install_binary};
 
+    /// Writes the metadata of the update_info at address 0, always
+    /// including the board id.
+    fn write_update_metadata(fl: &mut FakeFlasher, entries: &[(u16, &[u8])])
+    {
+        let board_id = TEST_BOARD_ID.to_le_bytes();
+        let mut all: [(u16, &[u8]); 8] = [(tlv::TAG_BOARD_ID, &board_id); 8];
+        all[1..entries.len() + 1].copy_from_slice(entries);
+        write_metadata(fl, core::mem::size_of::<update_info>(), &all[..entries.len() + 1]);
+    }
 
     #[test]
     pub fn check_update_will_yield_false_if_magic_word_is_missing()
     {
-        let fl = FakeFlasher::new();
+        let mut fl = FakeFlasher::new();
+        write_update_metadata(&mut fl, &[]);
         let update_info = update_info {
             magic: [b'M', b'M', b'M', b'M', b'M'],
             struct_ver: 1,
@@ -89,13 +334,14 @@ mod test
             checksum: 0x9988C6CA
         };
 
-        assert!(false == check_update(&update_info, &fl));
+        assert!(!check_update(&update_info, 0, Some(TEST_BOARD_ID), &fl, &FakeSecurityCounter::new(0), &mut Crc32Table::new()));
     }
 
     #[test]
     pub fn check_update_will_yield_false_if_struct_ver_is_bad()
     {
-        let fl = FakeFlasher::new();
+        let mut fl = FakeFlasher::new();
+        write_update_metadata(&mut fl, &[]);
         let update_info = update_info {
             magic: [b'M', b'U', b'U', b'P', b'D'],
             struct_ver: 2,
@@ -105,13 +351,14 @@ mod test
             checksum: 0x9988C6CA
         };
 
-        assert!(false == check_update(&update_info, &fl));       
+        assert!(!check_update(&update_info, 0, Some(TEST_BOARD_ID), &fl, &FakeSecurityCounter::new(0), &mut Crc32Table::new()));       
     }
 
     #[test]
     pub fn check_update_will_yield_false_if_checksum_is_bad()
     {
-        let fl = FakeFlasher::new();
+        let mut fl = FakeFlasher::new();
+        write_update_metadata(&mut fl, &[]);
         let update_info = update_info {
             magic: [b'M', b'U', b'U', b'P', b'D'],
             struct_ver: 1,
@@ -121,13 +368,14 @@ mod test
             checksum: 0xC0FFEE
         };
 
-        assert!(false == check_update(&update_info, &fl));       
+        assert!(!check_update(&update_info, 0, Some(TEST_BOARD_ID), &fl, &FakeSecurityCounter::new(0), &mut Crc32Table::new()));       
     }
 
     #[test]
     pub fn check_update_will_yield_true_if_no_error()
     {
-        let fl = FakeFlasher::new();
+        let mut fl = FakeFlasher::new();
+        write_update_metadata(&mut fl, &[]);
         let update_info = update_info {
             magic: [b'M', b'U', b'U', b'P', b'D'],
             struct_ver: 1,
@@ -137,7 +385,7 @@ mod test
             checksum: 0x9988C6CA
         };
 
-        assert!(true == check_update(&update_info, &fl));       
+        assert!(check_update(&update_info, 0, Some(TEST_BOARD_ID), &fl, &FakeSecurityCounter::new(0), &mut Crc32Table::new()));       
     }
 
     #[test]
@@ -157,15 +405,205 @@ mod test
         };       
 
 
-        install_binary(&update_info, &mut fl);
+        let _ = install_binary(&update_info, 0, &mut fl, &mut Crc32Table::new(), &WriteOptions::default());
 
-        for i in 0..5
-        {
-            assert!(fl.memory[i + 0x4000] == binary[i]);
-        }
+        assert!(fl.memory[0x4000..0x4005] == binary);
 
         assert!(fl.flush_called)
     }
 
+    #[test]
+    pub fn install_binary_will_report_address_that_does_not_verify()
+    {
+        let mut fl = FakeFlasher::new();
+        copy_to_flasher(&mut fl, 0x1000, &test_image());
+        fl.stuck_address = Some(0x4042);
+
+        let update_info = update_info {
+            magic: [b'M', b'U', b'U', b'P', b'D'],
+            struct_ver: 1,
+            update_len: 128,
+            update_start: 0x1000,
+            target_adress: 0x4000,
+            checksum: 0x2279EFE7
+        };
+
+        let options = WriteOptions { verify: true, verify_retries: 2, io_retries: 0 };
+        assert_eq!(install_binary(&update_info, 0, &mut fl, &mut Crc32Table::new(), &options), Err(InstallError::VerifyFailed { address: 0x4042 }));
+        // The broken chunk was written three times, nothing after it.
+        assert_eq!(fl.write_count, 1 + 3);
+
+        fl.stuck_address = None;
+        assert_eq!(install_binary(&update_info, 0, &mut fl, &mut Crc32Table::new(), &options), Ok(()));
+    }
+
+    #[test]
+    pub fn install_binary_gives_up_on_failing_flash()
+    {
+        let mut fl = FakeFlasher::new();
+        copy_to_flasher(&mut fl, 0x1000, &test_image());
 
-}
\ No newline at end of file
+        let mut update_info = test_update();
+        update_info.update_len = 128;
+        update_info.checksum = 0x2279EFE7;
+        let options = WriteOptions { verify: false, verify_retries: 0, io_retries: 2 };
+
+        // Two failures are covered by the retries...
+        fl.failing_reads.set(2);
+        fl.failing_writes = 2;
+        assert_eq!(install_binary(&update_info, 0, &mut fl, &mut Crc32Table::new(), &options), Ok(()));
+
+        // ... three are not.
+        fl.failing_reads.set(3);
+        assert_eq!(install_binary(&update_info, 0, &mut fl, &mut Crc32Table::new(), &options), Err(InstallError::ReadFailed { address: 0x1000 }));
+        fl.failing_reads.set(0);
+        fl.failing_writes = 3;
+        assert_eq!(install_binary(&update_info, 0, &mut fl, &mut Crc32Table::new(), &options), Err(InstallError::WriteFailed { address: 0x4000 }));
+    }
+
+    #[test]
+    pub fn check_update_accepts_mcuboot_images()
+    {
+        let mut fl = FakeFlasher::new();
+        let len = write_mcuboot_image(&mut fl, 0x1000, (1, 2, 0));
+        let mut update_info = test_update();
+        update_info.update_len = len;
+        update_info.checksum = 0;
+        // The unauthenticated metadata claims a newer version
+        write_update_metadata(&mut fl, &[(tlv::TAG_IMAGE_VERSION, &tlv::image_version(9, 0, 0).to_le_bytes())]);
+        copy_to_flasher(&mut fl, 0, unsafe { core::slice::from_raw_parts(&update_info as *const update_info as *const u8, core::mem::size_of::<update_info>()) });
+        let counter = |min_version| FakeSecurityCounter { accepted_signature: Some(TEST_MCUBOOT_SIGNATURE), ..FakeSecurityCounter::new(min_version) };
+
+        // The version is taken from the MCUboot header.
+        assert!(check_update(&update_info, 0, Some(TEST_BOARD_ID), &fl, &counter(tlv::image_version(1, 2, 0)), &mut Crc32Table::new()));
+        assert!(!check_update(&update_info, 0, Some(TEST_BOARD_ID), &fl, &counter(tlv::image_version(1, 3, 0)), &mut Crc32Table::new()));
+
+        // Not signed by someone we trust
+        assert!(!check_update(&update_info, 0, Some(TEST_BOARD_ID), &fl, &FakeSecurityCounter::new(0), &mut Crc32Table::new()));
+
+        assert_eq!(install_binary(&update_info, 0, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Ok(()));
+        fl.memory[0x1050] ^= 0x01;
+        assert!(!check_update(&update_info, 0, Some(TEST_BOARD_ID), &fl, &counter(0), &mut Crc32Table::new()));
+    }
+
+    fn test_update() -> update_info
+    {
+        update_info {
+            magic: [b'M', b'U', b'U', b'P', b'D'],
+            struct_ver: 1,
+            update_len: 100,
+            update_start: 0x1000,
+            target_adress: 0x4000,
+            checksum: 0x9988C6CA
+        }
+    }
+
+    #[test]
+    pub fn check_update_will_yield_false_for_other_board()
+    {
+        let mut fl = FakeFlasher::new();
+        assert!(!check_update(&test_update(), 0, Some(TEST_BOARD_ID), &fl, &FakeSecurityCounter::new(0), &mut Crc32Table::new()));
+
+        write_metadata(&mut fl, core::mem::size_of::<update_info>(), &[(tlv::TAG_BOARD_ID, &[0xFF, 0, 0, 0])]);
+        assert!(!check_update(&test_update(), 0, Some(TEST_BOARD_ID), &fl, &FakeSecurityCounter::new(0), &mut Crc32Table::new()));
+    }
+
+    #[test]
+    pub fn check_update_will_skip_board_id_without_one_of_its_own()
+    {
+        let mut fl = FakeFlasher::new();
+        assert!(check_update(&test_update(), 0, None, &fl, &FakeSecurityCounter::new(0), &mut Crc32Table::new()));
+
+        write_metadata(&mut fl, core::mem::size_of::<update_info>(), &[(tlv::TAG_BOARD_ID, &[0xFF, 0, 0, 0])]);
+        assert!(check_update(&test_update(), 0, None, &fl, &FakeSecurityCounter::new(0), &mut Crc32Table::new()));
+    }
+
+    #[test]
+    pub fn check_update_will_yield_false_if_version_is_too_old()
+    {
+        let mut fl = FakeFlasher::new();
+        write_update_metadata(&mut fl, &[(tlv::TAG_IMAGE_VERSION, &tlv::image_version(1, 2, 0).to_le_bytes())]);
+
+        assert!(check_update(&test_update(), 0, Some(TEST_BOARD_ID), &fl, &FakeSecurityCounter::new(tlv::image_version(1, 2, 0)), &mut Crc32Table::new()));
+        assert!(!check_update(&test_update(), 0, Some(TEST_BOARD_ID), &fl, &FakeSecurityCounter::new(tlv::image_version(1, 3, 0)), &mut Crc32Table::new()));
+    }
+
+    #[test]
+    pub fn check_update_will_allow_signed_downgrade()
+    {
+        let mut fl = FakeFlasher::new();
+        let version = tlv::image_version(1, 2, 0).to_le_bytes();
+        let flags = tlv::FLAG_DOWNGRADE_PERMITTED.to_le_bytes();
+        let digest = sha256_digest_value(&fl.memory[0x1000..0x1064]);
+        let entries: [(u16, &[u8]); 4] = [(tlv::TAG_IMAGE_VERSION, &version), (tlv::TAG_FLAGS, &flags), (tlv::TAG_DIGEST, &digest), (tlv::TAG_SIGNATURE, &[0xAB, 0xCD])];
+        write_update_metadata(&mut fl, &entries);
+        let mut counter = FakeSecurityCounter::new(tlv::image_version(2, 0, 0));
+
+        assert!(!check_update(&test_update(), 0, Some(TEST_BOARD_ID), &fl, &counter, &mut Crc32Table::new()));
+        counter.accepted_signature = Some([0xAB, 0xCD]);
+        let board_id = TEST_BOARD_ID.to_le_bytes();
+        counter.signed_hash = Some(update_signed_hash(0x4000, 100, &[(tlv::TAG_BOARD_ID, &board_id), entries[0], entries[1], entries[2]]));
+        assert!(check_update(&test_update(), 0, Some(TEST_BOARD_ID), &fl, &counter, &mut Crc32Table::new()));
+
+        // The authorization is reused for an even older version ...
+        let older = tlv::image_version(1, 0, 0).to_le_bytes();
+        write_update_metadata(&mut fl, &[(tlv::TAG_IMAGE_VERSION, &older), entries[1], entries[2], entries[3]]);
+        assert!(!check_update(&test_update(), 0, Some(TEST_BOARD_ID), &fl, &counter, &mut Crc32Table::new()));
+
+        // ... or for another image with the same CRC-32.
+        write_update_metadata(&mut fl, &entries);
+        fl.memory[0x1000] = 0x01;
+        assert!(!check_update(&test_update(), 0, Some(TEST_BOARD_ID), &fl, &counter, &mut Crc32Table::new()));
+        fl.memory[0x1000] = 0x00;
+
+        // Signatures require a SHA-256 digest of the image
+        write_update_metadata(&mut fl, &[entries[0], entries[1], entries[3]]);
+        assert!(!check_update(&test_update(), 0, Some(TEST_BOARD_ID), &fl, &counter, &mut Crc32Table::new()));
+    }
+
+    #[test]
+    pub fn authorization_covers_image_and_metadata()
+    {
+        let mut fl = FakeFlasher::new();
+        copy_to_flasher(&mut fl, 0x1000, &test_image());
+        let update = update_info { update_len: 128, ..test_update() };
+        let board_id = TEST_BOARD_ID.to_le_bytes();
+        let flags = tlv::FLAG_LOADER_UPDATE.to_le_bytes();
+        let digest = sha256_digest_value(&test_image());
+        let entries: [(u16, &[u8]); 4] = [(tlv::TAG_BOARD_ID, &board_id), (tlv::TAG_FLAGS, &flags), (tlv::TAG_DIGEST, &digest), (tlv::TAG_SIGNATURE, &[0xAB, 0xCD])];
+        write_update_metadata(&mut fl, &entries[1..]);
+
+        let mut counter = FakeSecurityCounter::new(0);
+        counter.accepted_signature = Some([0xAB, 0xCD]);
+        assert!(!check_authorization(&update, 0, &fl, &counter));
+        counter.signed_hash = Some(update_signed_hash(0x4000, 128, &entries));
+        assert!(check_authorization(&update, 0, &fl, &counter));
+
+        // The signature is replayed with another image ...
+        fl.memory[0x1010] ^= 0x01;
+        assert!(!check_authorization(&update, 0, &fl, &counter));
+        fl.memory[0x1010] ^= 0x01;
+
+        // ... with other metadata, or with a digest that is only a CRC.
+        write_update_metadata(&mut fl, &[entries[2], entries[3]]);
+        assert!(!check_authorization(&update, 0, &fl, &counter));
+        let crc32c = [crate::digest::DIGEST_CRC32C, 0, 0, 0, 0];
+        write_update_metadata(&mut fl, &[entries[1], (tlv::TAG_DIGEST, &crc32c), entries[3]]);
+        assert!(!check_authorization(&update, 0, &fl, &counter));
+    }
+
+    #[test]
+    pub fn commit_version_only_raises_the_counter()
+    {
+        let mut fl = FakeFlasher::new();
+        write_update_metadata(&mut fl, &[(tlv::TAG_IMAGE_VERSION, &tlv::image_version(1, 2, 0).to_le_bytes())]);
+
+        let mut counter = FakeSecurityCounter::new(0);
+        commit_version(0, &fl, &mut counter);
+        assert_eq!(counter.min_version(), tlv::image_version(1, 2, 0));
+
+        let mut counter = FakeSecurityCounter::new(tlv::image_version(3, 0, 0));
+        commit_version(0, &fl, &mut counter);
+        assert_eq!(counter.min_version(), tlv::image_version(3, 0, 0));
+    }
+}
@@ -1,8 +1,9 @@
-use super::{bin_info, Flasher, crc};
+use super::{bin_info, Flasher, digest, mcuboot, tlv};
+use super::crc::Checksum;
 
 
-pub fn check_binary<T>(data: &bin_info, flasher: &T) -> bool
-    where T: Flasher
+pub fn check_binary<T, C>(data: &bin_info, bin_info_address: usize, flasher: &T, engine: &mut C) -> bool
+    where T: Flasher, C: Checksum
 {
     let magic = b"MUBIN";
 
@@ -16,15 +17,30 @@ pub fn check_binary<T>(data: &bin_info, flasher: &T) -> bool
         return false;
     }
 
-    return crc::check_crc(data.app_start, data.app_len, data.checksum, flasher);
+    // An MCUboot image carries its own hash.
+    if mcuboot::is_image(data.app_start, flasher)
+    {
+        return mcuboot::check_image(data.app_start, data.app_len, flasher).is_some();
+    }
+
+    let image_digest = digest::ImageDigest::from_metadata(tlv::bin_info_metadata(bin_info_address, flasher), flasher);
+    digest::check_image(data.app_start, data.app_len, data.checksum, image_digest.as_ref(), flasher, engine)
 
 }
 
-pub fn launch_binary(data: bin_info) ->!
+/// Yields the address the application starts at, which is behind the
+/// header for MCUboot images.
+pub fn entry_point<T>(data: &bin_info, flasher: &T) -> usize
+    where T: Flasher
+{
+    data.app_start + mcuboot::ImageHeader::read(data.app_start, flasher).map(|header| header.hdr_size).unwrap_or(0)
+}
+
+pub fn launch_binary(entry_point: usize) ->!
 {
     unsafe 
     {
-        let app_entry = core::mem::transmute::<usize, fn() -> !>(data.app_start);
+        let app_entry = core::mem::transmute::<usize, fn() -> !>(entry_point);
         app_entry();
     }
 }
\ No newline at end of file
@@ -1,6 +1,10 @@
-use super::Flasher;
-use super::crc;
-use embedded_hal::serial::{Read, Write};
+use super::{Flasher, InstallError, WriteOptions};
+use super::digest;
+use super::crc::Checksum;
+use super::tlv;
+use super::partition_table;
+use super::xmodem::{self, Block};
+use super::transport::Transport;
 
 const STX: u8 = 0x02;
 const ETX: u8 = 0x03;
@@ -8,94 +12,483 @@ const ETX: u8 = 0x03;
 const INIT: u8 = 0x16;
 const DATA: u8 = 0x01;
 const END: u8 = 0x04;
+/// Requests a 128 byte chunk of the partition table, the index of the chunk
+/// is stored in the first two bytes of the payload (big endian).
+const TABLE_READ: u8 = 0x11;
+/// Requests the bitmap of missing DATA blocks (addressed mode only), the
+/// index of the first block is stored in the first two bytes of the payload
+/// (big endian).
+const MISSING_BLOCKS: u8 = 0x12;
+/// Sent by the host to switch from NAK to error packets.
+const ERROR_REPORTS: u8 = 0x14;
+/// Error packet, answers a rejected packet once error reports are enabled.
+/// The payload holds the error code and the failing address.
+const ERROR_REPORT: u8 = 0x17;
 const NAK: u8 = 0x15;
 const ACK: u8 = 0x06;
+/// Answer to END if the received image does not match its checksum. Must
+/// not collide with the XMODEM control bytes (e.g. CAN, 0x18).
+const BAD_CHECKSUM: u8 = 0x19;
+
+const PAYLOAD_SIZE: usize = 128;
+/// Size of the fixed part of an INIT payload. It may be followed by
+/// a metadata area, which takes up the rest of the payload.
+const INIT_SIZE: usize = 22;
+const MAX_METADATA_SIZE: usize = PAYLOAD_SIZE - INIT_SIZE;
+
+/// Packets to this address are processed by all nodes and never answered.
+pub const BROADCAST_ADDRESS: u8 = 0xFF;
+/// Largest image (in DATA blocks) the addressed mode keeps track of, 512 KiB.
+const MAX_BLOCKS: usize = 4096;
+
+/// Why a packet was rejected. Legacy hosts only get NAK (or BAD_CHECKSUM),
+/// hosts that enabled error reports get the code and the failing address.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum PacketError
+{
+    BadBcc,
+    /// ETX missing or a malformed metadata area.
+    BadFraming,
+    UnknownType,
+    /// DATA or END without an accepted INIT.
+    NoInit,
+    BoardMismatch,
+    WriteFailed { address: usize },
+    VerifyFailed { address: usize },
+    /// The image does not fit (e.g. a block beyond update_len).
+    OutOfRange { address: usize },
+    BadChecksum,
+    /// No partition table, or no image to report missing blocks of.
+    NotAvailable
+}
+
+impl PacketError
+{
+    fn from_write_error(error: super::WriteError, address: usize) -> Self
+    {
+        match error
+        {
+            super::WriteError::NoData => PacketError::WriteFailed { address },
+            super::WriteError::AddressOutOfRange => PacketError::OutOfRange { address },
+            super::WriteError::VerifyFailed { address } => PacketError::VerifyFailed { address }
+        }
+    }
+
+    fn code(&self) -> u8
+    {
+        match self
+        {
+            PacketError::BadBcc => 0x01,
+            PacketError::BadFraming => 0x02,
+            PacketError::UnknownType => 0x03,
+            PacketError::NoInit => 0x04,
+            PacketError::BoardMismatch => 0x05,
+            PacketError::WriteFailed { .. } => 0x06,
+            PacketError::VerifyFailed { .. } => 0x07,
+            PacketError::OutOfRange { .. } => 0x08,
+            PacketError::BadChecksum => 0x09,
+            PacketError::NotAvailable => 0x0A
+        }
+    }
+
+    fn address(&self) -> usize
+    {
+        match self
+        {
+            PacketError::WriteFailed { address } | PacketError::VerifyFailed { address } | PacketError::OutOfRange { address } => *address,
+            _ => 0
+        }
+    }
+}
 
 struct Packet
 {
     packettype: u8,
-    data: Option<[u8;128]>
+    data: Option<[u8;PAYLOAD_SIZE]>,
+    /// Block number of a DATA packet in addressed mode.
+    block: Option<usize>
 }
 
 fn usize_from_packet(packet_data: &[u8], index: usize) -> usize
 {
-    let result = ((packet_data[index] as u32) << 24 |
-                 (packet_data[index + 1]as u32) << 16 |
-                 (packet_data[index + 2]as u32) << 8 |
-                 (packet_data[index + 3]as u32)) as usize;
-    result
+    ((packet_data[index] as u32) << 24 |
+     (packet_data[index + 1] as u32) << 16 |
+     (packet_data[index + 2] as u32) << 8 |
+     (packet_data[index + 3] as u32)) as usize
 }
 
-pub struct ImageReceiver<'a, T: Flasher, U: Read<u8> + Write<u8> >
+pub struct ImageReceiver<'a, T: Flasher, U: Transport, C: Checksum>
 {
     flasher: &'a mut T,
     uart: &'a mut U,
+    checksum: &'a mut C,
     done: bool,
     current_address: usize,
-    image_info: Option<super::update_info>
+    image_info: Option<super::update_info>,
+    metadata: [u8; MAX_METADATA_SIZE],
+    metadata_len: usize,
+    board_id: Option<u32>,
+    update_info_address: usize,
+    received_len: usize,
+    digest_engine: Option<digest::DigestEngine>,
+    image_digest: Option<digest::ImageDigest>,
+    write_options: WriteOptions,
+    partition_table: Option<[usize; 2]>,
+    /// Node address in addressed mode.
+    address: Option<u8>,
+    /// Set while handling a broadcast packet.
+    broadcast: bool,
+    /// DATA blocks received in addressed mode.
+    received_blocks: [u32; MAX_BLOCKS / 32],
+    /// Set once the host asked for error packets instead of NAK.
+    error_reports: bool
 }
 
-impl <'a, T: Flasher, U: Read<u8> + Write<u8>> ImageReceiver<'a, T,U>
+impl <'a, T: Flasher, U: Transport, C: Checksum> ImageReceiver<'a, T, U, C>
 {
-    pub fn new(flasher: &'a mut T, uart: &'a mut U) -> Self
-        where T: Flasher, U: Read<u8> + Write<u8> 
+    pub fn new(flasher: &'a mut T, uart: &'a mut U, checksum: &'a mut C) -> Self
+        where T: Flasher, U: Transport
     {
         Self
         {
             flasher, 
             uart, 
+            checksum,
             done:false, 
             current_address: 0,
-            image_info: None
+            image_info: None,
+            metadata: [0; MAX_METADATA_SIZE],
+            metadata_len: 0,
+            board_id: None,
+            update_info_address: 0,
+            received_len: 0,
+            digest_engine: None,
+            image_digest: None,
+            write_options: WriteOptions::default(),
+            partition_table: None,
+            address: None,
+            broadcast: false,
+            received_blocks: [0; MAX_BLOCKS / 32],
+            error_reports: false
+        }
+    }
+
+    /// Selects whether DATA packets are read back after writing them. A packet
+    /// that does not verify is answered with NAK.
+    pub fn with_write_options(mut self, write_options: WriteOptions) -> Self
+    {
+        self.write_options = write_options;
+        self
+    }
+
+    /// Makes the receiver reject all updates that were not built for
+    /// the given board, before anything is written to flash.
+    pub fn with_board_id(mut self, board_id: u32) -> Self
+    {
+        self.board_id = Some(board_id);
+        self
+    }
+
+    /// Allows the host to read the partition table stored in the given slots.
+    pub fn with_partition_table(mut self, slots: [usize; 2]) -> Self
+    {
+        self.partition_table = Some(slots);
+        self
+    }
+
+    /// Switches to addressed framing for multi-drop buses (RS-485): the
+    /// receiver only handles packets to `address` or to the broadcast
+    /// address, answers only the former, and DATA packets carry their
+    /// block number. The broadcast address is no valid node address, as
+    /// broadcasts must stay unanswered.
+    pub fn with_address(mut self, address: u8) -> Result<Self, InstallError>
+    {
+        if address == BROADCAST_ADDRESS
+        {
+            return Err(InstallError::InvalidConfig);
         }
+        self.address = Some(address);
+        Ok(self)
     }
 
     pub fn execute(mut self, update_info_address: usize)
     {
+        self.update_info_address = update_info_address;
         while !self.done
         {
-            if let Some(packet) = self.receive_packet()
+            // Each packet gets exactly one answer, none if it was broadcast.
+            let result = match self.receive_packet()
+            {
+                Some(Ok(packet)) => self.dispatch_packet(packet),
+                Some(Err(error)) => Err(error),
+                None => continue
+            };
+            match result
+            {
+                _ if self.broadcast => {},
+                Ok(true) => self.respond(ACK),
+                Ok(false) => {},
+                Err(error) => self.report_error(error)
+            }
+        }
+    }
+
+    /// Receives an update via XMODEM-CRC/1K or YMODEM instead of the muload
+    /// protocol. The file starts with a 128 byte header, which is the payload
+    /// of an INIT packet (zero padded), followed by the image. Everything
+    /// behind the image (e.g. the padding of the last block) is ignored.
+    pub fn execute_xmodem(mut self, update_info_address: usize)
+    {
+        self.update_info_address = update_info_address;
+        let mut buf = [0u8; xmodem::BLOCK_SIZE_1K];
+        let mut expected: u8 = 1;
+        let mut ymodem = false;
+        let mut file_size = None;
+        let mut file_offset = 0;
+        let mut file_done = false;
+
+        self.respond(xmodem::CRC_REQUEST);
+        loop
+        {
+            let response = match xmodem::receive_block(self.uart, &mut buf)
             {
-                if !self.dispatch_packet(packet)
+                Block::Data { number: 0, len } if file_offset == 0 =>
+                {
+                    // YMODEM header, an empty one ends the batch.
+                    let (name, size) = xmodem::parse_header(&buf[..len]);
+                    if name.is_empty()
+                    {
+                        self.respond(xmodem::ACK);
+                        return;
+                    }
+                    if file_done
+                    {
+                        // Only one file per batch
+                        self.cancel_xmodem();
+                        return;
+                    }
+                    ymodem = true;
+                    file_size = size;
+                    self.respond(xmodem::ACK);
+                    xmodem::CRC_REQUEST
+                }
+                // The sender missed our ACK
+                Block::Data { number, .. } if number == expected.wrapping_sub(1) => xmodem::ACK,
+                Block::Data { number, len } if number == expected && !file_done =>
                 {
-                    let _ = self.uart.write(NAK);
+                    if !self.receive_xmodem_data(&buf[..len], file_offset, file_size)
+                    {
+                        self.cancel_xmodem();
+                        return;
+                    }
+                    file_offset += len;
+                    expected = expected.wrapping_add(1);
+                    xmodem::ACK
                 }
-                else
+                Block::Data { .. } =>
                 {
-                    let _ = self.uart.write(ACK);
+                    self.cancel_xmodem();
+                    return;
                 }
+                Block::End if file_offset > 0 && !file_done =>
+                {
+                    if self.end_update().is_err()
+                    {
+                        self.cancel_xmodem();
+                        return;
+                    }
+                    if !ymodem
+                    {
+                        self.respond(xmodem::ACK);
+                        return;
+                    }
+                    // Wait for the end of the batch
+                    file_done = true;
+                    file_offset = 0;
+                    self.respond(xmodem::ACK);
+                    xmodem::CRC_REQUEST
+                }
+                Block::Cancel => return,
+                _ => xmodem::NAK
+            };
+            self.respond(response);
+        }
+    }
+
+    /// Passes the data of an XMODEM block to INIT (the header) and DATA.
+    fn receive_xmodem_data(&mut self, data: &[u8], file_offset: usize, file_size: Option<usize>) -> bool
+    {
+        for (index, chunk) in data.chunks(PAYLOAD_SIZE).enumerate()
+        {
+            let mut payload = [0u8; PAYLOAD_SIZE];
+            payload[..chunk.len()].copy_from_slice(chunk);
+
+            if file_offset == 0 && index == 0
+            {
+                if self.init_update(Packet { packettype: INIT, data: Some(payload), block: None }).is_err()
+                {
+                    return false;
+                }
+                // YMODEM tells us the real size, which must hold the image.
+                let update_len = self.image_info.as_ref().map(|info| info.update_len).unwrap_or(0);
+                if file_size.map(|size| size < PAYLOAD_SIZE + update_len).unwrap_or(false)
+                {
+                    return false;
+                }
+                continue;
             }
+
+            let update_len = self.image_info.as_ref().map(|info| info.update_len).unwrap_or(0);
+            if self.received_len < update_len && self.flash_data(Packet { packettype: DATA, data: Some(payload), block: None }).is_err()
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn cancel_xmodem(&mut self)
+    {
+        self.uart.write_all(&[xmodem::CAN, xmodem::CAN]);
+        self.uart.flush();
+    }
+
+    /// Yields whether the packet has to be answered with ACK, false if
+    /// the answer was sent already.
+    fn dispatch_packet(&mut self, packet: Packet) -> Result<bool, PacketError>
+    {
+        match packet.packettype
+        {
+            INIT => self.init_update(packet).map(|_| true),
+            DATA => self.flash_data(packet).map(|_| true),
+            END => self.end_update().map(|_| true),
+            // Answers can't be broadcast.
+            TABLE_READ | MISSING_BLOCKS | ERROR_REPORTS if self.broadcast => Ok(false),
+            TABLE_READ => self.send_table_chunk(packet).map(|_| false),
+            MISSING_BLOCKS if self.address.is_some() => self.send_missing_blocks(packet).map(|_| false),
+            ERROR_REPORTS =>
+            {
+                self.error_reports = true;
+                Ok(true)
+            },
+            _ => Err(PacketError::UnknownType)
+        }
+    }
+
+    /// Answers a rejected packet with an error packet if the host asked
+    /// for them. Otherwise with NAK, as legacy hosts expect it.
+    fn report_error(&mut self, error: PacketError)
+    {
+        if self.error_reports
+        {
+            let mut payload = [0u8; PAYLOAD_SIZE];
+            payload[0] = error.code();
+            payload[1..5].copy_from_slice(&(error.address() as u32).to_be_bytes());
+            self.send_packet(ERROR_REPORT, &payload);
+            return;
+        }
+
+        match error
+        {
+            PacketError::BadChecksum => self.respond(BAD_CHECKSUM),
+            // The host runs into its timeout.
+            PacketError::BadFraming => {},
+            _ => self.respond(NAK)
         }
+    }
 
-        // Note: At this point we could check if we received enough bytes (as indicated by
-        // the infostruct), however: If we did not receive enough bytes the CRC check should
-        // fail, thus not writing the update struct to flash.
+    /// Answers TABLE_READ with a packet of the same type, holding the
+    /// requested chunk of the table (zero padded).
+    fn send_table_chunk(&mut self, packet: Packet) -> Result<(), PacketError>
+    {
+        let payload = packet.data.unwrap();
+        let index = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+        let table = match self.partition_table.and_then(|slots| partition_table::read_table(&slots, self.flasher))
+        {
+            Some(table) => table,
+            None => return Err(PacketError::NotAvailable)
+        };
 
-        // check the received image's CRC against the update_info_struct
-        if let Some(update_struct) = self.image_info
+        let offset = index * PAYLOAD_SIZE;
+        if offset >= table.size()
         {
-            if crc::check_crc(update_struct.update_start, update_struct.update_len, update_struct.checksum, self.flasher)
+            return Err(PacketError::NotAvailable);
+        }
+
+        let mut chunk = [0u8; PAYLOAD_SIZE];
+        let num_bytes = core::cmp::min(PAYLOAD_SIZE, table.size() - offset);
+        match self.flasher.read(table.address + offset, &mut chunk[..num_bytes])
+        {
+            Ok(len) if len == num_bytes => {},
+            _ => return Err(PacketError::NotAvailable)
+        }
+
+        self.send_packet(TABLE_READ, &chunk);
+        Ok(())
+    }
+
+    /// Answers MISSING_BLOCKS with a packet of the same type, holding a
+    /// bitmap of the blocks starting at the requested one (LSB of the first
+    /// byte first). A set bit marks a block of the image that is missing.
+    fn send_missing_blocks(&mut self, packet: Packet) -> Result<(), PacketError>
+    {
+        let payload = packet.data.unwrap();
+        let first = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+        let num_blocks = match self.image_info.as_ref()
+        {
+            Some(info) => info.update_len.div_ceil(PAYLOAD_SIZE),
+            None => return Err(PacketError::NotAvailable)
+        };
+
+        let mut bitmap = [0u8; PAYLOAD_SIZE];
+        for block in (first..num_blocks).take(PAYLOAD_SIZE * 8)
+        {
+            if !self.is_block_received(block)
             {
-                // Write the update struct as well
-                let num_bytes = core::mem::size_of::<super::update_info>();
-                let data_slice = unsafe {core::slice::from_raw_parts((&update_struct as *const super::update_info) as *const u8, num_bytes)};
-                let _= self.flasher.write(update_info_address, data_slice);
+                bitmap[(block - first) / 8] |= 1 << ((block - first) % 8);
             }
         }
+        self.send_packet(MISSING_BLOCKS, &bitmap);
+        Ok(())
     }
 
-    fn dispatch_packet(&mut self, packet: Packet) -> bool
+    fn is_block_received(&self, block: usize) -> bool
     {
-        match packet.packettype
+        self.received_blocks[block / 32] & (1 << (block % 32)) != 0
+    }
+
+    /// Whether DATA blocks of the current image are missing (addressed
+    /// mode only).
+    fn has_missing_blocks(&self) -> bool
+    {
+        match (self.address, self.image_info.as_ref())
         {
-            INIT => return self.init_update(packet),
-            DATA => return self.flash_data(packet).is_ok(),
-            END => return self.end_update(),
-            _ => return false
+            (Some(_), Some(info)) => !(0..info.update_len.div_ceil(PAYLOAD_SIZE)).all(|block| self.is_block_received(block)),
+            _ => false
         }
     }
 
-    fn init_update(&mut self, packet: Packet) -> bool
+    fn send_packet(&mut self, packet_type: u8, payload: &[u8])
+    {
+        let mut bcc = STX ^ packet_type ^ ETX;
+        self.uart.write_byte(STX);
+        if let Some(address) = self.address
+        {
+            self.uart.write_byte(address);
+            bcc ^= address;
+        }
+        self.uart.write_byte(packet_type);
+        for byte in payload.iter()
+        {
+            self.uart.write_byte(*byte);
+            bcc ^= *byte;
+        }
+        self.uart.write_byte(ETX);
+        self.uart.write_byte(bcc);
+        self.uart.flush();
+    }
+
+    fn init_update(&mut self, packet: Packet) -> Result<(), PacketError>
     {
         let payload = packet.data.unwrap();
         let version = payload[5];        
@@ -104,36 +497,170 @@ impl <'a, T: Flasher, U: Read<u8> + Write<u8>> ImageReceiver<'a, T,U>
         let target_adr = usize_from_packet(&payload, 14);
         let checksum = usize_from_packet(&payload, 18);
 
+        if let Some(board_id) = self.board_id
+        {
+            let image_board_id = tlv::parse_area(&payload[INIT_SIZE..])
+                .and_then(|area| area.find(tlv::TAG_BOARD_ID))
+                .and_then(tlv::u32_value);
+
+            if image_board_id != Some(board_id)
+            {
+                // Make sure that no DATA packet of this image hits the flash.
+                self.image_info = None;
+                return Err(PacketError::BoardMismatch);
+            }
+        }
+
+        if self.address.is_some() && upd_len > MAX_BLOCKS * PAYLOAD_SIZE
+        {
+            self.image_info = None;
+            return Err(PacketError::OutOfRange { address: start_area + upd_len });
+        }
+        self.received_blocks = [0; MAX_BLOCKS / 32];
+
         // ToDo: Check if we actually received the correct magic value.
         self.image_info = Some(super::update_info {
-            magic: ['M' as u8, 'U' as u8, 'U' as u8, 'P' as u8, 'D' as u8],
+            magic: *b"MUUPD",
             struct_ver: version,
             update_start: start_area,
             update_len: upd_len,
             target_adress: target_adr,
-            checksum: checksum,
+            checksum,
         });
 
+        self.metadata_len = tlv::area_size(&payload[INIT_SIZE..]).unwrap_or(0);
+        self.metadata[..self.metadata_len].copy_from_slice(&payload[INIT_SIZE..INIT_SIZE + self.metadata_len]);
+
         self.current_address = start_area;
 
-        true
+        // The checksum is calculated while receiving, such that we don't
+        // have to read back the whole image at the end.
+        self.received_len = 0;
+        self.checksum.reset();
+        self.image_digest = tlv::parse_area(&self.metadata[..self.metadata_len])
+            .and_then(|area| area.find(tlv::TAG_DIGEST))
+            .map(|value| digest::ImageDigest::from_value(value).unwrap_or(digest::ImageDigest::invalid()));
+        self.digest_engine = self.image_digest.and_then(|image_digest| digest::DigestEngine::new(image_digest.algorithm));
+
+        Ok(())
+    }
+
+    fn flash_data(&mut self, packet: Packet) -> Result<(), PacketError>
+    {
+        if self.image_info.is_none()
+        {
+            // No (accepted) INIT so far, we don't know where this belongs.
+            return Err(PacketError::NoInit);
+        }
+
+        let data = packet.data.unwrap();
+        if let Some(block) = packet.block
+        {
+            return self.flash_block(block, &data);
+        }
+
+        let address = self.current_address;
+        super::write_chunk(self.flasher, address, &data, &self.write_options)
+            .map_err(|error| PacketError::from_write_error(error, address))?;
+        self.current_address += PAYLOAD_SIZE;
+        self.update_checksum(&data);
+        Ok(())
     }
 
-    fn flash_data(&mut self, packet: Packet) -> Result<(), super::WriteError>
+    /// Writes a numbered DATA block (addressed mode). Blocks may arrive in
+    /// any order and more than once, the image is checked at the end.
+    fn flash_block(&mut self, block: usize, data: &[u8]) -> Result<(), PacketError>
     {
-        let result = self.flasher.write(self.current_address, &packet.data.unwrap());
-        if result.is_ok()
+        let (update_start, update_len) = self.image_info.as_ref().map(|info| (info.update_start, info.update_len)).unwrap_or((0, 0));
+        let address = update_start + block * PAYLOAD_SIZE;
+        if block * PAYLOAD_SIZE >= update_len
         {
-            self.current_address += 128;
+            return Err(PacketError::OutOfRange { address });
         }
-        result
+
+        super::write_chunk(self.flasher, address, data, &self.write_options)
+            .map_err(|error| PacketError::from_write_error(error, address))?;
+        self.received_blocks[block / 32] |= 1 << (block % 32);
+        Ok(())
     }
 
-    fn end_update(&mut self) -> bool
+    /// Feeds the part of a DATA payload that belongs to the image (i.e.
+    /// without the zero padding of the last packet) to the checksum.
+    fn update_checksum(&mut self, data: &[u8])
+    {
+        let update_len = self.image_info.as_ref().map(|info| info.update_len).unwrap_or(0);
+        let num_bytes = core::cmp::min(data.len(), update_len.saturating_sub(self.received_len));
+
+        match self.digest_engine.as_mut()
+        {
+            Some(engine) => engine.update(&data[..num_bytes]),
+            None => self.checksum.update(&data[..num_bytes])
+        }
+        self.received_len += num_bytes;
+    }
+
+    fn image_matches(&mut self, update_struct: &super::update_info) -> bool
+    {
+        if self.address.is_some()
+        {
+            // Blocks arrived in any order, check what ended up in flash.
+            return digest::check_image(update_struct.update_start, update_struct.update_len, update_struct.checksum, self.image_digest.as_ref(), self.flasher, self.checksum);
+        }
+
+        if self.received_len != update_struct.update_len
+        {
+            return false;
+        }
+
+        match (self.image_digest.as_ref(), self.digest_engine.as_mut())
+        {
+            (Some(image_digest), Some(engine)) => engine.matches(image_digest),
+            // The image has a digest, but we don't support the algorithm
+            (Some(_), None) => false,
+            (None, _) => self.checksum.finish() as usize == update_struct.checksum
+        }
+    }
+
+    fn end_update(&mut self) -> Result<(), PacketError>
     {
         self.flasher.flush();
+        if self.has_missing_blocks()
+        {
+            // The session stays open, the host can still query and resend
+            // the missing blocks.
+            return Err(PacketError::BadChecksum);
+        }
         self.done = true;
-        return true;
+
+        if let Some(update_struct) = self.image_info.take()
+        {
+            if !self.image_matches(&update_struct)
+            {
+                // Don't write the update_info, the image will never be installed.
+                return Err(PacketError::BadChecksum);
+            }
+
+            // Write the update struct as well
+            let num_bytes = core::mem::size_of::<super::update_info>();
+            let data_slice = unsafe {core::slice::from_raw_parts((&update_struct as *const super::update_info) as *const u8, num_bytes)};
+            let address = self.update_info_address;
+            self.flasher.write(address, data_slice)
+                .map_err(|error| PacketError::from_write_error(error, address))?;
+
+            // ... followed by its metadata area, if the host sent one.
+            if self.metadata_len > 0
+            {
+                self.flasher.write(address + num_bytes, &self.metadata[..self.metadata_len])
+                    .map_err(|error| PacketError::from_write_error(error, address + num_bytes))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn respond(&mut self, response: u8)
+    {
+        self.uart.write_byte(response);
+        self.uart.flush();
     }
 
     /// This function is guaranteed to return 
@@ -144,15 +671,15 @@ impl <'a, T: Flasher, U: Read<u8> + Write<u8>> ImageReceiver<'a, T,U>
     {
         loop 
         {
-            let read_result = self.uart.read();
-            if let Ok(byte ) = read_result
+            if let Some(byte) = self.uart.read_byte()
             {
                 return byte;
             }
         }
     }
 
-    fn receive_packet(&mut self) -> Option<Packet>
+    /// Yields None for packets to other nodes.
+    fn receive_packet(&mut self) -> Option<Result<Packet, PacketError>>
     {
         loop 
         {
@@ -162,50 +689,112 @@ impl <'a, T: Flasher, U: Read<u8> + Write<u8>> ImageReceiver<'a, T,U>
             }
         }
 
+        let mut bcc = STX;
+        let mut for_us = true;
+        self.broadcast = false;
+        if let Some(own_address) = self.address
+        {
+            let address = self.get_byte();
+            bcc ^= address;
+            self.broadcast = address == BROADCAST_ADDRESS;
+            for_us = address == own_address || self.broadcast;
+        }
+
         let packet_type = self.get_byte();
+        bcc ^= packet_type;
+        let mut received_data: Option<[u8;PAYLOAD_SIZE]> = None;
 
-        let mut bcc = 0x00 ^ STX ^ packet_type;
-        let mut received_data: Option<[u8;128]> = None;
+        let mut block = None;
+        if self.address.is_some() && packet_type == DATA
+        {
+            let number = [self.get_byte(), self.get_byte()];
+            bcc ^= number[0] ^ number[1];
+            block = Some(u16::from_be_bytes(number) as usize);
+        }
 
-        if packet_type == DATA || packet_type == INIT
+        if packet_type == DATA || packet_type == INIT || packet_type == TABLE_READ || packet_type == MISSING_BLOCKS
         {
-            let mut bytes_to_receive = 128;
+            let mut bytes_to_receive = PAYLOAD_SIZE;
             if packet_type == INIT
             {
-                bytes_to_receive = 22;
+                bytes_to_receive = INIT_SIZE;
             }
 
-            let mut payload: [u8; 128] = [0;128];
-            for i in 0..bytes_to_receive
+            let mut payload: [u8; PAYLOAD_SIZE] = [0;PAYLOAD_SIZE];
+            for byte in payload.iter_mut().take(bytes_to_receive)
             {
-                payload[i] = self.get_byte();
-                bcc ^= payload[i];
+                *byte = self.get_byte();
+                bcc ^= *byte;
             }
             received_data = Some(payload);
         }
 
-        let etx = self.get_byte();
+        let mut etx = self.get_byte();
+
+        // An INIT packet is either terminated right after the fixed
+        // fields or continues with a metadata area, which always starts
+        // with the area magic. Legacy hosts never send the latter.
+        if let (INIT, Some(payload)) = (packet_type, received_data.as_mut())
+        {
+            if etx == tlv::TLV_MAGIC[0]
+            {
+                if !self.receive_metadata(etx, &mut payload[INIT_SIZE..], &mut bcc)
+                {
+                    return if for_us { Some(Err(PacketError::BadFraming)) } else { None };
+                }
+                etx = self.get_byte();
+            }
+        }
 
         // we should have received an etx now, if not, something has gone
         // wrong.
 
         if etx != ETX
         {
-            return None;
+            return if for_us { Some(Err(PacketError::BadFraming)) } else { None };
         }
 
         bcc ^= etx;
 
         let received_bcc = self.get_byte();
 
-        if bcc != received_bcc
+        if !for_us
         {
-            // answer NAK, return None
-            let _ = self.uart.write(NAK);
             return None;
         }
+
+        if bcc != received_bcc
+        {
+            return Some(Err(PacketError::BadBcc));
+        }
         
-        return Some(Packet{packettype: packet_type, data: received_data});
+        Some(Ok(Packet{packettype: packet_type, data: received_data, block}))
+    }
+
+    /// Receives the metadata area of an INIT packet into `destination`. The
+    /// first byte of the area has already been received.
+    fn receive_metadata(&mut self, first_byte: u8, destination: &mut [u8], bcc: &mut u8) -> bool
+    {
+        destination[0] = first_byte;
+        *bcc ^= first_byte;
+        for byte in destination.iter_mut().take(tlv::TLV_AREA_HEADER_SIZE).skip(1)
+        {
+            *byte = self.get_byte();
+            *bcc ^= *byte;
+        }
+
+        let area_len = u16::from_le_bytes([destination[2], destination[3]]) as usize;
+        if tlv::TLV_AREA_HEADER_SIZE + area_len > destination.len()
+        {
+            return false;
+        }
+
+        for byte in destination.iter_mut().take(tlv::TLV_AREA_HEADER_SIZE + area_len).skip(tlv::TLV_AREA_HEADER_SIZE)
+        {
+            *byte = self.get_byte();
+            *bcc ^= *byte;
+        }
+        true
     }
 }
 
@@ -214,6 +803,7 @@ mod test
 {
     use crate::testhelpers::*;
     use crate::Flasher;
+    use crate::crc::{Checksum, Crc32Table};
 
     #[test]
     pub fn can_exit_updater_when_sending_end_packet()
@@ -222,8 +812,9 @@ mod test
         make_packet(&mut uart, &[super::STX, super::END, super::ETX, 0x05]);
 
         let mut flasher = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
 
-        let r = super::ImageReceiver::new(&mut flasher, & mut uart);
+        let r = super::ImageReceiver::new(&mut flasher, & mut uart, &mut crc);
         r.execute(0x1000); 
         assert!(uart.out_buf[0] == super::ACK)       
     }
@@ -240,8 +831,9 @@ mod test
         make_packet(&mut uart, &[super::STX, super::END, super::ETX, 0x05]);
 
         let mut flasher = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
 
-        let r = super::ImageReceiver::new(&mut flasher, & mut uart);
+        let r = super::ImageReceiver::new(&mut flasher, & mut uart, &mut crc);
         r.execute(0x1000);         
         assert!(uart.out_buf[0] == super::NAK)        
     }
@@ -286,8 +878,9 @@ mod test
         make_packet(&mut uart, &[super::STX, super::END, super::ETX]);
 
         let mut flasher = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
 
-        let r = super::ImageReceiver::new(&mut flasher, & mut uart);
+        let r = super::ImageReceiver::new(&mut flasher, & mut uart, &mut crc);
         r.execute(0x1000); 
 
         // read back data:
@@ -299,5 +892,376 @@ mod test
         }
     }
 
-    
-}
\ No newline at end of file
+    #[test]
+    pub fn init_metadata_is_stored_after_update_info()
+    {
+        let mut uart = FakeUart::new();
+        let packet = [super::STX,
+                                super::INIT,
+                                b'M', b'U', b'U', b'P', b'D',
+                                0x01,
+                                0x00, 0x00, 0x20, 0x00,
+                                0x00, 0x00, 0x00, 0x80,
+                                0x00, 0x00, 0x40, 0x00,
+                                0x22, 0x79, 0xEF, 0xE7,
+                                b'M', b'T', 8, 0,        // Metadata area
+                                0x01, 0x00, 4, 0, 0x01, 0x00, 0x02, 0x00,
+                                super::ETX];
+        make_packet(&mut uart, &packet);
+        make_data_packet(&mut uart, &test_image());
+        make_packet(&mut uart, &[super::STX, super::END, super::ETX]);
+
+        let mut flasher = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+
+        let r = super::ImageReceiver::new(&mut flasher, & mut uart, &mut crc);
+        r.execute(0x1000);
+
+        assert!(uart.out_buf[0..3] == [super::ACK, super::ACK, super::ACK]);
+        assert!(flasher.memory[0x1000..0x1005] == *b"MUUPD");
+        let version = crate::tlv::update_info_metadata(0x1000, &flasher).unwrap().find(crate::tlv::TAG_IMAGE_VERSION).unwrap();
+        assert_eq!(version.read_u32(&flasher), Some(0x00020001));
+    }
+
+    #[test]
+    pub fn end_will_report_bad_checksum()
+    {
+        let mut uart = FakeUart::new();
+        let packet = [super::STX,
+                                super::INIT,
+                                b'M', b'U', b'U', b'P', b'D',
+                                0x01,
+                                0x00, 0x00, 0x20, 0x00,
+                                0x00, 0x00, 0x00, 0x7F,  // last byte of the image is not part of the CRC
+                                0x00, 0x00, 0x40, 0x00,
+                                0x22, 0x79, 0xEF, 0xE7,
+                                super::ETX];
+        make_packet(&mut uart, &packet);
+        make_data_packet(&mut uart, &test_image());
+        make_packet(&mut uart, &[super::STX, super::END, super::ETX]);
+
+        let mut flasher = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+
+        let r = super::ImageReceiver::new(&mut flasher, & mut uart, &mut crc);
+        r.execute(0x1000);
+
+        assert!(uart.out_buf[0..3] == [super::ACK, super::ACK, super::BAD_CHECKSUM]);
+        assert!(flasher.memory[0x1000..0x1005] != *b"MUUPD");
+    }
+
+    #[test]
+    pub fn image_digest_is_checked_while_receiving()
+    {
+        let mut uart = FakeUart::new();
+        let packet = [super::STX,
+                                super::INIT,
+                                b'M', b'U', b'U', b'P', b'D',
+                                0x01,
+                                0x00, 0x00, 0x20, 0x00,
+                                0x00, 0x00, 0x00, 0x80,
+                                0x00, 0x00, 0x40, 0x00,
+                                0x00, 0x00, 0x00, 0x00,  // CRC-32 is not used
+                                b'M', b'T', 9, 0,
+                                0x08, 0x00, 5, 0, 0x01, 0x9E, 0x97, 0xFE, 0x78, // CRC-32C digest
+                                super::ETX];
+        make_packet(&mut uart, &packet);
+        make_data_packet(&mut uart, &test_image());
+        make_packet(&mut uart, &[super::STX, super::END, super::ETX]);
+
+        let mut flasher = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+
+        let r = super::ImageReceiver::new(&mut flasher, & mut uart, &mut crc);
+        r.execute(0x1000);
+
+        assert!(uart.out_buf[0..3] == [super::ACK, super::ACK, super::ACK]);
+        assert!(flasher.memory[0x1000..0x1005] == *b"MUUPD");
+    }
+
+    #[test]
+    pub fn data_that_does_not_verify_is_answered_with_nak()
+    {
+        let mut uart = FakeUart::new();
+        let packet = [super::STX,
+                                super::INIT,
+                                b'M', b'U', b'U', b'P', b'D',
+                                0x01,
+                                0x00, 0x00, 0x20, 0x00,
+                                0x00, 0x00, 0x00, 0x80,
+                                0x00, 0x00, 0x40, 0x00,
+                                0x22, 0x79, 0xEF, 0xE7,
+                                super::ETX];
+        make_packet(&mut uart, &packet);
+        make_data_packet(&mut uart, &test_image());
+        make_packet(&mut uart, &[super::STX, super::END, super::ETX]);
+
+        let mut flasher = FakeFlasher::new();
+        flasher.stuck_address = Some(0x2010);
+        let mut crc = Crc32Table::new();
+
+        let r = super::ImageReceiver::new(&mut flasher, & mut uart, &mut crc)
+            .with_write_options(crate::WriteOptions { verify: true, verify_retries: 1, io_retries: 0 });
+        r.execute(0x1000);
+
+        assert!(uart.out_buf[0..3] == [super::ACK, super::NAK, super::BAD_CHECKSUM]);
+        assert_eq!(flasher.write_count, 2);
+    }
+
+    #[test]
+    pub fn init_will_reject_image_for_other_board()
+    {
+        let mut uart = FakeUart::new();
+        let packet = [super::STX,
+                                super::INIT,
+                                b'M', b'U', b'U', b'P', b'D',
+                                0x01,
+                                0x00, 0x00, 0x20, 0x00,
+                                0x00, 0x00, 0x00, 0x80,
+                                0x00, 0x00, 0x40, 0x00,
+                                0x22, 0x79, 0xEF, 0xE7,
+                                b'M', b'T', 8, 0,
+                                0x04, 0x00, 4, 0, 0xB0, 0x00, 0x00, 0x00, // Board id 0xB0
+                                super::ETX];
+        make_packet(&mut uart, &packet);
+        make_data_packet(&mut uart, &test_image());
+        make_packet(&mut uart, &[super::STX, super::END, super::ETX]);
+
+        let mut flasher = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+
+        let r = super::ImageReceiver::new(&mut flasher, & mut uart, &mut crc).with_board_id(0xA0);
+        r.execute(0x1000);
+
+        assert!(uart.out_buf[0..3] == [super::NAK, super::NAK, super::ACK]);
+        assert!(flasher.memory[0x2000..0x2080].iter().all(|b| *b == 0));
+        assert!(flasher.memory[0x1000..0x1005] != *b"MUUPD");
+    }
+
+    #[test]
+    pub fn addressed_mode_handles_broadcasts_and_missing_blocks()
+    {
+        let mut image = [0u8; 256];
+        image[..128].copy_from_slice(&test_image());
+        image[128..].copy_from_slice(&test_image());
+        image[255] = 0x55;
+        let mut engine = Crc32Table::new();
+        engine.update(&image);
+        let crc = engine.finish().to_be_bytes();
+
+        let mut uart = FakeUart::new();
+        let mut init = [0u8; 26];
+        init[..3].copy_from_slice(&[super::STX, super::BROADCAST_ADDRESS, super::INIT]);
+        init[3..25].copy_from_slice(&[b'M', b'U', b'U', b'P', b'D', 0x01,
+                                      0x00, 0x00, 0x20, 0x00,
+                                      0x00, 0x00, 0x01, 0x00,
+                                      0x00, 0x00, 0x40, 0x00,
+                                      crc[0], crc[1], crc[2], crc[3]]);
+        init[25] = super::ETX;
+        make_packet(&mut uart, &init);
+
+        let mut data = [0u8; 134];
+        let mut send_block = |uart: &mut FakeUart, address: u8, block: u8|
+        {
+            data[..5].copy_from_slice(&[super::STX, address, super::DATA, 0, block]);
+            data[5..133].copy_from_slice(&image[block as usize * 128..(block as usize + 1) * 128]);
+            data[133] = super::ETX;
+            make_packet(uart, &data);
+        };
+        // Block 0 is lost, the broadcast isn't answered
+        send_block(&mut uart, super::BROADCAST_ADDRESS, 1);
+        // Another node
+        send_block(&mut uart, 7, 0);
+        // Broken broadcast, no NAK
+        copy_to_uart(&mut uart, &[super::STX, super::BROADCAST_ADDRESS, super::END, super::ETX, 0x00]);
+        // END with block 0 missing keeps the session open
+        make_packet(&mut uart, &[super::STX, super::BROADCAST_ADDRESS, super::END, super::ETX]);
+
+        let mut query = [0u8; 132];
+        query[..3].copy_from_slice(&[super::STX, 5, super::MISSING_BLOCKS]);
+        query[131] = super::ETX;
+        make_packet(&mut uart, &query);
+        send_block(&mut uart, 5, 0);
+        make_packet(&mut uart, &[super::STX, 5, super::END, super::ETX]);
+
+        let mut flasher = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+        super::ImageReceiver::new(&mut flasher, &mut uart, &mut crc).with_address(5).unwrap().execute(0x1000);
+
+        // The answer to the query flags block 0
+        assert_eq!(uart.out_buf[..4], [super::STX, 5, super::MISSING_BLOCKS, 0x01]);
+        assert!(uart.out_buf[4..131].iter().all(|b| *b == 0));
+        assert_eq!(uart.out_buf[131], super::ETX);
+        assert_eq!(uart.out_buf[133..uart.write_index], [super::ACK, super::ACK]);
+        assert!(flasher.memory[0x2000..0x2100] == image);
+        assert!(flasher.memory[0x1000..0x1005] == *b"MUUPD");
+    }
+
+    #[test]
+    pub fn broadcast_address_is_no_node_address()
+    {
+        let mut uart = FakeUart::new();
+        let mut flasher = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+        assert!(super::ImageReceiver::new(&mut flasher, &mut uart, &mut crc).with_address(super::BROADCAST_ADDRESS).is_err());
+    }
+
+    #[test]
+    pub fn error_reports_replace_nak_once_enabled()
+    {
+        let mut uart = FakeUart::new();
+        // Legacy: a single NAK
+        make_packet(&mut uart, &[super::STX, 0x42, super::ETX]);
+        make_packet(&mut uart, &[super::STX, super::ERROR_REPORTS, super::ETX]);
+        make_data_packet(&mut uart, &test_image());
+        copy_to_uart(&mut uart, &[super::STX, super::END, super::ETX, 0x00]);
+        make_packet(&mut uart, &[super::STX, super::END, super::ETX]);
+
+        let mut flasher = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+        super::ImageReceiver::new(&mut flasher, &mut uart, &mut crc).execute(0x1000);
+
+        assert_eq!(uart.out_buf[..2], [super::NAK, super::ACK]);
+        let mut expected = [0u8; 132];
+        expected[..3].copy_from_slice(&[super::STX, super::ERROR_REPORT, 0x04]);
+        expected[130] = super::ETX;
+        expected[131] = expected[..131].iter().fold(0, |bcc, byte| bcc ^ byte);
+        assert!(uart.out_buf[2..134] == expected);
+        assert_eq!(uart.out_buf[134..137], [super::STX, super::ERROR_REPORT, 0x01]);
+        assert_eq!(uart.out_buf[266], super::ACK);
+        assert_eq!(uart.write_index, 267);
+    }
+
+    #[test]
+    pub fn error_reports_carry_the_failing_address()
+    {
+        let mut uart = FakeUart::new();
+        make_packet(&mut uart, &[super::STX, super::ERROR_REPORTS, super::ETX]);
+        let packet = [super::STX, super::INIT,
+                      b'M', b'U', b'U', b'P', b'D', 0x01,
+                      0x00, 0x00, 0x20, 0x00,
+                      0x00, 0x00, 0x00, 0x80,
+                      0x00, 0x00, 0x40, 0x00,
+                      0x22, 0x79, 0xEF, 0xE7,
+                      super::ETX];
+        make_packet(&mut uart, &packet);
+        make_data_packet(&mut uart, &test_image());
+        make_packet(&mut uart, &[super::STX, super::END, super::ETX]);
+
+        let mut flasher = FakeFlasher::new();
+        flasher.stuck_address = Some(0x2010);
+        let mut crc = Crc32Table::new();
+        let options = crate::WriteOptions { verify: true, ..crate::WriteOptions::default() };
+        super::ImageReceiver::new(&mut flasher, &mut uart, &mut crc).with_write_options(options).execute(0x1000);
+
+        assert_eq!(uart.out_buf[..2], [super::ACK, super::ACK]);
+        assert_eq!(uart.out_buf[2..9], [super::STX, super::ERROR_REPORT, 0x07, 0x00, 0x00, 0x20, 0x10]);
+        // The image is incomplete
+        assert_eq!(uart.out_buf[134..137], [super::STX, super::ERROR_REPORT, 0x09]);
+    }
+
+    /// Sends `data` as XMODEM-CRC blocks, starting with block `first`.
+    fn send_xmodem_blocks(uart: &mut FakeUart, first: u8, data: &[u8])
+    {
+        for (index, chunk) in data.chunks(128).enumerate()
+        {
+            let number = first.wrapping_add(index as u8);
+            let mut block = [0x1Au8; 128];
+            block[..chunk.len()].copy_from_slice(chunk);
+            copy_to_uart(uart, &[crate::xmodem::SOH, number, !number]);
+            copy_to_uart(uart, &block);
+            copy_to_uart(uart, &crate::crc::crc16_xmodem(0, &block).to_be_bytes());
+        }
+    }
+
+    /// INIT payload for the test image, staged at 0x2000.
+    fn xmodem_header(update_len: u8) -> [u8; 128]
+    {
+        let mut header = [0u8; 128];
+        header[..22].copy_from_slice(&[b'M', b'U', b'U', b'P', b'D', 0x01,
+                                       0x00, 0x00, 0x20, 0x00,
+                                       0x00, 0x00, 0x00, update_len,
+                                       0x00, 0x00, 0x40, 0x00,
+                                       0x22, 0x79, 0xEF, 0xE7]);
+        header
+    }
+
+    #[test]
+    pub fn can_receive_update_via_xmodem()
+    {
+        let mut uart = FakeUart::new();
+        let mut file = [0u8; 256];
+        file[..128].copy_from_slice(&xmodem_header(0x80));
+        file[128..].copy_from_slice(&test_image());
+        send_xmodem_blocks(&mut uart, 1, &file[..128]);
+        // Repeated block, the sender missed our ACK
+        send_xmodem_blocks(&mut uart, 1, &file[..128]);
+        send_xmodem_blocks(&mut uart, 2, &file[128..]);
+        copy_to_uart(&mut uart, &[crate::xmodem::EOT]);
+
+        let mut flasher = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+        super::ImageReceiver::new(&mut flasher, &mut uart, &mut crc).execute_xmodem(0x1000);
+
+        assert!(uart.out_buf[0..5] == [b'C', super::ACK, super::ACK, super::ACK, super::ACK]);
+        assert!(flasher.memory[0x2000..0x2080] == test_image());
+        assert!(flasher.memory[0x1000..0x1005] == *b"MUUPD");
+    }
+
+    #[test]
+    pub fn ymodem_size_must_hold_the_image()
+    {
+        for (size, accepted) in [(&b"256"[..], true), (&b"255"[..], false)]
+        {
+            let mut uart = FakeUart::new();
+            let mut header = [0u8; 128];
+            header[..8].copy_from_slice(b"app.mub\x00");
+            header[8..8 + size.len()].copy_from_slice(size);
+            send_xmodem_blocks(&mut uart, 0, &header);
+            send_xmodem_blocks(&mut uart, 1, &xmodem_header(0x80));
+            send_xmodem_blocks(&mut uart, 2, &test_image());
+            copy_to_uart(&mut uart, &[crate::xmodem::EOT]);
+            send_xmodem_blocks(&mut uart, 0, &[0u8; 128]);
+
+            let mut flasher = FakeFlasher::new();
+            let mut crc = Crc32Table::new();
+            super::ImageReceiver::new(&mut flasher, &mut uart, &mut crc).execute_xmodem(0x1000);
+
+            assert_eq!(flasher.memory[0x1000..0x1005] == *b"MUUPD", accepted);
+            if accepted
+            {
+                assert!(uart.out_buf[..uart.write_index] == [b'C', super::ACK, b'C', super::ACK, super::ACK, super::ACK, b'C', super::ACK]);
+            }
+            else
+            {
+                assert!(uart.out_buf[..uart.write_index] == [b'C', super::ACK, b'C', crate::xmodem::CAN, crate::xmodem::CAN]);
+            }
+        }
+    }
+
+    #[test]
+    pub fn can_read_partition_table()
+    {
+        let mut uart = FakeUart::new();
+        let mut request = [0u8; 128];
+        make_payload_packet(&mut uart, super::TABLE_READ, &request);
+        request[1] = 1;
+        make_payload_packet(&mut uart, super::TABLE_READ, &request);
+        make_packet(&mut uart, &[super::STX, super::END, super::ETX]);
+
+        let mut flasher = FakeFlasher::new();
+        let (len, _) = write_partition_table(&mut flasher, 0x7400, 1);
+        let mut crc = Crc32Table::new();
+
+        let r = super::ImageReceiver::new(&mut flasher, &mut uart, &mut crc).with_partition_table([0x7000, 0x7400]);
+        r.execute(0x1000);
+
+        assert!(uart.out_buf[0..2] == [super::STX, super::TABLE_READ]);
+        assert!(uart.out_buf[2..2 + len] == flasher.memory[0x7400..0x7400 + len]);
+        assert!(uart.out_buf[130] == super::ETX);
+        let bcc = uart.out_buf[..131].iter().fold(0, |bcc, byte| bcc ^ byte);
+        assert_eq!(uart.out_buf[131], bcc);
+        // The table is shorter than two chunks
+        assert!(uart.out_buf[132..134] == [super::NAK, super::ACK]);
+    }
+}
@@ -0,0 +1,473 @@
+//! ISO 15765-2 (ISO-TP) over CAN, a `Transport` for nodes that only have
+//! a CAN bus.
+//!
+//! ```text
+//! single frame:       | 0x0 len: u4 | data (up to 7 bytes) |
+//! first frame:        | 0x1 len: u12 | data (6 bytes) |
+//! consecutive frame:  | 0x2 seq: u4 | data (up to 7 bytes) |
+//! flow control:       | 0x3 status: u4 | block_size: u8 | st_min: u8 |
+//! ```
+//!
+//! Normal addressing is used: the host sends its requests with the request
+//! id, the loader answers with the response id. Each response of a
+//! receiver (i.e. everything written up to `flush`) is sent as one ISO-TP
+//! message. Received messages are handed to the receiver byte by byte, so
+//! the muload protocol (or any other) runs unchanged on top.
+//!
+//! The port supplies the bus via `CanBus`. It has no clock, so the
+//! separation time requested by the host is left to
+//! `CanBus::separation_delay`, and a host that stops sending flow control
+//! frames stalls the transmission.
+
+use super::transport::Transport;
+
+/// Largest message that is received or sent. Larger first frames are
+/// answered with an overflow.
+pub const MAX_MESSAGE_SIZE: usize = 1024;
+
+const PCI_SINGLE: u8 = 0x00;
+const PCI_FIRST: u8 = 0x10;
+const PCI_CONSECUTIVE: u8 = 0x20;
+const PCI_FLOW_CONTROL: u8 = 0x30;
+
+const FLOW_CONTINUE: u8 = 0x00;
+const FLOW_WAIT: u8 = 0x01;
+const FLOW_OVERFLOW: u8 = 0x02;
+
+/// A classic CAN frame. Ids above 0x7FF are extended (29 bit) ids.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CanFrame
+{
+    pub id: u32,
+    pub data: [u8; 8],
+    pub len: usize
+}
+
+impl CanFrame
+{
+    pub fn new(id: u32, data: &[u8]) -> Self
+    {
+        let mut frame = Self { id, data: [0; 8], len: data.len() };
+        frame.data[..data.len()].copy_from_slice(data);
+        frame
+    }
+
+    pub fn data(&self) -> &[u8]
+    {
+        &self.data[..self.len]
+    }
+}
+
+pub trait CanBus
+{
+    /// Yields the next received frame, None if there is none. Must not
+    /// block.
+    fn receive(&mut self) -> Option<CanFrame>;
+
+    /// Sends a frame, blocks until there is room in the mailbox.
+    fn transmit(&mut self, frame: &CanFrame);
+
+    /// Waits the separation time the host requested between consecutive
+    /// frames (ISO-TP STmin encoding).
+    fn separation_delay(&mut self, _st_min: u8)
+    {
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct IsoTpConfig
+{
+    /// The id the host sends to.
+    pub request_id: u32,
+    /// The id the loader answers with.
+    pub response_id: u32,
+    /// Consecutive frames the host may send before waiting for the next
+    /// flow control frame, 0 for all of them.
+    pub block_size: u8,
+    /// Separation time the host has to keep between consecutive frames.
+    pub st_min: u8,
+    /// Frames are padded to 8 bytes with this byte, None sends short
+    /// frames.
+    pub padding: Option<u8>
+}
+
+impl IsoTpConfig
+{
+    pub fn new(request_id: u32, response_id: u32) -> Self
+    {
+        Self { request_id, response_id, block_size: 0, st_min: 0, padding: Some(0xCC) }
+    }
+}
+
+pub struct IsoTpTransport<B: CanBus>
+{
+    bus: B,
+    config: IsoTpConfig,
+    rx: [u8; MAX_MESSAGE_SIZE],
+    /// Length of the message that is received or handed out.
+    rx_len: usize,
+    /// While receiving: number of bytes received so far, afterwards the
+    /// number of bytes handed out.
+    rx_pos: usize,
+    rx_complete: bool,
+    rx_sequence: u8,
+    /// Consecutive frames left until the next flow control frame.
+    rx_block_left: u8,
+    tx: [u8; MAX_MESSAGE_SIZE],
+    tx_len: usize
+}
+
+impl<B: CanBus> IsoTpTransport<B>
+{
+    pub fn new(bus: B, config: IsoTpConfig) -> Self
+    {
+        Self
+        {
+            bus,
+            config,
+            rx: [0; MAX_MESSAGE_SIZE],
+            rx_len: 0,
+            rx_pos: 0,
+            rx_complete: false,
+            rx_sequence: 0,
+            rx_block_left: 0,
+            tx: [0; MAX_MESSAGE_SIZE],
+            tx_len: 0
+        }
+    }
+
+    pub fn release(self) -> B
+    {
+        self.bus
+    }
+
+    fn send_frame(&mut self, data: &[u8])
+    {
+        let mut frame = CanFrame::new(self.config.response_id, data);
+        if let Some(padding) = self.config.padding
+        {
+            frame.data[data.len()..].iter_mut().for_each(|byte| *byte = padding);
+            frame.len = 8;
+        }
+        self.bus.transmit(&frame);
+    }
+
+    fn send_flow_control(&mut self, status: u8)
+    {
+        self.send_frame(&[PCI_FLOW_CONTROL | status, self.config.block_size, self.config.st_min]);
+    }
+
+    /// Handles a received frame, yields whether a message is complete.
+    fn receive_frame(&mut self, frame: &CanFrame) -> bool
+    {
+        let data = frame.data();
+        if frame.id != self.config.request_id || data.is_empty()
+        {
+            return false;
+        }
+
+        match data[0] & 0xF0
+        {
+            PCI_SINGLE =>
+            {
+                let len = (data[0] & 0x0F) as usize;
+                if len == 0 || len + 1 > data.len()
+                {
+                    return false;
+                }
+                self.rx[..len].copy_from_slice(&data[1..1 + len]);
+                self.start_message(len);
+                true
+            },
+            PCI_FIRST if data.len() == 8 =>
+            {
+                let len = (((data[0] & 0x0F) as usize) << 8) | data[1] as usize;
+                if len > MAX_MESSAGE_SIZE
+                {
+                    self.send_flow_control(FLOW_OVERFLOW);
+                    return false;
+                }
+                if len < 8
+                {
+                    return false;
+                }
+                self.rx[..6].copy_from_slice(&data[2..8]);
+                self.rx_len = len;
+                self.rx_pos = 6;
+                self.rx_complete = false;
+                self.rx_sequence = 1;
+                self.rx_block_left = self.config.block_size;
+                self.send_flow_control(FLOW_CONTINUE);
+                false
+            },
+            PCI_CONSECUTIVE if !self.rx_complete && self.rx_pos < self.rx_len =>
+            {
+                if data[0] & 0x0F != self.rx_sequence
+                {
+                    // Lost a frame, drop the message.
+                    self.start_message(0);
+                    return false;
+                }
+                let len = core::cmp::min(self.rx_len - self.rx_pos, data.len() - 1);
+                self.rx[self.rx_pos..self.rx_pos + len].copy_from_slice(&data[1..1 + len]);
+                self.rx_pos += len;
+                self.rx_sequence = (self.rx_sequence + 1) & 0x0F;
+                if self.rx_pos == self.rx_len
+                {
+                    self.start_message(self.rx_len);
+                    return true;
+                }
+                if self.config.block_size != 0
+                {
+                    self.rx_block_left -= 1;
+                    if self.rx_block_left == 0
+                    {
+                        self.rx_block_left = self.config.block_size;
+                        self.send_flow_control(FLOW_CONTINUE);
+                    }
+                }
+                false
+            },
+            _ => false
+        }
+    }
+
+    /// Hands out the first `len` bytes of the rx buffer.
+    fn start_message(&mut self, len: usize)
+    {
+        self.rx_len = len;
+        self.rx_pos = 0;
+        self.rx_complete = true;
+    }
+
+    /// Waits for the host's flow control frame, yields (block size, st_min)
+    /// or None on overflow.
+    fn wait_flow_control(&mut self) -> Option<(u8, u8)>
+    {
+        loop
+        {
+            let frame = match self.bus.receive()
+            {
+                Some(frame) if frame.id == self.config.request_id && frame.len >= 3 && frame.data[0] & 0xF0 == PCI_FLOW_CONTROL => frame,
+                _ => continue
+            };
+            match frame.data[0] & 0x0F
+            {
+                FLOW_CONTINUE => return Some((frame.data[1], frame.data[2])),
+                FLOW_WAIT => continue,
+                _ => return None
+            }
+        }
+    }
+
+    fn send_message(&mut self)
+    {
+        let len = self.tx_len;
+        let tx = self.tx;
+        if len <= 7
+        {
+            let mut frame = [0u8; 8];
+            frame[0] = PCI_SINGLE | len as u8;
+            frame[1..1 + len].copy_from_slice(&tx[..len]);
+            self.send_frame(&frame[..1 + len]);
+            return;
+        }
+
+        let mut frame = [0u8; 8];
+        frame[0] = PCI_FIRST | (len >> 8) as u8;
+        frame[1] = len as u8;
+        frame[2..8].copy_from_slice(&tx[..6]);
+        self.send_frame(&frame);
+
+        let mut offset = 6;
+        let mut sequence = 1u8;
+        while offset < len
+        {
+            let (block_size, st_min) = match self.wait_flow_control()
+            {
+                Some(flow) => flow,
+                None => return
+            };
+            let mut sent = 0;
+            while offset < len && (block_size == 0 || sent < block_size)
+            {
+                if sent > 0
+                {
+                    self.bus.separation_delay(st_min);
+                }
+                let chunk = core::cmp::min(7, len - offset);
+                frame[0] = PCI_CONSECUTIVE | sequence;
+                frame[1..1 + chunk].copy_from_slice(&tx[offset..offset + chunk]);
+                self.send_frame(&frame[..1 + chunk]);
+                offset += chunk;
+                sequence = (sequence + 1) & 0x0F;
+                sent += 1;
+            }
+        }
+    }
+}
+
+impl<B: CanBus> Transport for IsoTpTransport<B>
+{
+    fn read_byte(&mut self) -> Option<u8>
+    {
+        if !(self.rx_complete && self.rx_pos < self.rx_len)
+        {
+            let frame = self.bus.receive()?;
+            if !self.receive_frame(&frame)
+            {
+                return None;
+            }
+        }
+        let byte = self.rx[self.rx_pos];
+        self.rx_pos += 1;
+        Some(byte)
+    }
+
+    fn write_byte(&mut self, byte: u8)
+    {
+        // A response that does not fit is cut off, the host sees a bad
+        // checksum.
+        if self.tx_len < MAX_MESSAGE_SIZE
+        {
+            self.tx[self.tx_len] = byte;
+            self.tx_len += 1;
+        }
+    }
+
+    fn flush(&mut self)
+    {
+        if self.tx_len > 0
+        {
+            self.send_message();
+            self.tx_len = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::crc::Crc32Table;
+    use super::*;
+
+    const REQUEST_ID: u32 = 0x7E0;
+    const RESPONSE_ID: u32 = 0x7E8;
+
+    struct FakeCan
+    {
+        incoming: [Option<CanFrame>; 16],
+        read_index: usize,
+        outgoing: [Option<CanFrame>; 16],
+        write_index: usize
+    }
+
+    impl FakeCan
+    {
+        fn new(incoming: &[CanFrame]) -> Self
+        {
+            let mut can = Self { incoming: [None; 16], read_index: 0, outgoing: [None; 16], write_index: 0 };
+            for (slot, frame) in can.incoming.iter_mut().zip(incoming.iter())
+            {
+                *slot = Some(*frame);
+            }
+            can
+        }
+
+        fn sent(&self, index: usize) -> &[u8]
+        {
+            let frame = self.outgoing[index].as_ref().unwrap();
+            assert_eq!(frame.id, RESPONSE_ID);
+            frame.data()
+        }
+    }
+
+    impl CanBus for FakeCan
+    {
+        fn receive(&mut self) -> Option<CanFrame>
+        {
+            let frame = self.incoming.get(self.read_index).copied().flatten();
+            self.read_index += 1;
+            frame
+        }
+
+        fn transmit(&mut self, frame: &CanFrame)
+        {
+            self.outgoing[self.write_index] = Some(*frame);
+            self.write_index += 1;
+        }
+    }
+
+    fn config() -> IsoTpConfig
+    {
+        IsoTpConfig { block_size: 1, padding: None, ..IsoTpConfig::new(REQUEST_ID, RESPONSE_ID) }
+    }
+
+    #[test]
+    fn can_receive_segmented_message()
+    {
+        let frames = [
+            CanFrame::new(REQUEST_ID, &[0x10, 20, 0, 1, 2, 3, 4, 5]),
+            // Not for us
+            CanFrame::new(0x123, &[0x21, 0xFF]),
+            CanFrame::new(REQUEST_ID, &[0x21, 6, 7, 8, 9, 10, 11, 12]),
+            CanFrame::new(REQUEST_ID, &[0x22, 13, 14, 15, 16, 17, 18, 19])
+        ];
+        let mut transport = IsoTpTransport::new(FakeCan::new(&frames), config());
+
+        let mut message = [0u8; 20];
+        let mut len = 0;
+        for _ in 0..32
+        {
+            if let Some(byte) = transport.read_byte()
+            {
+                message[len] = byte;
+                len += 1;
+            }
+        }
+        assert_eq!(len, 20);
+        assert!(message.iter().enumerate().all(|(index, byte)| *byte == index as u8));
+
+        let can = transport.release();
+        assert_eq!(can.write_index, 2);
+        assert_eq!(can.sent(0), &[0x30, 1, 0]);
+        assert_eq!(can.sent(1), &[0x30, 1, 0]);
+    }
+
+    #[test]
+    fn can_send_segmented_message()
+    {
+        let frames = [
+            CanFrame::new(REQUEST_ID, &[0x31, 0, 0]),
+            CanFrame::new(REQUEST_ID, &[0x30, 1, 0]),
+            CanFrame::new(REQUEST_ID, &[0x30, 0, 0])
+        ];
+        let mut transport = IsoTpTransport::new(FakeCan::new(&frames), IsoTpConfig::new(REQUEST_ID, RESPONSE_ID));
+        for byte in 0..16u8
+        {
+            transport.write_byte(byte);
+        }
+        transport.flush();
+
+        let can = transport.release();
+        assert_eq!(can.write_index, 3);
+        assert_eq!(can.sent(0), &[0x10, 16, 0, 1, 2, 3, 4, 5]);
+        assert_eq!(can.sent(1), &[0x21, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(can.sent(2), &[0x22, 13, 14, 15, 0xCC, 0xCC, 0xCC, 0xCC]);
+    }
+
+    #[test]
+    fn runs_the_receiver()
+    {
+        let frames = [CanFrame::new(REQUEST_ID, &[0x04, 0x02, 0x04, 0x03, 0x05])];
+        let mut transport = IsoTpTransport::new(FakeCan::new(&frames), config());
+        let mut flasher = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+        crate::image_receiver::ImageReceiver::new(&mut flasher, &mut transport, &mut crc).execute(0x1000);
+
+        let can = transport.release();
+        assert_eq!(can.write_index, 1);
+        assert_eq!(can.sent(0), &[0x01, 0x06]);
+    }
+}
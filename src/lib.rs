@@ -3,23 +3,48 @@
 extern crate embedded_hal;
 extern crate nb;
 
-use embedded_hal::serial::{Read, Write};
+use transport::Transport;
 use image_receiver::ImageReceiver;
+use crc::Checksum;
 
-mod crc;
+pub mod cbor;
+pub mod components;
+pub mod crc;
+pub mod data_partition;
+pub mod delta;
+pub mod dfu;
+pub mod digest;
+pub mod hexfile;
+pub mod sha256;
 mod image_receiver;
 mod image_installer;
 mod image_launcher;
+pub mod isotp;
+pub mod mcuboot;
+pub mod partition_table;
+pub mod selfupdate;
+pub mod smp;
+mod status_record;
+pub mod suit;
+pub mod swap;
+pub mod tlv;
+pub mod transport;
+pub mod uf2;
+pub mod xmodem;
 
 #[cfg(test)]
 mod testhelpers;
 
+#[derive(Debug, PartialEq)]
 pub enum WriteError
 {
     NoData,
-    AddressOutOfRange
+    AddressOutOfRange,
+    /// The flash did not hold the written data when reading it back.
+    VerifyFailed { address: usize }
 }
 
+#[derive(Debug, PartialEq)]
 pub enum ReadError
 {
     AddressOutOfRange,
@@ -27,12 +52,52 @@ pub enum ReadError
     ReadFailed
 }
 
+#[derive(Debug, PartialEq)]
+pub enum InstallError
+{
+    /// Reading the update at `address` kept failing.
+    ReadFailed { address: usize },
+    /// Writing to the target area at `address` kept failing.
+    WriteFailed { address: usize },
+    /// The flash at `address` did not hold the written data.
+    VerifyFailed { address: usize },
+    /// The installed image does not match its checksum.
+    ChecksumMismatch,
+    /// The update was refused, e.g. because its manifest is not
+    /// authentic or one of its conditions failed.
+    Rejected,
+    /// The loader configuration does not support the update, e.g. an
+    /// invalid delta page size.
+    InvalidConfig
+}
+
 #[derive(Debug, PartialEq)]
 pub enum UpdateEncoding
 {
     Raw,
-    LZMA
+    LZMA,
+    /// A patch against the resident image, see `delta`.
+    Delta,
+    /// A SUIT envelope with integrated payloads, see `suit`.
+    Suit
+}
+
+impl UpdateEncoding
+{
+    /// Decodes the value of a TAG_ENCODING entry.
+    pub fn from_u32(value: u32) -> Option<Self>
+    {
+        match value
+        {
+            tlv::ENCODING_RAW => Some(UpdateEncoding::Raw),
+            tlv::ENCODING_LZMA => Some(UpdateEncoding::LZMA),
+            tlv::ENCODING_DELTA => Some(UpdateEncoding::Delta),
+            tlv::ENCODING_SUIT => Some(UpdateEncoding::Suit),
+            _ => None
+        }
+    }
 }
+
 #[repr(C)]
 pub struct bin_info
 {
@@ -63,6 +128,183 @@ pub trait Flasher
     fn flush(&mut self);
 }
 
+/// Controls how the installer and the receiver write to flash.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions
+{
+    /// Read back every written chunk and compare it against the data
+    /// that was written. This requires the flasher to return buffered data
+    /// in `read` (or to not buffer at all).
+    pub verify: bool,
+    /// How often a chunk that did not verify is written again.
+    pub verify_retries: u8,
+    /// How often the installer repeats a failing read or write of a
+    /// chunk before it gives up.
+    pub io_retries: u8
+}
+
+/// Where receivers that don't get an update_info from the host (SMP, hex
+/// and UF2 files) put the image, and where it is installed to.
+#[derive(Debug, Clone, Copy)]
+pub struct StagingLayout
+{
+    /// Staging area, receives the image.
+    pub staging: usize,
+    /// Address the image is installed to (target_adress of the update_info).
+    pub target: usize,
+    /// Size of the staging area and of the target slot.
+    pub slot_size: usize
+}
+
+impl StagingLayout
+{
+    /// Takes the layout from the staging and application partitions of a
+    /// partition table.
+    pub fn from_table<T>(table: &partition_table::PartitionTable, flasher: &T) -> Option<Self>
+        where T: Flasher
+    {
+        let staging = table.find_kind(partition_table::KIND_STAGING, flasher)?;
+        let application = table.find_kind(partition_table::KIND_APPLICATION, flasher)?;
+        Some(Self { staging: staging.start, target: application.start, slot_size: core::cmp::min(staging.size, application.size) })
+    }
+
+    /// Writes the update_info for `len` bytes received into the staging
+    /// area. The board id, which the receiver takes from the image, is
+    /// stored in its metadata. Never pass the id of the loader itself, that
+    /// would let images for any board pass the board check.
+    fn write_update_info<T>(&self, update_info_address: usize, len: usize, checksum: u32, board_id: Option<u32>, flasher: &mut T) -> Result<(), WriteError>
+        where T: Flasher
+    {
+        let info = update_info
+        {
+            magic: *b"MUUPD",
+            struct_ver: 1,
+            update_start: self.staging,
+            update_len: len,
+            target_adress: self.target,
+            checksum: checksum as usize
+        };
+        let num_bytes = core::mem::size_of::<update_info>();
+        let data_slice = unsafe {core::slice::from_raw_parts((&info as *const update_info) as *const u8, num_bytes)};
+        flasher.write(update_info_address, data_slice)?;
+
+        if let Some(board_id) = board_id
+        {
+            let mut metadata = [0u8; tlv::TLV_AREA_HEADER_SIZE + tlv::TLV_ENTRY_HEADER_SIZE + 4];
+            metadata[0..2].copy_from_slice(&tlv::TLV_MAGIC);
+            metadata[2..4].copy_from_slice(&((tlv::TLV_ENTRY_HEADER_SIZE + 4) as u16).to_le_bytes());
+            metadata[4..6].copy_from_slice(&tlv::TAG_BOARD_ID.to_le_bytes());
+            metadata[6..8].copy_from_slice(&4u16.to_le_bytes());
+            metadata[8..12].copy_from_slice(&board_id.to_le_bytes());
+            flasher.write(update_info_address + num_bytes, &metadata)?;
+        }
+        flasher.flush();
+        Ok(())
+    }
+}
+
+/// The protocol the loader speaks while waiting for an image.
+#[derive(Debug, Clone, Copy)]
+pub enum Protocol
+{
+    /// The muload UART protocol (STX | Type | Payload | ETX | BCC).
+    Native,
+    /// The muload protocol with node addresses for multi-drop buses such as
+    /// RS-485 (STX | Address | Type | Payload | ETX | BCC). The broadcast
+    /// address 0xFF is no valid node address, the loader doesn't receive
+    /// anything with it.
+    Addressed { address: u8 },
+    /// SMP (mcumgr) over the serial transport, see `smp`. A partition table
+    /// with staging and application partitions replaces the layout.
+    Smp(StagingLayout),
+    /// Intel HEX or S-record files sent as plain text, see `hexfile`. A
+    /// partition table with staging and application partitions replaces
+    /// the layout.
+    Hex(StagingLayout),
+    /// UF2 blocks, see `uf2`. Blocks of another family are rejected if
+    /// family_id is set. The family id of a file becomes its board id. A
+    /// partition table with staging and application partitions replaces
+    /// the layout.
+    Uf2 { layout: StagingLayout, family_id: Option<u32> },
+    /// XMODEM-CRC/1K or YMODEM, the file holds the INIT payload followed by
+    /// the image, see `xmodem`.
+    Xmodem
+}
+
+/// Port specific settings of the loader.
+#[derive(Debug, Clone, Copy)]
+pub struct LoaderConfig
+{
+    pub update_info_address: usize,
+    pub bin_info_address: usize,
+    /// Identifies the hardware, see `tlv::TAG_BOARD_ID`. None accepts
+    /// updates without checking their board id.
+    pub board_id: Option<u32>,
+    pub write_options: WriteOptions,
+    /// Install updates by swapping them with the resident image instead
+    /// of overwriting it, see `swap`.
+    pub swap: Option<swap::SwapLayout>,
+    /// Loader slots used by stage0, enables loader updates, see `selfupdate`.
+    pub loader: Option<selfupdate::LoaderLayout>,
+    /// Journal and backup area used to install multi-component updates,
+    /// see `components`.
+    pub components: Option<components::ComponentLayout>,
+    /// Data partitions that can be updated, see `data_partition`.
+    pub data_partitions: &'static [data_partition::DataPartition],
+    /// Addresses of the two partition table slots. If a valid table is
+    /// found it replaces the addresses above, see `partition_table`.
+    pub partition_table: Option<[usize; 2]>,
+    /// Staging area and page size used to install delta updates, see
+    /// `delta`. A staging partition in the partition table replaces the
+    /// staging area. None disables delta updates.
+    pub delta: Option<delta::DeltaLayout>,
+    /// Identity checked by SUIT manifests, enables SUIT updates together
+    /// with a partition table, see `suit`.
+    pub suit_identity: Option<suit::SuitIdentity>,
+    pub protocol: Protocol
+}
+
+/// Persisted anti rollback state, usually kept in OTP memory, a dedicated
+/// flash page or a monotonic counter peripheral of the MCU.
+pub trait SecurityCounter
+{
+    /// The lowest image version that may be installed.
+    fn min_version(&self) -> u32;
+    /// Raises the lowest image version that may be installed. The counter
+    /// must never be decreased by the implementation.
+    fn set_min_version(&mut self, version: u32);
+    /// Checks the signature carried by an image that wants to be installed
+    /// despite being older than `min_version`. `hash` is the SHA-256 the
+    /// signature is made over, see `verify_privileged`. It covers the image,
+    /// its version and its flags.
+    fn verify_downgrade(&self, hash: &[u8; 32], signature: &[u8]) -> bool;
+    /// Checks the signature of an update that changes the device
+    /// configuration, e.g. a new partition table or a loader. `hash` is
+    /// the SHA-256 the signature is made over: target_adress and
+    /// update_len (u64 each) followed by all metadata entries except the
+    /// signature, which have to include a SHA-256 digest of the image.
+    /// Such updates are rejected unless the port implements this.
+    fn verify_privileged(&self, _hash: &[u8; 32], _signature: &[u8]) -> bool
+    {
+        false
+    }
+    /// Verifies the COSE_Sign1 signature of a SUIT manifest. `to_be_signed`
+    /// is the encoded Sig_structure. Manifests are rejected unless the port
+    /// implements this.
+    fn verify_manifest(&self, _to_be_signed: &[u8], _signature: &[u8]) -> bool
+    {
+        false
+    }
+    /// Verifies a signature TLV (`mcuboot::TLV_*`) of an MCUboot image over
+    /// its SHA-256 hash. Unsigned images are passed with type 0 and an empty
+    /// signature. MCUboot images are rejected unless the port implements
+    /// this, ports that accept unsigned images must do so explicitly.
+    fn verify_mcuboot_signature(&self, _hash: &[u8; 32], _signature_type: u16, _signature: &[u8]) -> bool
+    {
+        false
+    }
+}
+
 fn load_info_struct_from_address<T, F>(address: usize, flasher: &F) -> Result<T, ReadError>
     where F: Flasher, T: Sized
 {
@@ -81,6 +323,51 @@ fn load_info_struct_from_address<T, F>(address: usize, flasher: &F) -> Result<T,
     Err(ReadError::ReadFailed)
 }
 
+/// Yields the address of the first byte in flash that differs from `data`.
+fn find_mismatch<T>(flasher: &T, address: usize, data: &[u8]) -> Option<usize>
+    where T: Flasher
+{
+    let mut buf = [0u8; 16];
+    for (index, expected) in data.chunks(buf.len()).enumerate()
+    {
+        let chunk_address = address + index * buf.len();
+        let actual = &mut buf[..expected.len()];
+        match flasher.read(chunk_address, actual)
+        {
+            Ok(num_bytes) if num_bytes == expected.len() => {},
+            _ => return Some(chunk_address)
+        }
+
+        if let Some(offset) = actual.iter().zip(expected.iter()).position(|(a, e)| a != e)
+        {
+            return Some(chunk_address + offset);
+        }
+    }
+    None
+}
+
+/// Writes a chunk of data to flash, verifying it if requested by `options`.
+fn write_chunk<T>(flasher: &mut T, address: usize, data: &[u8], options: &WriteOptions) -> Result<(), WriteError>
+    where T: Flasher
+{
+    let mut attempts = 0;
+    loop
+    {
+        flasher.write(address, data)?;
+        if !options.verify
+        {
+            return Ok(());
+        }
+
+        match find_mismatch(flasher, address, data)
+        {
+            None => return Ok(()),
+            Some(bad_address) if attempts >= options.verify_retries => return Err(WriteError::VerifyFailed { address: bad_address }),
+            Some(_) => attempts += 1
+        }
+    }
+}
+
 // fn any_from_byte_array<T>(data: &[u8]) -> Result<T, ReadError>
 //     where T: Sized
 // {
@@ -101,56 +388,219 @@ fn load_info_struct_from_address<T, F>(address: usize, flasher: &F) -> Result<T,
 //     }
 // }
 
-fn on_error() -> !
+/// Yields the addresses of the update_info and the bin_info, the partition
+/// table replaces those of the config.
+fn info_addresses<T>(config: &LoaderConfig, table: Option<&partition_table::PartitionTable>, flasher: &T) -> (usize, usize)
+    where T: Flasher
 {
-    loop{}
+    let table_address = |kind, default| table.and_then(|table| table.find_kind(kind, flasher)).map(|entry| entry.start).unwrap_or(default);
+    (table_address(partition_table::KIND_UPDATE_INFO, config.update_info_address),
+     table_address(partition_table::KIND_BIN_INFO, config.bin_info_address))
 }
 
-pub fn muload_main<T, U: Read<u8> + Write<u8> >(update_info_address: usize, bin_info_address: usize, mut flasher: T, mut uart: U)
-    where T: Flasher 
+pub fn muload_main<T, U: Transport, S, C>(config: LoaderConfig, mut flasher: T, mut uart: U, mut counter: S, mut checksum: C)
+    where T: Flasher, S: SecurityCounter, C: Checksum
 {
+    let table = config.partition_table.and_then(|slots| partition_table::read_table(&slots, &flasher));
+    let (update_info_address, bin_info_address) = info_addresses(&config, table.as_ref(), &flasher);
+
     // first steps first: Send out a notification
     // that we are available and wait up to 100 ms for a download request.
 
     // Assumption: Lowlevel init has been done by some other piece of code,
     // we can immediately check if we have a new binary
-    if let Ok(update_info) = load_info_struct_from_address::<update_info, T>(update_info_address, &flasher)
+    if let Some(layout) = config.loader
+    {
+        // We are running, so a newly installed loader is good. If this
+        // fails stage0 falls back to the previous loader on the next reset.
+        let _ = selfupdate::confirm_loader(&layout, &mut flasher, &config.write_options);
+    }
+
+    let staged_update = load_info_struct_from_address::<update_info, T>(update_info_address, &flasher).ok();
+    let is_loader_update = staged_update.is_some() &&
+        image_installer::update_flags(update_info_address, &flasher) & tlv::FLAG_LOADER_UPDATE != 0;
+
+    let is_table_update = staged_update.is_some() &&
+        image_installer::update_flags(update_info_address, &flasher) & tlv::FLAG_PARTITION_TABLE != 0;
+
+    if is_table_update
+    {
+        if let (Some(slots), Some(update_info)) = (config.partition_table, staged_update.as_ref())
+        {
+            if image_installer::check_update_integrity(update_info, update_info_address, config.board_id, &flasher, &mut checksum) &&
+               image_installer::check_authorization(update_info, update_info_address, &flasher, &counter)
+            {
+                // Takes effect on the next boot.
+                let _ = partition_table::install_table(update_info, &slots, &mut flasher, &mut checksum, &config.write_options);
+            }
+        }
+    }
+    else if is_loader_update
+    {
+        if let (Some(layout), Some(update_info)) = (config.loader, staged_update)
+        {
+            // The security counter and the version check are meant for the
+            // application, a loader has to be signed by someone the port
+            // trusts instead. The new loader is started by stage0 on the
+            // next reset.
+            if image_installer::check_update_integrity(&update_info, update_info_address, config.board_id, &flasher, &mut checksum) &&
+               image_installer::check_authorization(&update_info, update_info_address, &flasher, &counter)
+            {
+                let _ = selfupdate::install_loader(&update_info, update_info_address, &layout, &mut flasher, &mut checksum, &config.write_options);
+            }
+        }
+    }
+    else if staged_update.is_some() && data_partition::is_data_update(update_info_address, &flasher)
+    {
+        // Data for an unknown partition is never installed anywhere.
+        let partition = data_partition::find_partition(config.data_partitions, update_info_address, &flasher).copied()
+            .or_else(|| table.and_then(|table| data_partition::partition_from_table(&table, update_info_address, &flasher)));
+        if let (Some(partition), Some(update_info)) = (partition, staged_update.as_ref())
+        {
+            // Data has no place in the anti-rollback scheme of the application.
+            if image_installer::check_update_integrity(update_info, update_info_address, config.board_id, &flasher, &mut checksum)
+            {
+                let _ = data_partition::install_data(update_info, update_info_address, &partition, &mut flasher, &mut checksum, &config.write_options);
+            }
+        }
+    }
+    else if staged_update.is_some() && components::component_count(update_info_address, &flasher) > 0
+    {
+        if let (Some(layout), Some(update_info)) = (config.components, staged_update)
+        {
+            if image_installer::check_update(&update_info, update_info_address, config.board_id, &flasher, &counter, &mut checksum) &&
+               components::install_components(&update_info, update_info_address, &layout, &mut flasher, &mut checksum, &config.write_options) == Ok(true)
+            {
+                image_installer::commit_version(update_info_address, &flasher, &mut counter);
+            }
+        }
+    }
+    else if staged_update.is_some() && image_installer::update_encoding(update_info_address, &flasher) == Some(UpdateEncoding::Suit)
+    {
+        // Components are resolved through the partition table, the manifest
+        // replaces the version check of the update_info.
+        if let (Some(identity), Some(table), Some(update_info)) = (config.suit_identity, table, staged_update.as_ref())
+        {
+            if image_installer::check_update_integrity(update_info, update_info_address, config.board_id, &flasher, &mut checksum)
+            {
+                let _ = suit::process(update_info, &table, &identity, &mut flasher, &mut counter, &config.write_options);
+            }
+        }
+    }
+    else if let Some(layout) = config.swap
+    {
+        // If this fails we end up with a broken primary slot, which
+        // check_binary will notice. The next boot resumes the swap.
+        let _ = swap::prepare_boot(&layout, staged_update.as_ref(), update_info_address, &config, &mut flasher, &mut counter, &mut checksum);
+    }
+    else if let Some(update_info) = staged_update
     {        
-        if image_installer::check_update(&update_info, &flasher)
+        if image_installer::check_update(&update_info, update_info_address, config.board_id, &flasher, &counter, &mut checksum)
         {
-            if !image_installer::install_binary(&update_info, &mut flasher)
+            let result = match image_installer::update_encoding(update_info_address, &flasher)
             {
-                // Installation failed. This is basically the worst case as
-                // we now destroyed the installed image with a halfbaked version
-                // of the previous image. We can't do much here. Note that this
-                // issue can only arise if the actual installation failed as
-                // a faulty image would have been caught by check_update.
-                on_error();
+                Some(UpdateEncoding::Delta) => match config.delta
+                {
+                    Some(layout) => delta::install_delta(&update_info, update_info_address, &layout.with_table(table.as_ref(), &flasher), &mut flasher, &mut checksum, &config.write_options),
+                    None => Err(InstallError::Rejected)
+                },
+                _ => image_installer::install_binary(&update_info, update_info_address, &mut flasher, &mut checksum, &config.write_options)
+            };
+            match result
+            {
+                Ok(()) =>
+                {
+                    // Never accept anything older than what we just installed.
+                    image_installer::commit_version(update_info_address, &flasher, &mut counter);
+                }
+                Err(_) =>
+                {
+                    // Installation failed. If it failed before anything was
+                    // written the resident image is still intact and will be
+                    // launched below. Otherwise the resident image won't pass
+                    // check_binary and we end up in recovery mode. The update
+                    // is still staged, so the next boot will try again.
+                }
             }
         }
     }
 
-    // // At this point: either a binary was installed... or not. We don't care for now,
-    // // but attempt to launch the actually installed binary if that is good:
+    // At this point: either a binary was installed... or not. We don't care for now,
+    // but attempt to launch the actually installed binary if that is good:
     if let Ok(binary_info) = load_info_struct_from_address::<bin_info, T>(bin_info_address, &flasher)
     {
-        if !image_launcher::check_binary(&binary_info, &flasher)
+        if image_launcher::check_binary(&binary_info, bin_info_address, &flasher, &mut checksum)
         {
             // Note that we assume that the app binary will setup its own stack and the likes
             // so basically: after we call app_start everything will be setup by the cstart routine (or similar)
             // of the binary.
-            image_launcher::launch_binary(binary_info);
+            image_launcher::launch_binary(image_launcher::entry_point(&binary_info, &flasher));
         }
-        // we should never get here!
-        on_error();
     }
-    else
+
+    // Nothing bootable available - we stay in bootmode and wait until someone sends us
+    // a binary via u(s)art
+    match config.protocol
     {
-        // Nothing bootable available - we stay in bootmode and wait until someone sends us
-        // a binary via u(s)art
-        let rec = ImageReceiver::new(&mut flasher, &mut uart);
-        rec.execute(update_info_address);
-        // after we received the binary we just reboot. We'll endup in this function again
-        // with a hopefully wellformed update_info which can be installed and booted.        
+        Protocol::Native | Protocol::Addressed { .. } =>
+        {
+            let mut rec = ImageReceiver::new(&mut flasher, &mut uart, &mut checksum)
+                .with_write_options(config.write_options);
+            if let Some(board_id) = config.board_id
+            {
+                rec = rec.with_board_id(board_id);
+            }
+            if let Some(slots) = config.partition_table
+            {
+                rec = rec.with_partition_table(slots);
+            }
+            if let Protocol::Addressed { address } = config.protocol
+            {
+                rec = match rec.with_address(address)
+                {
+                    Ok(rec) => rec,
+                    // A node at the broadcast address would answer broadcasts.
+                    Err(_) => return
+                };
+            }
+            rec.execute(update_info_address);
+        }
+        Protocol::Xmodem =>
+        {
+            let mut rec = ImageReceiver::new(&mut flasher, &mut uart, &mut checksum)
+                .with_write_options(config.write_options);
+            if let Some(board_id) = config.board_id
+            {
+                rec = rec.with_board_id(board_id);
+            }
+            rec.execute_xmodem(update_info_address);
+        }
+        Protocol::Smp(layout) =>
+        {
+            let layout = table.and_then(|table| StagingLayout::from_table(&table, &flasher)).unwrap_or(layout);
+            smp::SmpReceiver::new(&mut flasher, &mut uart, &mut checksum, layout)
+                .with_write_options(config.write_options)
+                .execute(update_info_address);
+        }
+        Protocol::Hex(layout) =>
+        {
+            let layout = table.and_then(|table| StagingLayout::from_table(&table, &flasher)).unwrap_or(layout);
+            hexfile::HexReceiver::new(&mut flasher, &mut uart, &mut checksum, layout)
+                .with_write_options(config.write_options)
+                .execute(update_info_address);
+        }
+        Protocol::Uf2 { layout, family_id } =>
+        {
+            let layout = table.and_then(|table| StagingLayout::from_table(&table, &flasher)).unwrap_or(layout);
+            let mut rec = uf2::Uf2Receiver::new(&mut flasher, &mut uart, &mut checksum, layout)
+                .with_write_options(config.write_options);
+            if let Some(family_id) = family_id
+            {
+                rec = rec.with_family_id(family_id);
+            }
+            rec.execute(update_info_address);
+        }
     }
+    // after we received the binary we just reboot. We'll endup in this function again
+    // with a hopefully wellformed update_info which can be installed and booted.        
 }
\ No newline at end of file
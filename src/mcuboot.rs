@@ -0,0 +1,302 @@
+//! MCUboot image format, as produced by `imgtool sign`.
+//!
+//! ```text
+//! | header (hdr_size bytes) | image (img_size bytes) | protected TLVs | TLVs |
+//!
+//! header: | magic: u32 | load_addr: u32 | hdr_size: u16 | protect_tlv_size: u16 | img_size: u32 |
+//!         | flags: u32 | major: u8 | minor: u8 | revision: u16 | build_num: u32 | pad: u32 |
+//! TLV area: | magic: u16 | tlv_tot: u16 | type: u16 | len: u16 | value[len] | type ... |
+//! ```
+//!
+//! All fields are little endian. tlv_tot includes the area header, the
+//! protected area (magic 0x6908) is only present if protect_tlv_size is not
+//! 0. The SHA-256 TLV covers everything before the unprotected area, the
+//! signature TLVs sign that hash.
+//!
+//! MCUboot images are accepted wherever a plain image is: an image starting
+//! with the MCUboot magic is checked against its SHA-256 TLV instead of the
+//! CRC-32 (or digest) of bin_info/update_info, and its header version takes
+//! part in the anti-rollback check, a version in the update_info is ignored
+//! as it isn't covered by the hash. Staged images are only installed once
+//! the port accepted their signature. Encrypted images are not supported.
+
+use super::{Flasher, crc, tlv};
+use super::sha256::{Sha256, SHA256_DIGEST_SIZE};
+
+pub const IMAGE_MAGIC: u32 = 0x96f3_b83d;
+pub const HEADER_SIZE: usize = 32;
+pub const TLV_INFO_MAGIC: u16 = 0x6907;
+pub const TLV_PROT_INFO_MAGIC: u16 = 0x6908;
+pub const TLV_HEADER_SIZE: usize = 4;
+
+pub const TLV_KEYHASH: u16 = 0x01;
+pub const TLV_SHA256: u16 = 0x10;
+pub const TLV_RSA2048_PSS: u16 = 0x20;
+pub const TLV_ECDSA224: u16 = 0x21;
+pub const TLV_ECDSA256: u16 = 0x22;
+pub const TLV_RSA3072_PSS: u16 = 0x23;
+pub const TLV_ED25519: u16 = 0x24;
+/// Custom protected TLV holding the board id (u32, little endian) the image
+/// was built for, see `tlv::TAG_BOARD_ID`. Added with `imgtool sign
+/// --custom-tlv 0xB0 <value>`.
+pub const TLV_BOARD_ID: u16 = 0xB0;
+
+const FLAG_ENCRYPTED_AES128: u32 = 0x0000_0004;
+const FLAG_ENCRYPTED_AES256: u32 = 0x0000_0008;
+
+/// Large enough for RSA-3072 signatures.
+const MAX_SIGNATURE_SIZE: usize = 384;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ImageVersion
+{
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u16,
+    pub build_num: u32
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ImageHeader
+{
+    pub load_addr: u32,
+    pub hdr_size: usize,
+    pub protect_tlv_size: usize,
+    pub img_size: usize,
+    pub flags: u32,
+    pub version: ImageVersion
+}
+
+impl ImageHeader
+{
+    /// Reads the header at `address`, yields None if there is no MCUboot
+    /// image.
+    pub fn read<T>(address: usize, flasher: &T) -> Option<Self>
+        where T: Flasher
+    {
+        let mut bytes = [0u8; HEADER_SIZE];
+        match flasher.read(address, &mut bytes)
+        {
+            Ok(HEADER_SIZE) => {},
+            _ => return None
+        }
+
+        let half = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let word = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        if word(0) != IMAGE_MAGIC || (half(8) as usize) < HEADER_SIZE
+        {
+            return None;
+        }
+
+        Some(Self
+        {
+            load_addr: word(4),
+            hdr_size: half(8) as usize,
+            protect_tlv_size: half(10) as usize,
+            img_size: word(12) as usize,
+            flags: word(16),
+            version: ImageVersion { major: bytes[20], minor: bytes[21], revision: half(22), build_num: word(24) }
+        })
+    }
+
+    /// The version in the layout of `tlv::TAG_IMAGE_VERSION`, the build
+    /// number is dropped.
+    pub fn image_version(&self) -> u32
+    {
+        tlv::image_version(self.version.major, self.version.minor, self.version.revision)
+    }
+
+    /// Offset of the unprotected TLV area, i.e. the length of the hashed
+    /// part of the image. Yields None if the sizes overflow.
+    pub fn hashed_len(&self) -> Option<usize>
+    {
+        self.hdr_size.checked_add(self.img_size)?.checked_add(self.protect_tlv_size)
+    }
+}
+
+pub fn is_image<T>(address: usize, flasher: &T) -> bool
+    where T: Flasher
+{
+    ImageHeader::read(address, flasher).is_some()
+}
+
+/// A checked image.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Image
+{
+    pub address: usize,
+    pub header: ImageHeader,
+    pub hash: [u8; SHA256_DIGEST_SIZE],
+    /// Size of the image including all TLVs.
+    pub len: usize
+}
+
+/// Yields the (type, value address, len) of the TLVs in the unprotected area.
+fn tlv_entries<'a, T>(address: usize, header: &ImageHeader, flasher: &'a T) -> Option<impl Iterator<Item = (u16, usize, usize)> + 'a>
+    where T: Flasher
+{
+    area_entries(address.checked_add(header.hashed_len()?)?, TLV_INFO_MAGIC, flasher)
+}
+
+/// Yields the (type, value address, len) of the TLVs in the area at `area`,
+/// which starts with `magic`.
+fn area_entries<T>(area: usize, magic: u16, flasher: &T) -> Option<impl Iterator<Item = (u16, usize, usize)> + '_>
+    where T: Flasher
+{
+    let mut info = [0u8; TLV_HEADER_SIZE];
+    match flasher.read(area, &mut info)
+    {
+        Ok(TLV_HEADER_SIZE) => {},
+        _ => return None
+    }
+    if u16::from_le_bytes([info[0], info[1]]) != magic
+    {
+        return None;
+    }
+
+    let end = area.checked_add(u16::from_le_bytes([info[2], info[3]]) as usize)?;
+    let mut next = area.checked_add(TLV_HEADER_SIZE)?;
+    Some(core::iter::from_fn(move || {
+        let mut entry = [0u8; TLV_HEADER_SIZE];
+        if next.checked_add(TLV_HEADER_SIZE)? > end || flasher.read(next, &mut entry) != Ok(TLV_HEADER_SIZE)
+        {
+            return None;
+        }
+        let value = next + TLV_HEADER_SIZE;
+        let len = u16::from_le_bytes([entry[2], entry[3]]) as usize;
+        if value.checked_add(len)? > end
+        {
+            return None;
+        }
+        next = value + len;
+        Some((u16::from_le_bytes([entry[0], entry[1]]), value, len))
+    }))
+}
+
+/// Yields the board id (TLV_BOARD_ID) in the protected TLVs of the image at
+/// `address`. The value is covered by the hash of the image, so it can be
+/// trusted once the image was checked.
+pub fn board_id<T>(address: usize, flasher: &T) -> Option<u32>
+    where T: Flasher
+{
+    let header = ImageHeader::read(address, flasher)?;
+    if header.protect_tlv_size == 0
+    {
+        return None;
+    }
+    let area = address.checked_add(header.hdr_size)?.checked_add(header.img_size)?;
+    let (_, value, len) = area_entries(area, TLV_PROT_INFO_MAGIC, flasher)?.find(|(kind, _, _)| *kind == TLV_BOARD_ID)?;
+    let mut buf = [0u8; 4];
+    match flasher.read(value, &mut buf)
+    {
+        Ok(4) if len == 4 => Some(u32::from_le_bytes(buf)),
+        _ => None
+    }
+}
+
+/// Checks the image at `address`, which must fit into `len` bytes, against
+/// its SHA-256 TLV.
+pub fn check_image<T>(address: usize, len: usize, flasher: &T) -> Option<Image>
+    where T: Flasher
+{
+    let header = ImageHeader::read(address, flasher)?;
+    let hashed_len = header.hashed_len()?;
+    if header.flags & (FLAG_ENCRYPTED_AES128 | FLAG_ENCRYPTED_AES256) != 0 || hashed_len.checked_add(TLV_HEADER_SIZE)? > len
+    {
+        return None;
+    }
+
+    let mut tlv_len = [0u8; 2];
+    match flasher.read(address.checked_add(hashed_len + 2)?, &mut tlv_len)
+    {
+        Ok(2) if hashed_len + u16::from_le_bytes(tlv_len) as usize <= len => {},
+        _ => return None
+    }
+
+    let (_, hash_address, hash_len) = tlv_entries(address, &header, flasher)?.find(|(kind, _, _)| *kind == TLV_SHA256)?;
+    let mut hash = [0u8; SHA256_DIGEST_SIZE];
+    if hash_len != SHA256_DIGEST_SIZE || flasher.read(hash_address, &mut hash) != Ok(SHA256_DIGEST_SIZE)
+    {
+        return None;
+    }
+
+    let mut sha = Sha256::new();
+    if !crc::read_chunks(address, hashed_len, flasher, |chunk| sha.update(chunk)) || sha.finish() != hash
+    {
+        return None;
+    }
+
+    Some(Image { address, header, hash, len: hashed_len + u16::from_le_bytes(tlv_len) as usize })
+}
+
+/// Passes each signature TLV of a checked image to `verify` (along with its
+/// type) until one is accepted. Unsigned images pass type 0 and an empty
+/// signature, so the port decides whether they are acceptable.
+pub fn check_signature<T, F>(image: &Image, flasher: &T, mut verify: F) -> bool
+    where T: Flasher, F: FnMut(u16, &[u8]) -> bool
+{
+    let entries = match tlv_entries(image.address, &image.header, flasher)
+    {
+        Some(entries) => entries,
+        None => return false
+    };
+
+    let mut signed = false;
+    let mut buf = [0u8; MAX_SIGNATURE_SIZE];
+    for (kind, address, len) in entries.filter(|(kind, _, _)| (TLV_RSA2048_PSS..=TLV_ED25519).contains(kind))
+    {
+        signed = true;
+        if len <= MAX_SIGNATURE_SIZE && flasher.read(address, &mut buf[..len]) == Ok(len) && verify(kind, &buf[..len])
+        {
+            return true;
+        }
+    }
+    !signed && verify(0, &[])
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use super::*;
+
+    #[test]
+    fn can_check_image()
+    {
+        let mut fl = FakeFlasher::new();
+        assert!(!is_image(0x1000, &fl));
+        let len = write_mcuboot_image(&mut fl, 0x1000, (1, 2, 3));
+
+        let image = check_image(0x1000, len, &fl).unwrap();
+        assert_eq!(image.len, len);
+        assert_eq!(image.header.image_version(), tlv::image_version(1, 2, 3));
+        assert!(check_signature(&image, &fl, |kind, signature| kind == TLV_ECDSA256 && signature == TEST_MCUBOOT_SIGNATURE));
+        assert!(!check_signature(&image, &fl, |_, _| false));
+
+        // Too short for its TLVs
+        assert_eq!(check_image(0x1000, len - 1, &fl), None);
+
+        assert_eq!(board_id(0x1000, &fl), None);
+        let len = write_mcuboot_image_for_board(&mut fl, 0x2000, (1, 2, 3), Some(TEST_BOARD_ID));
+        assert!(check_image(0x2000, len, &fl).is_some());
+        assert_eq!(board_id(0x2000, &fl), Some(TEST_BOARD_ID));
+
+        // Tampered protected TLV
+        fl.memory[0x1000 + 0xA4] ^= 0x01;
+        assert_eq!(check_image(0x1000, len, &fl), None);
+    }
+
+    #[test]
+    fn rejects_overflowing_sizes()
+    {
+        let mut fl = FakeFlasher::new();
+        write_mcuboot_image(&mut fl, 0x1000, (1, 2, 3));
+        let mut header = ImageHeader::read(0x1000, &fl).unwrap();
+        assert_eq!(header.hashed_len(), Some(0xA8));
+        assert!(tlv_entries(usize::MAX - 0x10, &header, &fl).is_none());
+
+        header.img_size = usize::MAX;
+        assert_eq!(header.hashed_len(), None);
+        assert!(tlv_entries(0x1000, &header, &fl).is_none());
+    }
+}
@@ -0,0 +1,278 @@
+//! Partition table stored in flash.
+//!
+//! The table describes the flash layout (loader, application, bin_info,
+//! update_info, staging area, data partitions, ...), such that neither the
+//! port nor the update header has to hardcode it. Layout (little endian):
+//!
+//! ```text
+//! | 'M' 'U' 'P' 'T' | format: u16 | count: u16 | revision: u32 | entry[count] | crc: u32 |
+//!
+//! entry: | name: [u8; 16] | kind: u8 | flags: u8 | reserved: u16 | start: u32 | size: u32 | aux: u32 |
+//! ```
+//!
+//! The name is zero padded. aux depends on the kind, for data partitions it is
+//! the address of the data_info struct. flags are not interpreted by the
+//! loader. crc is the CRC-32 of everything before it.
+//!
+//! The table is kept in two slots. The valid table with the highest revision
+//! is used, a new table is always written to the other slot, so a failed
+//! rewrite leaves the current table untouched.
+
+use super::{update_info, Flasher, InstallError, WriteOptions, image_installer};
+use super::crc::{self, Checksum, Crc32Nibble};
+use super::data_partition::DataPartition;
+
+pub const TABLE_MAGIC: [u8; 4] = *b"MUPT";
+pub const TABLE_FORMAT: u16 = 1;
+pub const TABLE_HEADER_SIZE: usize = 12;
+pub const ENTRY_SIZE: usize = 32;
+pub const MAX_ENTRIES: usize = 16;
+/// Longest supported partition name, also for data partitions.
+pub const MAX_NAME_SIZE: usize = 16;
+/// Size of the largest possible table, including its CRC.
+pub const MAX_TABLE_SIZE: usize = TABLE_HEADER_SIZE + MAX_ENTRIES * ENTRY_SIZE + 4;
+
+pub const KIND_LOADER: u8 = 0x01;
+pub const KIND_APPLICATION: u8 = 0x02;
+pub const KIND_BIN_INFO: u8 = 0x03;
+pub const KIND_UPDATE_INFO: u8 = 0x04;
+pub const KIND_STAGING: u8 = 0x05;
+pub const KIND_DATA: u8 = 0x06;
+pub const KIND_SCRATCH: u8 = 0x07;
+pub const KIND_STATUS: u8 = 0x08;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PartitionEntry
+{
+    name: [u8; MAX_NAME_SIZE],
+    pub kind: u8,
+    pub flags: u8,
+    pub start: usize,
+    pub size: usize,
+    pub aux: usize
+}
+
+impl PartitionEntry
+{
+    fn from_bytes(bytes: &[u8; ENTRY_SIZE]) -> Self
+    {
+        let word = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as usize;
+        let mut name = [0u8; MAX_NAME_SIZE];
+        name.copy_from_slice(&bytes[0..MAX_NAME_SIZE]);
+        Self { name, kind: bytes[16], flags: bytes[17], start: word(20), size: word(24), aux: word(28) }
+    }
+
+    /// The name without its padding.
+    pub fn name(&self) -> &[u8]
+    {
+        let len = self.name.iter().position(|byte| *byte == 0).unwrap_or(MAX_NAME_SIZE);
+        &self.name[..len]
+    }
+}
+
+/// A valid table located in flash.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PartitionTable
+{
+    pub address: usize,
+    pub count: usize,
+    pub revision: u32
+}
+
+impl PartitionTable
+{
+    /// Size of the table in flash, including its CRC.
+    pub fn size(&self) -> usize
+    {
+        TABLE_HEADER_SIZE + self.count * ENTRY_SIZE + 4
+    }
+
+    pub fn entry<T>(&self, index: usize, flasher: &T) -> Option<PartitionEntry>
+        where T: Flasher
+    {
+        if index >= self.count
+        {
+            return None;
+        }
+
+        let mut bytes = [0u8; ENTRY_SIZE];
+        match flasher.read(self.address + TABLE_HEADER_SIZE + index * ENTRY_SIZE, &mut bytes)
+        {
+            Ok(ENTRY_SIZE) => Some(PartitionEntry::from_bytes(&bytes)),
+            _ => None
+        }
+    }
+
+    /// Yields the first partition of the given kind.
+    pub fn find_kind<T>(&self, kind: u8, flasher: &T) -> Option<PartitionEntry>
+        where T: Flasher
+    {
+        (0..self.count).filter_map(|index| self.entry(index, flasher)).find(|entry| entry.kind == kind)
+    }
+
+    pub fn find_name<T>(&self, name: &[u8], flasher: &T) -> Option<PartitionEntry>
+        where T: Flasher
+    {
+        (0..self.count).filter_map(|index| self.entry(index, flasher)).find(|entry| entry.name() == name)
+    }
+
+    /// Yields the data partition with the given name.
+    pub fn data_partition<T>(&self, name: &[u8], flasher: &T) -> Option<DataPartition>
+        where T: Flasher
+    {
+        match self.find_name(name, flasher)
+        {
+            // The name of the DataPartition is only used to look it up.
+            Some(entry) if entry.kind == KIND_DATA => Some(DataPartition { name: "", info_address: entry.aux, start: entry.start, size: entry.size }),
+            _ => None
+        }
+    }
+}
+
+/// Checks the table at the given address.
+pub fn check_table<T>(address: usize, flasher: &T) -> Option<PartitionTable>
+    where T: Flasher
+{
+    let mut header = [0u8; TABLE_HEADER_SIZE];
+    match flasher.read(address, &mut header)
+    {
+        Ok(TABLE_HEADER_SIZE) => {},
+        _ => return None
+    }
+
+    let format = u16::from_le_bytes([header[4], header[5]]);
+    let count = u16::from_le_bytes([header[6], header[7]]) as usize;
+    if header[0..4] != TABLE_MAGIC || format != TABLE_FORMAT || count > MAX_ENTRIES
+    {
+        return None;
+    }
+
+    let table = PartitionTable { address, count, revision: u32::from_le_bytes([header[8], header[9], header[10], header[11]]) };
+    let crc_address = address + table.size() - 4;
+    let mut crc = [0u8; 4];
+    match flasher.read(crc_address, &mut crc)
+    {
+        Ok(4) => {},
+        _ => return None
+    }
+
+    if !crc::check_crc(address, crc_address - address, u32::from_le_bytes(crc) as usize, flasher, &mut Crc32Nibble::new())
+    {
+        return None;
+    }
+    Some(table)
+}
+
+/// Yields the current table, i.e. the valid one with the highest revision.
+pub fn read_table<T>(slots: &[usize; 2], flasher: &T) -> Option<PartitionTable>
+    where T: Flasher
+{
+    match (check_table(slots[0], flasher), check_table(slots[1], flasher))
+    {
+        (Some(a), Some(b)) => if a.revision >= b.revision { Some(a) } else { Some(b) },
+        (a, b) => a.or(b)
+    }
+}
+
+/// Installs a new table staged by an update. The update has to be checked
+/// and authorized by the caller (`image_installer::check_authorization`,
+/// which binds the signature to the staged table through its SHA-256
+/// digest). Yields false if the staged table is not newer than the
+/// current one.
+pub fn install_table<T, C>(data: &update_info, slots: &[usize; 2], flasher: &mut T, engine: &mut C, options: &WriteOptions) -> Result<bool, InstallError>
+    where T: Flasher, C: Checksum
+{
+    let staged = check_table(data.update_start, flasher).ok_or(InstallError::ChecksumMismatch)?;
+    if staged.size() != data.update_len
+    {
+        return Err(InstallError::ChecksumMismatch);
+    }
+
+    let current = read_table(slots, flasher);
+    if current.map(|table| staged.revision <= table.revision).unwrap_or(false)
+    {
+        return Ok(false);
+    }
+
+    // Never overwrite the table that is in use.
+    let target = match current
+    {
+        Some(table) if table.address == slots[0] => slots[1],
+        _ => slots[0]
+    };
+    image_installer::copy_area(data.update_start, data.update_len, target, flasher, options)?;
+
+    if !crc::check_crc(target, data.update_len, data.checksum, flasher, engine) || check_table(target, flasher).is_none()
+    {
+        return Err(InstallError::ChecksumMismatch);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::crc::Crc32Table;
+    use crate::{update_info, tlv, WriteOptions};
+    use super::*;
+
+    const SLOTS: [usize; 2] = [0x7000, 0x7400];
+
+    #[test]
+    fn can_read_table()
+    {
+        let mut fl = FakeFlasher::new();
+        assert_eq!(read_table(&SLOTS, &fl), None);
+
+        write_partition_table(&mut fl, SLOTS[1], 3);
+        let table = read_table(&SLOTS, &fl).unwrap();
+        assert_eq!(table.revision, 3);
+        assert_eq!(table.find_kind(KIND_APPLICATION, &fl).map(|entry| entry.start), Some(0x4000));
+        let fonts = table.data_partition(b"fonts", &fl).unwrap();
+        assert_eq!((fonts.info_address, fonts.start), (0x5000, 0x5100));
+        assert!(table.data_partition(b"app", &fl).is_none());
+
+        fl.memory[SLOTS[1] + TABLE_HEADER_SIZE + 20] ^= 0x01;
+        assert_eq!(read_table(&SLOTS, &fl), None);
+    }
+
+    #[test]
+    fn new_table_goes_to_the_other_slot()
+    {
+        let mut fl = FakeFlasher::new();
+        write_partition_table(&mut fl, SLOTS[0], 1);
+        let (len, checksum) = write_partition_table(&mut fl, 0x1000, 2);
+        let update = update_info { magic: *b"MUUPD", struct_ver: 1, update_start: 0x1000, update_len: len, target_adress: 0, checksum: checksum as usize };
+
+        assert_eq!(install_table(&update, &SLOTS, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Ok(true));
+        assert_eq!(read_table(&SLOTS, &fl).map(|table| (table.address, table.revision)), Some((SLOTS[1], 2)));
+        assert_eq!(check_table(SLOTS[0], &fl).map(|table| table.revision), Some(1));
+
+        // Same revision again
+        assert_eq!(install_table(&update, &SLOTS, &mut fl, &mut Crc32Table::new(), &WriteOptions::default()), Ok(false));
+    }
+
+    #[test]
+    fn table_signature_covers_the_table()
+    {
+        let mut fl = FakeFlasher::new();
+        let (len, checksum) = write_partition_table(&mut fl, 0x1000, 2);
+        let update = update_info { magic: *b"MUUPD", struct_ver: 1, update_start: 0x1000, update_len: len, target_adress: 0, checksum: checksum as usize };
+        let flags = tlv::FLAG_PARTITION_TABLE.to_le_bytes();
+        let digest = sha256_digest_value(&fl.memory[0x1000..0x1000 + len]);
+        let entries: [(u16, &[u8]); 3] = [(tlv::TAG_FLAGS, &flags), (tlv::TAG_DIGEST, &digest), (tlv::TAG_SIGNATURE, &[0x5A, 0x5A])];
+        write_metadata(&mut fl, core::mem::size_of::<update_info>(), &entries);
+
+        let mut counter = FakeSecurityCounter::new(0);
+        counter.accepted_signature = Some([0x5A, 0x5A]);
+        counter.signed_hash = Some(update_signed_hash(0, len, &entries));
+        assert!(crate::image_installer::check_authorization(&update, 0, &fl, &counter));
+
+        // A table of the same size that moves the partitions, even with a
+        // matching CRC-32 in the update_info, is not covered.
+        let (other_len, other_checksum) = write_partition_table_entries(&mut fl, 0x1000, 2, &[(b"app", KIND_APPLICATION, 0x100, 0x1000, 0), (b"fonts", KIND_DATA, 0x5100, 0x1000, 0x5000)]);
+        let replayed = update_info { update_len: other_len, checksum: other_checksum as usize, ..update };
+        assert!(!crate::image_installer::check_authorization(&replayed, 0, &fl, &counter));
+    }
+}
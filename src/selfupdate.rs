@@ -0,0 +1,310 @@
+//! Updating the loader itself.
+//!
+//! The loader is split into a minimal first stage (stage0), which is never
+//! updated, and two slots holding a complete loader each. stage0 calls
+//! `select_loader` and jumps to the slot it yields.
+//!
+//! A loader update is an update_info with `tlv::FLAG_LOADER_UPDATE` set,
+//! targeting the slot that is not running. The running loader
+//! 1. checks the integrity of the update and that it is signed by someone
+//!    the port trusts (`SecurityCounter::verify_privileged`),
+//! 2. copies it into the inactive slot and verifies it there,
+//! 3. marks the new slot as TestPending.
+//!
+//! On the next reset stage0 switches to the new slot and marks it as
+//! Testing. Once the new loader runs it confirms itself (`confirm_loader`,
+//! done by `muload_main`). If it never gets that far, stage0 switches back
+//! to the old loader on the following reset. The old loader is kept until
+//! the next loader update.
+//!
+//! Like the swap status the selection is kept in two records, which are
+//! written alternately.
+
+use super::{update_info, Flasher, InstallError, WriteOptions, image_installer};
+use super::crc::{self, Checksum, Crc32Nibble};
+use super::status_record;
+
+const STATUS_MAGIC: [u8; 4] = *b"MULD";
+const STATUS_SIZE: usize = 32;
+
+/// Describes the flash areas used by stage0 and the loader slots.
+#[derive(Debug, Clone, Copy)]
+pub struct LoaderLayout
+{
+    /// Start addresses of the two loader slots.
+    pub slots: [usize; 2],
+    pub slot_size: usize,
+    /// Addresses of the two status records. They have to be located in
+    /// different erase units, outside of the slots.
+    pub status: [usize; 2]
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LoaderState
+{
+    /// The active slot holds a loader that confirmed itself.
+    Confirmed,
+    /// A new loader was installed into the active slot and was not started yet.
+    TestPending,
+    /// The new loader was started, but did not confirm itself yet.
+    Testing
+}
+
+impl LoaderState
+{
+    fn from_u8(value: u8) -> Option<Self>
+    {
+        match value
+        {
+            0 => Some(LoaderState::Confirmed),
+            1 => Some(LoaderState::TestPending),
+            2 => Some(LoaderState::Testing),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LoaderStatus
+{
+    pub state: LoaderState,
+    /// Index of the slot stage0 launches.
+    pub active: u8,
+    /// Checksum of the last update that was installed, such that the
+    /// same update is not installed again.
+    pub update_checksum: u32,
+    /// Length and CRC-32 of the loader in each slot, a length of 0
+    /// means that the slot is not verified by stage0.
+    pub len: [u32; 2],
+    pub crc: [u32; 2],
+    sequence: u32
+}
+
+impl LoaderStatus
+{
+    fn to_bytes(self) -> [u8; STATUS_SIZE]
+    {
+        let mut bytes = [0u8; STATUS_SIZE];
+        bytes[0..4].copy_from_slice(&STATUS_MAGIC);
+        bytes[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8] = self.state as u8;
+        bytes[9] = self.active;
+        bytes[12..16].copy_from_slice(&self.update_checksum.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.len[0].to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.len[1].to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.crc[0].to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.crc[1].to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; STATUS_SIZE]) -> Option<Self>
+    {
+        let word = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        if bytes[0..4] != STATUS_MAGIC || bytes[9] > 1
+        {
+            return None;
+        }
+
+        Some(Self
+        {
+            sequence: word(4),
+            state: LoaderState::from_u8(bytes[8])?,
+            active: bytes[9],
+            update_checksum: word(12),
+            len: [word(16), word(20)],
+            crc: [word(24), word(28)]
+        })
+    }
+}
+
+fn read_record<T>(address: usize, flasher: &T) -> Option<LoaderStatus>
+    where T: Flasher
+{
+    status_record::read(address, flasher).and_then(|bytes| LoaderStatus::from_bytes(&bytes))
+}
+
+/// Yields the most recent valid status record. Without any record the
+/// loader in slot 0 (as flashed in production) is active.
+pub fn read_status<T>(layout: &LoaderLayout, flasher: &T) -> LoaderStatus
+    where T: Flasher
+{
+    let records = [read_record(layout.status[0], flasher), read_record(layout.status[1], flasher)];
+    status_record::latest(records, |status| status.sequence)
+        .unwrap_or(LoaderStatus { state: LoaderState::Confirmed, active: 0, update_checksum: 0, len: [0; 2], crc: [0; 2], sequence: 0 })
+}
+
+fn write_status<T>(layout: &LoaderLayout, status: &mut LoaderStatus, flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    status.sequence = status.sequence.wrapping_add(1);
+    status_record::write(&layout.status, status.sequence, &status.to_bytes(), flasher, options)
+}
+
+fn slot_is_intact<T>(layout: &LoaderLayout, status: &LoaderStatus, slot: u8, flasher: &T) -> bool
+    where T: Flasher
+{
+    let slot = slot as usize;
+    status.len[slot] == 0 ||
+        crc::check_crc(layout.slots[slot], status.len[slot] as usize, status.crc[slot] as usize, flasher, &mut Crc32Nibble::new())
+}
+
+/// Called by stage0, yields the address of the loader to launch.
+pub fn select_loader<T>(layout: &LoaderLayout, flasher: &mut T, options: &WriteOptions) -> usize
+    where T: Flasher
+{
+    let mut status = read_status(layout, flasher);
+    let previous = 1 - status.active;
+
+    let revert = match status.state
+    {
+        // The new loader is started for the first time.
+        LoaderState::TestPending => !slot_is_intact(layout, &status, status.active, flasher),
+        // The new loader was started before, but never confirmed itself.
+        LoaderState::Testing => true,
+        LoaderState::Confirmed => !slot_is_intact(layout, &status, status.active, flasher) &&
+                                  status.len[previous as usize] != 0 &&
+                                  slot_is_intact(layout, &status, previous, flasher)
+    };
+
+    if revert
+    {
+        status.active = previous;
+        status.state = LoaderState::Confirmed;
+        // If this fails the same decision is made on the next reset.
+        let _ = write_status(layout, &mut status, flasher, options);
+    }
+    else if status.state == LoaderState::TestPending
+    {
+        status.state = LoaderState::Testing;
+        // Without the Testing mark a hanging loader would be retried forever,
+        // better keep the old loader.
+        if write_status(layout, &mut status, flasher, options).is_err()
+        {
+            return layout.slots[previous as usize];
+        }
+    }
+    layout.slots[status.active as usize]
+}
+
+/// Makes a newly installed loader permanent. Has to be called by the
+/// running loader, `muload_main` does this at start.
+pub fn confirm_loader<T>(layout: &LoaderLayout, flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    let mut status = read_status(layout, flasher);
+    if status.state != LoaderState::Testing
+    {
+        return Ok(());
+    }
+    status.state = LoaderState::Confirmed;
+    write_status(layout, &mut status, flasher, options)
+}
+
+/// Installs a loader update into the slot that is not running. Yields
+/// false if the update was not installed because it is already known.
+pub fn install_loader<T, C>(data: &update_info, update_info_address: usize, layout: &LoaderLayout, flasher: &mut T, engine: &mut C, options: &WriteOptions) -> Result<bool, InstallError>
+    where T: Flasher, C: Checksum
+{
+    let mut status = read_status(layout, flasher);
+    if status.state != LoaderState::Confirmed || status.update_checksum == data.checksum as u32
+    {
+        // A new loader is being tested or this update was tried before.
+        return Ok(false);
+    }
+
+    let inactive = 1 - status.active;
+    if data.target_adress != layout.slots[inactive as usize] || data.update_len > layout.slot_size
+    {
+        return Ok(false);
+    }
+
+    // Don't let stage0 fall back to the slot while it is rewritten.
+    status.len[inactive as usize] = 0;
+    status.update_checksum = data.checksum as u32;
+    write_status(layout, &mut status, flasher, options)?;
+
+    image_installer::install_binary(data, update_info_address, flasher, engine, options)?;
+
+    let mut slot_crc = Crc32Nibble::new();
+    if !crc::read_chunks(data.target_adress, data.update_len, flasher, |chunk| slot_crc.update(chunk))
+    {
+        return Err(InstallError::ReadFailed { address: data.target_adress });
+    }
+
+    status.len[inactive as usize] = data.update_len as u32;
+    status.crc[inactive as usize] = slot_crc.finish();
+    status.active = inactive;
+    status.state = LoaderState::TestPending;
+    write_status(layout, &mut status, flasher, options)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::crc::Crc32Table;
+    use crate::{update_info, WriteOptions};
+    use super::*;
+
+    const LAYOUT: LoaderLayout = LoaderLayout
+    {
+        slots: [0x1000, 0x2000],
+        slot_size: 0x1000,
+        status: [0x3000, 0x3400]
+    };
+
+    fn loader_update() -> update_info
+    {
+        update_info {
+            magic: *b"MUUPD",
+            struct_ver: 1,
+            update_start: 0x5000,
+            update_len: 128,
+            target_adress: 0x2000,
+            checksum: 0x2279EFE7
+        }
+    }
+
+    #[test]
+    fn new_loader_is_tested_and_confirmed()
+    {
+        let mut fl = FakeFlasher::new();
+        copy_to_flasher(&mut fl, 0x5000, &test_image());
+        let options = WriteOptions::default();
+
+        assert_eq!(select_loader(&LAYOUT, &mut fl, &options), 0x1000);
+        assert_eq!(install_loader(&loader_update(), 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &options), Ok(true));
+        assert!(fl.memory[0x2000..0x2080] == test_image());
+        assert_eq!(read_status(&LAYOUT, &fl).state, LoaderState::TestPending);
+
+        // The staged update is seen again by the new loader, but not installed twice.
+        assert_eq!(install_loader(&loader_update(), 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &options), Ok(false));
+
+        assert_eq!(select_loader(&LAYOUT, &mut fl, &options), 0x2000);
+        assert_eq!(confirm_loader(&LAYOUT, &mut fl, &options), Ok(()));
+        assert_eq!(select_loader(&LAYOUT, &mut fl, &options), 0x2000);
+        assert_eq!(read_status(&LAYOUT, &fl).state, LoaderState::Confirmed);
+    }
+
+    #[test]
+    fn unconfirmed_or_broken_loader_falls_back()
+    {
+        let mut fl = FakeFlasher::new();
+        copy_to_flasher(&mut fl, 0x5000, &test_image());
+        let options = WriteOptions::default();
+
+        assert_eq!(install_loader(&loader_update(), 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &options), Ok(true));
+        assert_eq!(select_loader(&LAYOUT, &mut fl, &options), 0x2000);
+        // The new loader hangs before confirming itself.
+        assert_eq!(select_loader(&LAYOUT, &mut fl, &options), 0x1000);
+        assert_eq!(select_loader(&LAYOUT, &mut fl, &options), 0x1000);
+
+        // Same for a loader that got corrupted after installation.
+        let mut fl = FakeFlasher::new();
+        copy_to_flasher(&mut fl, 0x5000, &test_image());
+        assert_eq!(install_loader(&loader_update(), 0, &LAYOUT, &mut fl, &mut Crc32Table::new(), &options), Ok(true));
+        fl.memory[0x2010] ^= 0xFF;
+        assert_eq!(select_loader(&LAYOUT, &mut fl, &options), 0x1000);
+    }
+}
@@ -0,0 +1,170 @@
+//! Streaming SHA-256 (FIPS 180-4) with a fixed RAM footprint of
+//! one 64 byte block plus the hash state.
+
+pub const SHA256_DIGEST_SIZE: usize = 32;
+const BLOCK_SIZE: usize = 64;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+];
+
+pub struct Sha256
+{
+    state: [u32; 8],
+    block: [u8; BLOCK_SIZE],
+    block_len: usize,
+    total_len: u64
+}
+
+impl Sha256
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            state: H0,
+            block: [0; BLOCK_SIZE],
+            block_len: 0,
+            total_len: 0
+        }
+    }
+
+    pub fn reset(&mut self)
+    {
+        *self = Self::new();
+    }
+
+    pub fn update(&mut self, data: &[u8])
+    {
+        self.total_len += data.len() as u64;
+
+        let mut data = data;
+        while !data.is_empty()
+        {
+            let num_bytes = core::cmp::min(BLOCK_SIZE - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + num_bytes].copy_from_slice(&data[..num_bytes]);
+            self.block_len += num_bytes;
+            data = &data[num_bytes..];
+
+            if self.block_len == BLOCK_SIZE
+            {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    /// Yields the digest, the hasher has to be reset before it is used again.
+    pub fn finish(&mut self) -> [u8; SHA256_DIGEST_SIZE]
+    {
+        let bit_len = self.total_len * 8;
+
+        self.block[self.block_len] = 0x80;
+        self.block_len += 1;
+        if self.block_len > BLOCK_SIZE - 8
+        {
+            self.block[self.block_len..].fill(0);
+            self.compress();
+            self.block_len = 0;
+        }
+        self.block[self.block_len..BLOCK_SIZE - 8].fill(0);
+        self.block[BLOCK_SIZE - 8..].copy_from_slice(&bit_len.to_be_bytes());
+        self.compress();
+
+        let mut digest = [0u8; SHA256_DIGEST_SIZE];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state.iter())
+        {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self)
+    {
+        let mut w = [0u32; 64];
+        for (index, chunk) in self.block.chunks(4).enumerate()
+        {
+            w[index] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..64
+        {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64
+        {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter())
+        {
+            *state = state.wrapping_add(*value);
+        }
+    }
+}
+
+impl Default for Sha256
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::Sha256;
+
+    #[test]
+    fn can_hash_test_vectors()
+    {
+        let mut sha = Sha256::new();
+        assert_eq!(sha.finish()[..4], [0xe3, 0xb0, 0xc4, 0x42]);
+
+        sha.reset();
+        sha.update(b"abc");
+        assert_eq!(sha.finish(), [0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23,
+                                  0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad]);
+    }
+
+    #[test]
+    fn can_hash_in_chunks()
+    {
+        let data = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        let mut sha = Sha256::new();
+        for chunk in data.chunks(7)
+        {
+            sha.update(chunk);
+        }
+        assert_eq!(sha.finish()[..8], [0x24, 0x8d, 0x6a, 0x61, 0xd2, 0x06, 0x38, 0xb8]);
+    }
+}
@@ -0,0 +1,733 @@
+//! SMP (mcumgr) over the serial transport, an alternative to the muload
+//! UART protocol that is understood by `mcumgr`, `smpmgr` and friends.
+//!
+//! Packets are sent as lines: a frame marker (0x06 0x09 for the first line
+//! of a packet, 0x04 0x14 for continuations), base64 text and a newline,
+//! at most 127 bytes per line. The decoded packet is
+//!
+//! ```text
+//! | len: u16 | op: u8 | flags: u8 | data_len: u16 | group: u16 | seq: u8 | id: u8 | CBOR map | crc: u16 |
+//! ```
+//!
+//! in big endian, len counts everything after itself, crc is the
+//! CRC-16/XMODEM of everything between len and crc. The low three bits of op
+//! are the operation (only read and write requests are answered), bits 3-4
+//! the SMP version, which the response echoes.
+//!
+//! Supported commands:
+//! * image upload (group 1, id 1): the image is written to the staging area.
+//!   Once all of it arrived (and it matches the SHA-256 the host sent along,
+//!   if any) an update_info for the target slot is written, with the CRC-32
+//!   calculated while receiving. The board id is taken from the
+//!   `mcuboot::TLV_BOARD_ID` of the image, images without one get none.
+//! * image state read (group 1, id 0): lists the MCUboot images in the
+//!   target slot (slot 0) and in the staging area (slot 1, pending).
+//! * reset (group 0, id 5): ends the receiver, i.e. the port resets.
+//!
+//! Everything else is answered with rc 8 (not supported).
+
+use super::{Flasher, StagingLayout, WriteOptions, crc, mcuboot};
+use super::cbor::{Decoder, Encoder};
+use super::crc::Checksum;
+use super::transport::Transport;
+use super::sha256::{Sha256, SHA256_DIGEST_SIZE};
+
+const FRAME_START: [u8; 2] = [0x06, 0x09];
+const FRAME_CONTINUATION: [u8; 2] = [0x04, 0x14];
+const FRAME_END: u8 = b'\n';
+pub const MAX_LINE_SIZE: usize = 127;
+/// Base64 characters per line we send, a multiple of 4 so each line
+/// decodes on its own.
+const LINE_DATA_SIZE: usize = 124;
+/// Largest packet (including length and CRC) the receiver accepts.
+pub const MAX_PACKET_SIZE: usize = 512;
+const MAX_RESPONSE_SIZE: usize = 256;
+const HEADER_SIZE: usize = 8;
+
+const OP_MASK: u8 = 0x07;
+const VERSION_MASK: u8 = 0x18;
+const OP_READ: u8 = 0;
+const OP_WRITE: u8 = 2;
+const GROUP_OS: u16 = 0;
+const GROUP_IMAGE: u16 = 1;
+const OS_RESET: u8 = 5;
+const IMAGE_STATE: u8 = 0;
+const IMAGE_UPLOAD: u8 = 1;
+
+pub const RC_OK: u64 = 0;
+pub const RC_UNKNOWN: u64 = 1;
+pub const RC_INVALID: u64 = 3;
+pub const RC_NOT_SUPPORTED: u64 = 8;
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_value(symbol: u8) -> Option<u32>
+{
+    match symbol
+    {
+        b'A'..=b'Z' => Some((symbol - b'A') as u32),
+        b'a'..=b'z' => Some((symbol - b'a' + 26) as u32),
+        b'0'..=b'9' => Some((symbol - b'0' + 52) as u32),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None
+    }
+}
+
+/// Decodes padded base64 into `output`, yields the number of bytes written.
+fn decode_base64(input: &[u8], output: &mut [u8]) -> Option<usize>
+{
+    if !input.len().is_multiple_of(4)
+    {
+        return None;
+    }
+
+    let mut len = 0;
+    for quad in input.chunks(4)
+    {
+        let padding = quad.iter().rev().take_while(|symbol| **symbol == b'=').count();
+        if padding > 2
+        {
+            return None;
+        }
+        let mut value = 0u32;
+        for symbol in quad[..4 - padding].iter()
+        {
+            value = value << 6 | base64_value(*symbol)?;
+        }
+        value <<= 6 * padding as u32;
+
+        let bytes = value.to_be_bytes();
+        let count = 3 - padding;
+        output.get_mut(len..len + count)?.copy_from_slice(&bytes[1..1 + count]);
+        len += count;
+    }
+    Some(len)
+}
+
+/// Passes the base64 encoding of `input` to `output`, symbol by symbol.
+fn encode_base64<F>(input: &[u8], mut output: F)
+    where F: FnMut(u8)
+{
+    for triple in input.chunks(3)
+    {
+        let mut bytes = [0u8; 3];
+        bytes[..triple.len()].copy_from_slice(triple);
+        let value = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for index in 0..4
+        {
+            if index <= triple.len()
+            {
+                output(BASE64_ALPHABET[(value >> (18 - 6 * index) & 0x3F) as usize]);
+            }
+            else
+            {
+                output(b'=');
+            }
+        }
+    }
+}
+
+/// Passes the framed (length, CRC, base64, split into lines) `body` to
+/// `output`.
+fn encode_frame<F>(body: &[u8], mut output: F)
+    where F: FnMut(u8)
+{
+    // Raw bytes per line, such that each line is padded on its own.
+    const LINE_BYTES: usize = LINE_DATA_SIZE / 4 * 3;
+    let mut packet = [0u8; MAX_RESPONSE_SIZE + 4];
+    let len = body.len() + 2;
+    packet[..2].copy_from_slice(&(len as u16).to_be_bytes());
+    packet[2..2 + body.len()].copy_from_slice(body);
+    packet[2 + body.len()..2 + len].copy_from_slice(&crc::crc16_xmodem(0, body).to_be_bytes());
+
+    for (index, line) in packet[..2 + len].chunks(LINE_BYTES).enumerate()
+    {
+        let marker = if index == 0 { FRAME_START } else { FRAME_CONTINUATION };
+        output(marker[0]);
+        output(marker[1]);
+        encode_base64(line, &mut output);
+        output(FRAME_END);
+    }
+}
+
+/// Reassembles a packet from its lines.
+struct PacketBuffer
+{
+    data: [u8; MAX_PACKET_SIZE],
+    len: usize
+}
+
+impl PacketBuffer
+{
+    fn new() -> Self
+    {
+        Self { data: [0; MAX_PACKET_SIZE], len: 0 }
+    }
+
+    /// Adds the base64 text of a line. Yields the body of the packet (without
+    /// length and CRC) once the packet is complete and its CRC matches.
+    fn add_line(&mut self, first: bool, line: &[u8]) -> Result<Option<&[u8]>, ()>
+    {
+        if first
+        {
+            self.len = 0;
+        }
+        else if self.len == 0
+        {
+            // A continuation without a start
+            return Err(());
+        }
+
+        self.len += decode_base64(line, &mut self.data[self.len..]).ok_or(())?;
+        if self.len < 2
+        {
+            return Ok(None);
+        }
+
+        let packet_len = u16::from_be_bytes([self.data[0], self.data[1]]) as usize;
+        if packet_len < 2 || 2 + packet_len > MAX_PACKET_SIZE || self.len > 2 + packet_len
+        {
+            return Err(());
+        }
+        if self.len < 2 + packet_len
+        {
+            return Ok(None);
+        }
+
+        self.len = 0;
+        let packet = &self.data[2..2 + packet_len];
+        if crc::crc16_xmodem(0, packet) != 0
+        {
+            return Err(());
+        }
+        Ok(Some(&packet[..packet_len - 2]))
+    }
+}
+
+struct Upload
+{
+    len: usize,
+    offset: usize,
+    sha: Option<[u8; SHA256_DIGEST_SIZE]>,
+    engine: Sha256
+}
+
+/// Appends the decimal representation of `value` to `buf` at `len`.
+fn write_decimal(buf: &mut [u8], len: &mut usize, value: u32)
+{
+    let mut digits = [0u8; 10];
+    let mut count = 0;
+    let mut rest = value;
+    loop
+    {
+        digits[count] = b'0' + (rest % 10) as u8;
+        count += 1;
+        rest /= 10;
+        if rest == 0
+        {
+            break;
+        }
+    }
+    for digit in digits[..count].iter().rev()
+    {
+        buf[*len] = *digit;
+        *len += 1;
+    }
+}
+
+pub struct SmpReceiver<'a, T: Flasher, U: Transport, C: Checksum>
+{
+    flasher: &'a mut T,
+    uart: &'a mut U,
+    checksum: &'a mut C,
+    layout: StagingLayout,
+    write_options: WriteOptions,
+    update_info_address: usize,
+    upload: Option<Upload>,
+    done: bool
+}
+
+impl <'a, T: Flasher, U: Transport, C: Checksum> SmpReceiver<'a, T, U, C>
+{
+    pub fn new(flasher: &'a mut T, uart: &'a mut U, checksum: &'a mut C, layout: StagingLayout) -> Self
+    {
+        Self
+        {
+            flasher,
+            uart,
+            checksum,
+            layout,
+            write_options: WriteOptions::default(),
+            update_info_address: 0,
+            upload: None,
+            done: false
+        }
+    }
+
+    pub fn with_write_options(mut self, write_options: WriteOptions) -> Self
+    {
+        self.write_options = write_options;
+        self
+    }
+
+    /// Serves requests until the host asks for a reset.
+    pub fn execute(mut self, update_info_address: usize)
+    {
+        self.update_info_address = update_info_address;
+        let mut packet = PacketBuffer::new();
+        while !self.done
+        {
+            let mut line = [0u8; MAX_LINE_SIZE];
+            let (first, line_len) = match self.receive_line(&mut line)
+            {
+                Some(line) => line,
+                None => continue
+            };
+
+            match packet.add_line(first, &line[..line_len])
+            {
+                Ok(Some(body)) => self.dispatch_packet(body),
+                Ok(None) => {},
+                // SMP has no NAK, the host times out and retries.
+                Err(()) => packet.len = 0
+            }
+        }
+    }
+
+    fn get_byte(&mut self) -> u8
+    {
+        loop
+        {
+            if let Some(byte) = self.uart.read_byte()
+            {
+                return byte;
+            }
+        }
+    }
+
+    /// Receives the base64 text of a line into `line`. Yields whether the
+    /// line starts a packet and the length of the text, None if the line is
+    /// malformed (e.g. console output of the host).
+    fn receive_line(&mut self, line: &mut [u8; MAX_LINE_SIZE]) -> Option<(bool, usize)>
+    {
+        let first = loop
+        {
+            match self.get_byte()
+            {
+                byte if byte == FRAME_START[0] => break true,
+                byte if byte == FRAME_CONTINUATION[0] => break false,
+                _ => {}
+            }
+        };
+        let expected = if first { FRAME_START[1] } else { FRAME_CONTINUATION[1] };
+        if self.get_byte() != expected
+        {
+            return None;
+        }
+
+        let mut len = 0;
+        loop
+        {
+            let byte = self.get_byte();
+            if byte == FRAME_END
+            {
+                return Some((first, len));
+            }
+            // Lines are limited to 127 bytes, including marker and newline.
+            if len == MAX_LINE_SIZE - 3
+            {
+                return None;
+            }
+            line[len] = byte;
+            len += 1;
+        }
+    }
+
+    fn dispatch_packet(&mut self, body: &[u8])
+    {
+        if body.len() < HEADER_SIZE
+        {
+            return;
+        }
+
+        let op = body[0] & OP_MASK;
+        let version = body[0] & VERSION_MASK;
+        if op != OP_READ && op != OP_WRITE
+        {
+            return;
+        }
+        let data_len = u16::from_be_bytes([body[2], body[3]]) as usize;
+        let group = u16::from_be_bytes([body[4], body[5]]);
+        let id = body[7];
+        let payload = match body.get(HEADER_SIZE..HEADER_SIZE + data_len)
+        {
+            Some(payload) => payload,
+            None => return
+        };
+
+        let mut response = [0u8; MAX_RESPONSE_SIZE];
+        let mut encoder = Encoder::new(&mut response[HEADER_SIZE..]);
+        match (op, group, id)
+        {
+            (OP_WRITE, GROUP_IMAGE, IMAGE_UPLOAD) => self.upload(payload, &mut encoder),
+            (OP_READ, GROUP_IMAGE, IMAGE_STATE) => self.image_state(&mut encoder),
+            (OP_WRITE, GROUP_OS, OS_RESET) =>
+            {
+                self.done = true;
+                encoder.map(0);
+            }
+            _ => { encoder.map(1).text("rc").unsigned(RC_NOT_SUPPORTED); }
+        }
+
+        let response_len = match encoder.finish()
+        {
+            Some(len) => len,
+            None => return
+        };
+        response[0] = version | (op + 1);
+        response[1] = body[1];
+        response[2..4].copy_from_slice(&(response_len as u16).to_be_bytes());
+        response[4..8].copy_from_slice(&body[4..8]);
+
+        let uart = &mut *self.uart;
+        encode_frame(&response[..HEADER_SIZE + response_len], |byte| uart.write_byte(byte));
+        uart.flush();
+    }
+
+    fn upload(&mut self, payload: &[u8], response: &mut Encoder)
+    {
+        let (mut len, mut offset, mut data, mut sha) = (None, None, None, None);
+        let mut decoder = Decoder::new(payload);
+        let entries = decoder.map().unwrap_or(0);
+        for _ in 0..entries
+        {
+            let parsed = match decoder.text()
+            {
+                Some(b"len") => decoder.unsigned().map(|value| len = Some(value as usize)),
+                Some(b"off") => decoder.unsigned().map(|value| offset = Some(value as usize)),
+                Some(b"data") => decoder.bytes().map(|value| data = Some(value)),
+                Some(b"sha") => decoder.bytes().map(|value| sha = Some(value)),
+                Some(_) => decoder.skip(),
+                None => None
+            };
+            if parsed.is_none()
+            {
+                response.map(1).text("rc").unsigned(RC_INVALID);
+                return;
+            }
+        }
+
+        let rc = match (offset, data)
+        {
+            (Some(offset), Some(data)) => self.write_upload(offset, data, len, sha),
+            _ => RC_INVALID
+        };
+
+        match (rc, self.upload.as_ref())
+        {
+            (RC_OK, Some(upload)) => { response.map(2).text("rc").unsigned(RC_OK).text("off").unsigned(upload.offset as u64); },
+            _ => { response.map(1).text("rc").unsigned(rc); }
+        }
+    }
+
+    fn write_upload(&mut self, offset: usize, data: &[u8], len: Option<usize>, sha: Option<&[u8]>) -> u64
+    {
+        if offset == 0
+        {
+            // The first chunk (re)starts the upload.
+            let len = match len
+            {
+                Some(len) if len > 0 && len <= self.layout.slot_size => len,
+                _ => return RC_INVALID
+            };
+            let mut expected_sha = [0u8; SHA256_DIGEST_SIZE];
+            let sha = match sha
+            {
+                Some(sha) if sha.len() == SHA256_DIGEST_SIZE => { expected_sha.copy_from_slice(sha); Some(expected_sha) },
+                _ => None
+            };
+            self.checksum.reset();
+            self.upload = Some(Upload { len, offset: 0, sha, engine: Sha256::new() });
+        }
+
+        let upload = match self.upload.as_mut()
+        {
+            Some(upload) => upload,
+            None => return RC_INVALID
+        };
+        if offset != upload.offset
+        {
+            // The host resumes at the offset we answer with.
+            return RC_OK;
+        }
+        if offset + data.len() > upload.len
+        {
+            return RC_INVALID;
+        }
+
+        if super::write_chunk(self.flasher, self.layout.staging + offset, data, &self.write_options).is_err()
+        {
+            return RC_UNKNOWN;
+        }
+        self.checksum.update(data);
+        upload.engine.update(data);
+        upload.offset += data.len();
+
+        if upload.offset < upload.len
+        {
+            return RC_OK;
+        }
+
+        self.flasher.flush();
+        if upload.sha.map(|sha| sha != upload.engine.finish()).unwrap_or(false)
+        {
+            self.upload = None;
+            return RC_INVALID;
+        }
+        let len = upload.len;
+        let board_id = mcuboot::board_id(self.layout.staging, self.flasher);
+        if self.layout.write_update_info(self.update_info_address, len, self.checksum.finish(), board_id, self.flasher).is_err()
+        {
+            return RC_UNKNOWN;
+        }
+        RC_OK
+    }
+
+    fn image_state(&mut self, response: &mut Encoder)
+    {
+        let images = [(0, self.layout.target), (1, self.layout.staging)];
+        let mut found = [None; 2];
+        for (entry, (slot, address)) in found.iter_mut().zip(images.iter())
+        {
+            *entry = mcuboot::check_image(*address, self.layout.slot_size, self.flasher).map(|image| (*slot, image));
+        }
+
+        response.map(2).text("images").array(found.iter().flatten().count());
+        for (slot, image) in found.iter().flatten()
+        {
+            let version = image.header.version;
+            let mut text = [0u8; 48];
+            let mut len = 0;
+            for (index, part) in [version.major as u32, version.minor as u32, version.revision as u32].iter().enumerate()
+            {
+                if index > 0
+                {
+                    text[len] = b'.';
+                    len += 1;
+                }
+                write_decimal(&mut text, &mut len, *part);
+            }
+            if version.build_num != 0
+            {
+                text[len] = b'.';
+                len += 1;
+                write_decimal(&mut text, &mut len, version.build_num);
+            }
+
+            let active = *slot == 0;
+            response.map(8)
+                .text("slot").unsigned(*slot)
+                .text("version").text(core::str::from_utf8(&text[..len]).unwrap_or(""))
+                .text("hash").bytes(&image.hash)
+                .text("bootable").bool(true)
+                .text("pending").bool(!active)
+                .text("confirmed").bool(active)
+                .text("active").bool(active)
+                .text("permanent").bool(false);
+        }
+        response.text("splitStatus").unsigned(0);
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::cbor::{Decoder, Encoder};
+    use crate::crc::Crc32Table;
+    use crate::sha256::Sha256;
+    use super::*;
+
+    const LAYOUT: StagingLayout = StagingLayout { staging: 0x2000, target: 0x4000, slot_size: 0x1000 };
+
+    fn send_request(uart: &mut FakeUart, op: u8, group: u16, id: u8, seq: u8, payload: &[u8])
+    {
+        let mut body = [0u8; 400];
+        body[0] = op;
+        body[2..4].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        body[4..6].copy_from_slice(&group.to_be_bytes());
+        body[6] = seq;
+        body[7] = id;
+        body[8..8 + payload.len()].copy_from_slice(payload);
+
+        let mut frame = [0u8; 512];
+        let mut len = 0;
+        encode_frame(&body[..8 + payload.len()], |byte| { frame[len] = byte; len += 1; });
+        copy_to_uart(uart, &frame[..len]);
+    }
+
+    /// Decodes the responses sent by the receiver, yields the body of the
+    /// `index`th response.
+    fn response(uart: &FakeUart, index: usize, body: &mut [u8]) -> usize
+    {
+        let mut packet = PacketBuffer::new();
+        let mut count = 0;
+        for line in uart.out_buf[..uart.write_index].split(|byte| *byte == FRAME_END)
+        {
+            if line.len() < 2
+            {
+                continue;
+            }
+            if let Ok(Some(data)) = packet.add_line(line[..2] == FRAME_START, &line[2..])
+            {
+                if count == index
+                {
+                    body[..data.len()].copy_from_slice(data);
+                    return data.len();
+                }
+                count += 1;
+            }
+        }
+        panic!("no response {}", index);
+    }
+
+    #[test]
+    fn base64_round_trip()
+    {
+        let mut encoded = [0u8; 16];
+        let mut len = 0;
+        encode_base64(b"muload", |symbol| { encoded[len] = symbol; len += 1; });
+        assert_eq!(&encoded[..len], b"bXVsb2Fk");
+        len = 0;
+        encode_base64(b"mu", |symbol| { encoded[len] = symbol; len += 1; });
+        assert_eq!(&encoded[..len], b"bXU=");
+
+        let mut decoded = [0u8; 8];
+        assert_eq!(decode_base64(b"bXVsb2Fk", &mut decoded), Some(6));
+        assert_eq!(&decoded[..6], b"muload");
+        assert_eq!(decode_base64(b"bXU=", &mut decoded), Some(2));
+        assert_eq!(decode_base64(b"bX!=", &mut decoded), None);
+    }
+
+    #[test]
+    fn can_upload_and_list_image()
+    {
+        let mut fl = FakeFlasher::new();
+        let mut image = [0u8; 0xDE];
+        let len = write_mcuboot_image_for_board(&mut fl, 0x6000, (1, 2, 3), Some(TEST_BOARD_ID));
+        image.copy_from_slice(&fl.memory[0x6000..0x6000 + len]);
+        let mut sha = Sha256::new();
+        sha.update(&image);
+
+        let mut uart = FakeUart::new();
+        let mut payload = [0u8; 200];
+        let mut encoder = Encoder::new(&mut payload);
+        encoder.map(5).text("image").unsigned(0).text("len").unsigned(len as u64).text("off").unsigned(0)
+            .text("sha").bytes(&sha.finish()).text("data").bytes(&image[..100]);
+        let payload_len = encoder.finish().unwrap();
+        send_request(&mut uart, OP_WRITE, GROUP_IMAGE, IMAGE_UPLOAD, 1, &payload[..payload_len]);
+
+        let mut encoder = Encoder::new(&mut payload);
+        encoder.map(2).text("off").unsigned(100).text("data").bytes(&image[100..]);
+        let payload_len = encoder.finish().unwrap();
+        send_request(&mut uart, OP_WRITE, GROUP_IMAGE, IMAGE_UPLOAD, 2, &payload[..payload_len]);
+        send_request(&mut uart, OP_READ, GROUP_IMAGE, IMAGE_STATE, 3, &[0xA0]);
+        send_request(&mut uart, OP_WRITE, GROUP_OS, OS_RESET, 4, &[0xA0]);
+
+        let mut crc = Crc32Table::new();
+        SmpReceiver::new(&mut fl, &mut uart, &mut crc, LAYOUT).execute(0x1000);
+
+        assert!(fl.memory[0x2000..0x2000 + len] == image);
+        assert!(fl.memory[0x1000..0x1005] == *b"MUUPD");
+        let board_id = crate::tlv::update_info_metadata(0x1000, &fl).unwrap().find(crate::tlv::TAG_BOARD_ID).unwrap();
+        assert_eq!(board_id.read_u32(&fl), Some(TEST_BOARD_ID));
+
+        let mut body = [0u8; 256];
+        let body_len = response(&uart, 1, &mut body);
+        assert_eq!(body[0..8], [OP_WRITE + 1, 0, 0, (body_len - 8) as u8, 0, 1, 2, IMAGE_UPLOAD]);
+        let mut decoder = Decoder::new(&body[8..body_len]);
+        assert_eq!(decoder.map(), Some(2));
+        assert_eq!((decoder.text(), decoder.unsigned()), (Some(&b"rc"[..]), Some(RC_OK)));
+        assert_eq!((decoder.text(), decoder.unsigned()), (Some(&b"off"[..]), Some(len as u64)));
+
+        let body_len = response(&uart, 2, &mut body);
+        let mut decoder = Decoder::new(&body[8..body_len]);
+        assert_eq!(decoder.map(), Some(2));
+        assert_eq!((decoder.text(), decoder.array()), (Some(&b"images"[..]), Some(1)));
+        assert_eq!(decoder.map(), Some(8));
+        assert_eq!((decoder.text(), decoder.unsigned()), (Some(&b"slot"[..]), Some(1)));
+        assert_eq!((decoder.text(), decoder.text()), (Some(&b"version"[..]), Some(&b"1.2.3"[..])));
+
+        // The reset was answered, then the receiver returned.
+        assert_eq!(response(&uart, 3, &mut body), 8 + 1);
+    }
+
+    #[test]
+    fn upload_with_wrong_sha_is_not_staged()
+    {
+        let mut uart = FakeUart::new();
+        let mut payload = [0u8; 200];
+        let mut encoder = Encoder::new(&mut payload);
+        encoder.map(4).text("len").unsigned(128).text("off").unsigned(0).text("sha").bytes(&[0x55; 32]).text("data").bytes(&test_image());
+        let payload_len = encoder.finish().unwrap();
+        send_request(&mut uart, OP_WRITE, GROUP_IMAGE, IMAGE_UPLOAD, 1, &payload[..payload_len]);
+        // Unknown group
+        send_request(&mut uart, OP_READ, 0x40, 0, 2, &[0xA0]);
+        send_request(&mut uart, OP_WRITE, GROUP_OS, OS_RESET, 3, &[0xA0]);
+
+        let mut fl = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+        SmpReceiver::new(&mut fl, &mut uart, &mut crc, LAYOUT).execute(0x1000);
+        assert!(fl.memory[0x1000..0x1005] != *b"MUUPD");
+
+        let mut body = [0u8; 256];
+        let body_len = response(&uart, 0, &mut body);
+        assert_eq!(body[8..body_len], [0xA1, 0x62, b'r', b'c', RC_INVALID as u8]);
+        let body_len = response(&uart, 1, &mut body);
+        assert_eq!(body[8..body_len], [0xA1, 0x62, b'r', b'c', RC_NOT_SUPPORTED as u8]);
+    }
+
+    #[test]
+    fn answers_only_requests_and_echoes_the_version()
+    {
+        let mut uart = FakeUart::new();
+        // A write response and an undefined op are ignored ...
+        send_request(&mut uart, OP_WRITE + 1, GROUP_OS, OS_RESET, 1, &[0xA0]);
+        send_request(&mut uart, OP_MASK, GROUP_OS, OS_RESET, 2, &[0xA0]);
+        // ... an SMPv2 request is not.
+        send_request(&mut uart, 0x08 | OP_WRITE, GROUP_OS, OS_RESET, 3, &[0xA0]);
+
+        let mut fl = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+        SmpReceiver::new(&mut fl, &mut uart, &mut crc, LAYOUT).execute(0x1000);
+
+        let mut body = [0u8; 256];
+        assert_eq!(response(&uart, 0, &mut body), 8 + 1);
+        assert_eq!(body[0..8], [0x08 | (OP_WRITE + 1), 0, 0, 1, 0, 0, 3, OS_RESET]);
+    }
+
+    #[test]
+    fn upload_without_board_id_stages_none()
+    {
+        let mut fl = FakeFlasher::new();
+        let len = write_mcuboot_image(&mut fl, 0x6000, (1, 2, 3));
+        let mut image = [0u8; 0xD6];
+        image.copy_from_slice(&fl.memory[0x6000..0x6000 + len]);
+
+        let mut uart = FakeUart::new();
+        let mut payload = [0u8; 300];
+        let mut encoder = Encoder::new(&mut payload);
+        encoder.map(3).text("len").unsigned(len as u64).text("off").unsigned(0).text("data").bytes(&image);
+        let payload_len = encoder.finish().unwrap();
+        send_request(&mut uart, OP_WRITE, GROUP_IMAGE, IMAGE_UPLOAD, 1, &payload[..payload_len]);
+        send_request(&mut uart, OP_WRITE, GROUP_OS, OS_RESET, 2, &[0xA0]);
+
+        let mut crc = Crc32Table::new();
+        SmpReceiver::new(&mut fl, &mut uart, &mut crc, LAYOUT).execute(0x1000);
+
+        // The update can't pass the board check of a loader that has an id.
+        assert!(fl.memory[0x1000..0x1005] == *b"MUUPD");
+        assert!(crate::tlv::update_info_metadata(0x1000, &fl).is_none());
+    }
+}
@@ -0,0 +1,64 @@
+//! Power loss safe status records.
+//!
+//! A status is kept in two records, which are written alternately, such that
+//! a power loss while writing one never loses both. Each record is followed by
+//! its CRC-32, a torn write yields an invalid record. By convention the record
+//! starts with a magic (4 bytes) followed by a sequence number (u32 LE), the
+//! record with the higher sequence number is the current one.
+
+use super::{Flasher, InstallError, WriteOptions};
+use super::crc::{Checksum, Crc32Nibble};
+
+/// Upper bound for the size of a record, excluding its CRC.
+pub const MAX_RECORD_SIZE: usize = 60;
+
+/// Reads the record at `address`, yields None if it is torn or was never written.
+pub fn read<T, const N: usize>(address: usize, flasher: &T) -> Option<[u8; N]>
+    where T: Flasher
+{
+    let mut bytes = [0u8; N];
+    let mut crc = [0u8; 4];
+    match (flasher.read(address, &mut bytes), flasher.read(address + N, &mut crc))
+    {
+        (Ok(len), Ok(4)) if len == N => {},
+        _ => return None
+    }
+
+    let mut engine = Crc32Nibble::new();
+    engine.update(&bytes);
+    if engine.finish() != u32::from_le_bytes(crc)
+    {
+        return None;
+    }
+    Some(bytes)
+}
+
+/// Writes `bytes` to the record selected by `sequence`.
+pub fn write<T, const N: usize>(addresses: &[usize; 2], sequence: u32, bytes: &[u8; N], flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    let mut engine = Crc32Nibble::new();
+    engine.update(bytes);
+
+    // Record and CRC are written in one go, so a buffering flasher
+    // commits them together.
+    let mut record = [0u8; MAX_RECORD_SIZE + 4];
+    record[..N].copy_from_slice(bytes);
+    record[N..N + 4].copy_from_slice(&engine.finish().to_le_bytes());
+
+    let address = addresses[(sequence % 2) as usize];
+    super::write_chunk(flasher, address, &record[..N + 4], options).map_err(|_| InstallError::WriteFailed { address })?;
+    flasher.flush();
+    Ok(())
+}
+
+/// Picks the current one of two decoded records.
+pub fn latest<S>(records: [Option<S>; 2], sequence: impl Fn(&S) -> u32) -> Option<S>
+{
+    match records
+    {
+        [Some(a), Some(b)] => if sequence(&a) >= sequence(&b) { Some(a) } else { Some(b) },
+        [Some(a), None] => Some(a),
+        [None, b] => b
+    }
+}
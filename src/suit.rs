@@ -0,0 +1,681 @@
+//! SUIT manifests (RFC 9124, draft-ietf-suit-manifest).
+//!
+//! A SUIT update is an update_info with encoding `UpdateEncoding::Suit`.
+//! Its staging area holds a SUIT envelope with integrated payloads: the
+//! authentication wrapper and the manifest come first, the payloads (keyed
+//! by their URI, e.g. "#app") follow as byte strings. Only the part before
+//! the payloads has to fit into `MAX_ENVELOPE_SIZE`, which deterministic
+//! encoding guarantees, as integer keys sort before text keys.
+//!
+//! Processing maps SUIT onto the existing checks:
+//! * The digest of the manifest is checked (SHA-256, computed over the
+//!   bstr-wrapped manifest). Its COSE_Sign1 signature is verified by the
+//!   port through `SecurityCounter::verify_manifest`.
+//! * The sequence number takes the role of the image version, it has to be
+//!   at least the minimum version of the security counter and raises it
+//!   after a successful installation.
+//! * Components are identified by a single byte string, the name of an
+//!   application or data partition in the partition table.
+//! * Vendor and class id conditions are checked against `SuitIdentity`.
+//!
+//! The shared and install sequences are run twice: first against the staged
+//! payloads (nothing is written), then for real, followed by the validate
+//! sequence. Supported commands: vendor/class identifier and image match
+//! conditions, set component index, override parameters, fetch (copies an
+//! integrated payload into its component) and invoke, which is a no-op as
+//! the application is launched through bin_info. Everything else rejects
+//! the manifest.
+
+use super::{update_info, Flasher, InstallError, SecurityCounter, WriteOptions, crc, image_installer};
+use super::cbor::{self, Decoder, Encoder, Item};
+use super::partition_table::{self, PartitionEntry, PartitionTable};
+use super::sha256::{Sha256, SHA256_DIGEST_SIZE};
+
+/// Upper bound for the envelope without its integrated payloads.
+pub const MAX_ENVELOPE_SIZE: usize = 1024;
+pub const MAX_COMPONENTS: usize = 4;
+const MAX_PAYLOADS: usize = 4;
+const MAX_SIG_STRUCTURE_SIZE: usize = 160;
+
+const TAG_ENVELOPE: u64 = 107;
+const TAG_COSE_SIGN1: u64 = 18;
+const COSE_ALG_SHA256: i64 = -16;
+
+const ENVELOPE_AUTHENTICATION: u64 = 2;
+const ENVELOPE_MANIFEST: u64 = 3;
+
+const MANIFEST_VERSION: u64 = 1;
+const MANIFEST_SEQUENCE_NUMBER: u64 = 2;
+const MANIFEST_COMMON: u64 = 3;
+const MANIFEST_VALIDATE: u64 = 7;
+const MANIFEST_INSTALL: u64 = 20;
+
+const COMMON_COMPONENTS: u64 = 2;
+const COMMON_SHARED_SEQUENCE: u64 = 4;
+
+const CONDITION_VENDOR_ID: u64 = 1;
+const CONDITION_CLASS_ID: u64 = 2;
+const CONDITION_IMAGE_MATCH: u64 = 3;
+const DIRECTIVE_SET_COMPONENT_INDEX: u64 = 12;
+const DIRECTIVE_OVERRIDE_PARAMETERS: u64 = 20;
+const DIRECTIVE_FETCH: u64 = 21;
+const DIRECTIVE_INVOKE: u64 = 23;
+
+const PARAMETER_VENDOR_ID: u64 = 1;
+const PARAMETER_CLASS_ID: u64 = 2;
+const PARAMETER_IMAGE_DIGEST: u64 = 3;
+const PARAMETER_IMAGE_SIZE: u64 = 14;
+const PARAMETER_URI: u64 = 21;
+
+/// Identifies the device for the vendor and class id conditions.
+#[derive(Debug, Clone, Copy)]
+pub struct SuitIdentity
+{
+    pub vendor_id: [u8; 16],
+    pub class_id: [u8; 16]
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Payload<'e>
+{
+    uri: &'e [u8],
+    address: usize,
+    len: usize
+}
+
+struct Envelope<'e>
+{
+    authentication: &'e [u8],
+    /// The manifest including its bstr header, which the digest covers.
+    manifest: &'e [u8],
+    payloads: [Option<Payload<'e>>; MAX_PAYLOADS]
+}
+
+/// Decodes the envelope at the start of `data`, which is located at
+/// `address` in a staging area of `len` bytes.
+fn decode_envelope(data: &[u8], address: usize, len: usize) -> Option<Envelope<'_>>
+{
+    let mut decoder = Decoder::new(data);
+    let mut item = decoder.item()?;
+    if item == Item::Tag(TAG_ENVELOPE)
+    {
+        item = decoder.item()?;
+    }
+    let entries = match item
+    {
+        Item::Map(entries) => entries,
+        _ => return None
+    };
+
+    let mut envelope = Envelope { authentication: &[], manifest: &[], payloads: [None; MAX_PAYLOADS] };
+    let mut payload_count = 0;
+    for _ in 0..entries
+    {
+        match decoder.item()?
+        {
+            Item::Unsigned(ENVELOPE_AUTHENTICATION) => envelope.authentication = decoder.bytes()?,
+            Item::Unsigned(ENVELOPE_MANIFEST) =>
+            {
+                let start = decoder.position();
+                decoder.bytes()?;
+                envelope.manifest = &data[start..decoder.position()];
+            }
+            Item::Text(uri) =>
+            {
+                let payload_len = decoder.bytes_header()?;
+                let payload_address = address.checked_add(decoder.position())?;
+                let payload_end = payload_len.checked_add(payload_address)?;
+                if payload_count == MAX_PAYLOADS || payload_end > address.checked_add(len)?
+                {
+                    return None;
+                }
+                envelope.payloads[payload_count] = Some(Payload { uri, address: payload_address, len: payload_len });
+                payload_count += 1;
+                decoder.advance(payload_len);
+            }
+            _ => decoder.skip()?
+        }
+    }
+    Some(envelope)
+}
+
+/// Decodes a SUIT_Digest, only SHA-256 is supported.
+fn decode_digest(data: &[u8]) -> Option<&[u8]>
+{
+    let mut decoder = Decoder::new(data);
+    if decoder.array()? < 2 || decoder.integer()? != COSE_ALG_SHA256
+    {
+        return None;
+    }
+    decoder.bytes().filter(|digest| digest.len() == SHA256_DIGEST_SIZE)
+}
+
+/// Verifies a COSE_Sign1 authentication block, whose (detached) payload
+/// is the digest of the manifest.
+fn verify_block<S>(block: &[u8], digest: &[u8], counter: &S) -> bool
+    where S: SecurityCounter
+{
+    let mut decoder = Decoder::new(block);
+    let mut item = decoder.item();
+    if item == Some(Item::Tag(TAG_COSE_SIGN1))
+    {
+        item = decoder.item();
+    }
+    if item != Some(Item::Array(4))
+    {
+        return false;
+    }
+
+    let protected = decoder.bytes();
+    let unprotected = decoder.skip();
+    let payload = match decoder.item()
+    {
+        Some(Item::Simple(cbor::SIMPLE_NULL)) => Some(digest),
+        Some(Item::Bytes(payload)) if payload == digest => Some(digest),
+        _ => None
+    };
+    let (protected, signature) = match (protected, unprotected, payload, decoder.bytes())
+    {
+        (Some(protected), Some(()), Some(_), Some(signature)) => (protected, signature),
+        _ => return false
+    };
+
+    // Sig_structure = ["Signature1", protected, external_aad, payload]
+    let mut buf = [0u8; MAX_SIG_STRUCTURE_SIZE];
+    let mut encoder = Encoder::new(&mut buf);
+    encoder.array(4).text("Signature1").bytes(protected).bytes(&[]).bytes(digest);
+    match encoder.finish()
+    {
+        Some(len) => counter.verify_manifest(&buf[..len], signature),
+        None => false
+    }
+}
+
+fn authenticate<S>(envelope: &Envelope, counter: &S) -> bool
+    where S: SecurityCounter
+{
+    let mut decoder = Decoder::new(envelope.authentication);
+    let blocks = match decoder.array()
+    {
+        Some(len) if len >= 2 => len - 1,
+        _ => return false
+    };
+    let digest = match decoder.bytes()
+    {
+        Some(digest) => digest,
+        None => return false
+    };
+
+    let mut sha = Sha256::new();
+    sha.update(envelope.manifest);
+    if decode_digest(digest) != Some(&sha.finish()[..])
+    {
+        return false;
+    }
+
+    for _ in 0..blocks
+    {
+        match decoder.bytes()
+        {
+            Some(block) if verify_block(block, digest, counter) => return true,
+            Some(_) => {},
+            None => return false
+        }
+    }
+    false
+}
+
+struct Manifest<'e>
+{
+    sequence_number: u64,
+    components: [Option<PartitionEntry>; MAX_COMPONENTS],
+    component_count: usize,
+    shared: Option<&'e [u8]>,
+    install: Option<&'e [u8]>,
+    validate: Option<&'e [u8]>
+}
+
+fn parse_components<'e, T>(data: &'e [u8], table: &PartitionTable, flasher: &T, manifest: &mut Manifest<'e>) -> Option<()>
+    where T: Flasher
+{
+    let mut decoder = Decoder::new(data);
+    for _ in 0..decoder.map()?
+    {
+        match decoder.item()?
+        {
+            Item::Unsigned(COMMON_COMPONENTS) =>
+            {
+                let count = decoder.array()?;
+                if count == 0 || count > MAX_COMPONENTS
+                {
+                    return None;
+                }
+                for component in manifest.components.iter_mut().take(count)
+                {
+                    if decoder.array()? != 1
+                    {
+                        return None;
+                    }
+                    let entry = table.find_name(decoder.bytes()?, flasher)
+                        .filter(|entry| entry.kind == partition_table::KIND_APPLICATION || entry.kind == partition_table::KIND_DATA)?;
+                    *component = Some(entry);
+                }
+                manifest.component_count = count;
+            }
+            Item::Unsigned(COMMON_SHARED_SEQUENCE) => manifest.shared = Some(decoder.bytes()?),
+            _ => decoder.skip()?
+        }
+    }
+    Some(())
+}
+
+fn parse_manifest<'e, T>(data: &'e [u8], table: &PartitionTable, flasher: &T) -> Option<Manifest<'e>>
+    where T: Flasher
+{
+    let mut manifest = Manifest { sequence_number: 0, components: [None; MAX_COMPONENTS], component_count: 0, shared: None, install: None, validate: None };
+    let mut version = None;
+
+    let mut decoder = Decoder::new(Decoder::new(data).bytes()?);
+    for _ in 0..decoder.map()?
+    {
+        match decoder.item()?
+        {
+            Item::Unsigned(MANIFEST_VERSION) => version = decoder.unsigned(),
+            Item::Unsigned(MANIFEST_SEQUENCE_NUMBER) => manifest.sequence_number = decoder.unsigned()?,
+            Item::Unsigned(MANIFEST_COMMON) => parse_components(decoder.bytes()?, table, flasher, &mut manifest)?,
+            // Severed members (a digest instead of the sequence) are not supported.
+            Item::Unsigned(MANIFEST_INSTALL) => manifest.install = Some(decoder.bytes()?),
+            Item::Unsigned(MANIFEST_VALIDATE) => manifest.validate = Some(decoder.bytes()?),
+            _ => decoder.skip()?
+        }
+    }
+
+    if version != Some(1) || manifest.component_count == 0
+    {
+        return None;
+    }
+    Some(manifest)
+}
+
+#[derive(Clone, Copy, Default)]
+struct Parameters<'e>
+{
+    vendor_id: Option<&'e [u8]>,
+    class_id: Option<&'e [u8]>,
+    digest: Option<&'e [u8]>,
+    size: Option<usize>,
+    uri: Option<&'e [u8]>
+}
+
+struct Processor<'e, 'a, T: Flasher>
+{
+    flasher: &'a mut T,
+    manifest: &'a Manifest<'e>,
+    payloads: &'a [Option<Payload<'e>>; MAX_PAYLOADS],
+    identity: &'a SuitIdentity,
+    options: &'a WriteOptions,
+    parameters: [Parameters<'e>; MAX_COMPONENTS],
+    /// Where the content of each component is located. While dry running
+    /// fetched payloads stay in the staging area.
+    content: [usize; MAX_COMPONENTS],
+    selected: (usize, usize),
+    dry_run: bool,
+    error: Option<InstallError>
+}
+
+impl <'e, 'a, T: Flasher> Processor<'e, 'a, T>
+{
+    fn run(&mut self, sequence: &'e [u8]) -> bool
+    {
+        let mut decoder = Decoder::new(sequence);
+        let len = match decoder.array()
+        {
+            Some(len) if len % 2 == 0 => len,
+            _ => return false
+        };
+
+        for _ in 0..len / 2
+        {
+            let result = match decoder.unsigned()
+            {
+                Some(DIRECTIVE_SET_COMPONENT_INDEX) => match decoder.item()
+                {
+                    Some(Item::Unsigned(index)) if (index as usize) < self.manifest.component_count =>
+                    {
+                        self.selected = (index as usize, index as usize + 1);
+                        true
+                    }
+                    Some(Item::Simple(cbor::SIMPLE_TRUE)) =>
+                    {
+                        self.selected = (0, self.manifest.component_count);
+                        true
+                    }
+                    _ => false
+                },
+                Some(DIRECTIVE_OVERRIDE_PARAMETERS) => self.override_parameters(&mut decoder).is_some(),
+                Some(command) =>
+                {
+                    // The argument of all other supported commands is a reporting policy.
+                    matches!(decoder.item(), Some(Item::Unsigned(_))) &&
+                        self.selected.0 < self.selected.1 &&
+                        (self.selected.0..self.selected.1).all(|index| self.execute(command, index))
+                }
+                None => false
+            };
+
+            if !result
+            {
+                return false;
+            }
+        }
+        decoder.is_empty()
+    }
+
+    fn override_parameters(&mut self, decoder: &mut Decoder<'e>) -> Option<()>
+    {
+        if self.selected.0 >= self.selected.1
+        {
+            return None;
+        }
+
+        for _ in 0..decoder.map()?
+        {
+            let key = decoder.unsigned()?;
+            let mut update = |apply: &dyn Fn(&mut Parameters<'e>)| {
+                for parameters in self.parameters[self.selected.0..self.selected.1].iter_mut()
+                {
+                    apply(parameters);
+                }
+            };
+
+            match key
+            {
+                PARAMETER_VENDOR_ID => { let value = decoder.bytes()?; update(&|p| p.vendor_id = Some(value)) },
+                PARAMETER_CLASS_ID => { let value = decoder.bytes()?; update(&|p| p.class_id = Some(value)) },
+                PARAMETER_IMAGE_DIGEST => { let value = decode_digest(decoder.bytes()?)?; update(&|p| p.digest = Some(value)) },
+                PARAMETER_IMAGE_SIZE => { let value = decoder.unsigned()? as usize; update(&|p| p.size = Some(value)) },
+                PARAMETER_URI => { let value = decoder.text()?; update(&|p| p.uri = Some(value)) },
+                _ => decoder.skip()?
+            }
+        }
+        Some(())
+    }
+
+    fn execute(&mut self, command: u64, index: usize) -> bool
+    {
+        let parameters = self.parameters[index];
+        let component = match self.manifest.components[index]
+        {
+            Some(component) => component,
+            None => return false
+        };
+
+        match command
+        {
+            CONDITION_VENDOR_ID => parameters.vendor_id == Some(&self.identity.vendor_id[..]),
+            CONDITION_CLASS_ID => parameters.class_id == Some(&self.identity.class_id[..]),
+            CONDITION_IMAGE_MATCH => match (parameters.digest, parameters.size)
+            {
+                (Some(digest), Some(size)) if size <= component.size =>
+                {
+                    let mut sha = Sha256::new();
+                    crc::read_chunks(self.content[index], size, self.flasher, |chunk| sha.update(chunk)) && sha.finish() == digest
+                }
+                _ => false
+            },
+            DIRECTIVE_FETCH =>
+            {
+                let payload = match self.payloads.iter().flatten().find(|payload| Some(payload.uri) == parameters.uri)
+                {
+                    Some(payload) => *payload,
+                    None => return false
+                };
+                if payload.len > component.size || parameters.size.map(|size| size != payload.len).unwrap_or(false)
+                {
+                    return false;
+                }
+
+                if self.dry_run
+                {
+                    self.content[index] = payload.address;
+                    return true;
+                }
+
+                match image_installer::copy_area(payload.address, payload.len, component.start, self.flasher, self.options)
+                {
+                    Ok(()) =>
+                    {
+                        self.content[index] = component.start;
+                        true
+                    }
+                    Err(error) =>
+                    {
+                        self.error = Some(error);
+                        false
+                    }
+                }
+            }
+            // The application is launched through bin_info.
+            DIRECTIVE_INVOKE => true,
+            _ => false
+        }
+    }
+}
+
+/// Processes the SUIT envelope staged by the update. The update itself
+/// (i.e. the checksum of the staging area) has to be checked by the caller.
+pub fn process<T, S>(data: &update_info, table: &PartitionTable, identity: &SuitIdentity, flasher: &mut T, counter: &mut S, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher, S: SecurityCounter
+{
+    let mut buf = [0u8; MAX_ENVELOPE_SIZE];
+    let len = core::cmp::min(data.update_len, MAX_ENVELOPE_SIZE);
+    match flasher.read(data.update_start, &mut buf[..len])
+    {
+        Ok(num_bytes) if num_bytes == len => {},
+        _ => return Err(InstallError::ReadFailed { address: data.update_start })
+    }
+
+    let envelope = decode_envelope(&buf[..len], data.update_start, data.update_len).ok_or(InstallError::Rejected)?;
+    if !authenticate(&envelope, counter)
+    {
+        return Err(InstallError::Rejected);
+    }
+
+    let manifest = parse_manifest(envelope.manifest, table, flasher).ok_or(InstallError::Rejected)?;
+    if manifest.sequence_number < counter.min_version() as u64 || manifest.sequence_number > u32::MAX as u64
+    {
+        return Err(InstallError::Rejected);
+    }
+    let install = manifest.install.ok_or(InstallError::Rejected)?;
+
+    // Nothing is written unless all conditions hold for the staged payloads.
+    for dry_run in [true, false]
+    {
+        let mut processor = Processor
+        {
+            flasher: &mut *flasher,
+            manifest: &manifest,
+            payloads: &envelope.payloads,
+            identity,
+            options,
+            parameters: [Parameters::default(); MAX_COMPONENTS],
+            content: [0; MAX_COMPONENTS],
+            selected: (0, if manifest.component_count == 1 { 1 } else { 0 }),
+            dry_run,
+            error: None
+        };
+        for (content, component) in processor.content.iter_mut().zip(manifest.components.iter().flatten())
+        {
+            *content = component.start;
+        }
+
+        let passed = manifest.shared.map(|shared| processor.run(shared)).unwrap_or(true) &&
+                     processor.run(install) &&
+                     (dry_run || manifest.validate.map(|validate| processor.run(validate)).unwrap_or(true));
+        if !passed
+        {
+            return Err(processor.error.unwrap_or(InstallError::Rejected));
+        }
+    }
+
+    if manifest.sequence_number as u32 > counter.min_version()
+    {
+        counter.set_min_version(manifest.sequence_number as u32);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::cbor::Encoder;
+    use crate::partition_table;
+    use crate::sha256::Sha256;
+    use crate::{update_info, InstallError, SecurityCounter, WriteOptions};
+    use super::*;
+
+    const SLOTS: [usize; 2] = [0x7000, 0x7400];
+    const IDENTITY: SuitIdentity = SuitIdentity { vendor_id: [0xEE; 16], class_id: [0xC1; 16] };
+
+    /// Builds an envelope installing the test image into "app", staged at 0x1000.
+    fn stage_envelope(fl: &mut FakeFlasher, sequence_number: u64, class_id: &[u8]) -> update_info
+    {
+        let mut sha = Sha256::new();
+        sha.update(&test_image());
+        let image_digest = sha.finish();
+
+        let mut digest_buf = [0u8; 40];
+        let mut encoder = Encoder::new(&mut digest_buf);
+        encoder.array(2).integer(-16).bytes(&image_digest);
+        let digest_len = encoder.finish().unwrap();
+
+        let mut shared_buf = [0u8; 128];
+        let mut encoder = Encoder::new(&mut shared_buf);
+        encoder.array(6)
+            .unsigned(DIRECTIVE_OVERRIDE_PARAMETERS).map(4)
+                .unsigned(PARAMETER_VENDOR_ID).bytes(&IDENTITY.vendor_id)
+                .unsigned(PARAMETER_CLASS_ID).bytes(class_id)
+                .unsigned(PARAMETER_IMAGE_DIGEST).bytes(&digest_buf[..digest_len])
+                .unsigned(PARAMETER_IMAGE_SIZE).unsigned(128)
+            .unsigned(CONDITION_VENDOR_ID).unsigned(15)
+            .unsigned(CONDITION_CLASS_ID).unsigned(15);
+        let shared_len = encoder.finish().unwrap();
+
+        let mut install_buf = [0u8; 32];
+        let mut encoder = Encoder::new(&mut install_buf);
+        encoder.array(6)
+            .unsigned(DIRECTIVE_OVERRIDE_PARAMETERS).map(1).unsigned(PARAMETER_URI).text("#app")
+            .unsigned(DIRECTIVE_FETCH).unsigned(2)
+            .unsigned(CONDITION_IMAGE_MATCH).unsigned(15);
+        let install_len = encoder.finish().unwrap();
+
+        let mut common_buf = [0u8; 160];
+        let mut encoder = Encoder::new(&mut common_buf);
+        encoder.map(2)
+            .unsigned(COMMON_COMPONENTS).array(1).array(1).bytes(b"app")
+            .unsigned(COMMON_SHARED_SEQUENCE).bytes(&shared_buf[..shared_len]);
+        let common_len = encoder.finish().unwrap();
+
+        let mut manifest_buf = [0u8; 256];
+        let mut encoder = Encoder::new(&mut manifest_buf);
+        encoder.map(4)
+            .unsigned(MANIFEST_VERSION).unsigned(1)
+            .unsigned(MANIFEST_SEQUENCE_NUMBER).unsigned(sequence_number)
+            .unsigned(MANIFEST_COMMON).bytes(&common_buf[..common_len])
+            .unsigned(MANIFEST_INSTALL).bytes(&install_buf[..install_len]);
+        let manifest_len = encoder.finish().unwrap();
+
+        let mut wrapped_buf = [0u8; 260];
+        let mut encoder = Encoder::new(&mut wrapped_buf);
+        encoder.bytes(&manifest_buf[..manifest_len]);
+        let wrapped_len = encoder.finish().unwrap();
+
+        let mut sha = Sha256::new();
+        sha.update(&wrapped_buf[..wrapped_len]);
+        let mut manifest_digest_buf = [0u8; 40];
+        let mut encoder = Encoder::new(&mut manifest_digest_buf);
+        encoder.array(2).integer(-16).bytes(&sha.finish());
+        let manifest_digest_len = encoder.finish().unwrap();
+
+        let mut sign1_buf = [0u8; 16];
+        let mut encoder = Encoder::new(&mut sign1_buf);
+        encoder.tag(18).array(4).bytes(&[0xA1, 0x01, 0x26]).map(0).raw(&[0xF6]).bytes(&[0x5A, 0x5A]);
+        let sign1_len = encoder.finish().unwrap();
+
+        let mut auth_buf = [0u8; 64];
+        let mut encoder = Encoder::new(&mut auth_buf);
+        encoder.array(2).bytes(&manifest_digest_buf[..manifest_digest_len]).bytes(&sign1_buf[..sign1_len]);
+        let auth_len = encoder.finish().unwrap();
+
+        let mut envelope = [0u8; 512];
+        let mut encoder = Encoder::new(&mut envelope);
+        encoder.tag(107).map(3)
+            .unsigned(ENVELOPE_AUTHENTICATION).bytes(&auth_buf[..auth_len])
+            .raw(&[ENVELOPE_MANIFEST as u8]).raw(&wrapped_buf[..wrapped_len])
+            .text("#app").bytes(&test_image());
+        let envelope_len = encoder.finish().unwrap();
+        copy_to_flasher(fl, 0x1000, &envelope[..envelope_len]);
+
+        update_info { magic: *b"MUUPD", struct_ver: 1, update_start: 0x1000, update_len: envelope_len, target_adress: 0, checksum: 0 }
+    }
+
+    fn signed_counter() -> FakeSecurityCounter
+    {
+        let mut counter = FakeSecurityCounter::new(3);
+        counter.accepted_signature = Some([0x5A, 0x5A]);
+        counter
+    }
+
+    #[test]
+    fn can_install_signed_manifest()
+    {
+        let mut fl = FakeFlasher::new();
+        write_partition_table(&mut fl, SLOTS[0], 1);
+        let table = partition_table::read_table(&SLOTS, &fl).unwrap();
+        let update = stage_envelope(&mut fl, 7, &IDENTITY.class_id);
+
+        // Not signed by someone we trust
+        let mut counter = FakeSecurityCounter::new(3);
+        assert_eq!(process(&update, &table, &IDENTITY, &mut fl, &mut counter, &WriteOptions::default()), Err(InstallError::Rejected));
+
+        let mut counter = signed_counter();
+        assert_eq!(process(&update, &table, &IDENTITY, &mut fl, &mut counter, &WriteOptions::default()), Ok(()));
+        assert!(fl.memory[0x4000..0x4080] == test_image());
+        assert_eq!(counter.min_version(), 7);
+    }
+
+    #[test]
+    fn failed_conditions_write_nothing()
+    {
+        let mut fl = FakeFlasher::new();
+        write_partition_table(&mut fl, SLOTS[0], 1);
+        let table = partition_table::read_table(&SLOTS, &fl).unwrap();
+
+        // Wrong class, rollback, tampered payload
+        let cases: [(u64, &[u8], bool); 3] = [(7, &[0xC2; 16], false), (2, &IDENTITY.class_id, false), (7, &IDENTITY.class_id, true)];
+        for (sequence_number, class_id, tamper) in cases.iter()
+        {
+            let update = stage_envelope(&mut fl, *sequence_number, class_id);
+            if *tamper
+            {
+                fl.memory[update.update_start + update.update_len - 1] ^= 0x01;
+            }
+            assert_eq!(process(&update, &table, &IDENTITY, &mut fl, &mut signed_counter(), &WriteOptions::default()), Err(InstallError::Rejected));
+            assert!(fl.memory[0x4000..0x4080].iter().all(|byte| *byte == 0));
+        }
+    }
+
+    #[test]
+    fn rejects_payloads_beyond_the_staging_area()
+    {
+        // "#app" followed by a byte string header claiming 2^64 - 1 bytes
+        let mut envelope = [0u8; 32];
+        let mut encoder = Encoder::new(&mut envelope);
+        encoder.tag(107).map(1).text("#app").raw(&[0x5B, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        let envelope_len = encoder.finish().unwrap();
+
+        assert!(decode_envelope(&envelope[..envelope_len], 0x1000, 0x400).is_none());
+        assert!(decode_envelope(&envelope[..envelope_len], usize::MAX - 8, 8).is_none());
+    }
+}
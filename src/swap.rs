@@ -0,0 +1,489 @@
+//! Swap based installation.
+//!
+//! Instead of overwriting the primary slot (the area the application is
+//! linked for) the contents of the primary and the secondary slot (where the
+//! update is staged) are exchanged sector by sector, using a single scratch
+//! sector. Afterwards the old image lives in the secondary slot, so the
+//! swap can be reverted if the new image does not confirm itself.
+//!
+//! Each sector is swapped in three steps:
+//! 1. primary -> scratch
+//! 2. secondary -> primary
+//! 3. scratch -> secondary
+//!
+//! The source of each step stays intact until the step after it is done, so
+//! every step can be repeated. The next step is persisted in a status record
+//! after each step, which allows resuming an interrupted swap at boot. The
+//! status is kept in two records, which are written alternately, such that a
+//! power loss while writing the status never loses both.
+
+use super::{Flasher, InstallError, LoaderConfig, SecurityCounter, UpdateEncoding, WriteOptions, image_installer, update_info};
+use super::crc::Checksum;
+use super::status_record;
+
+const STATUS_MAGIC: [u8; 4] = *b"MUSW";
+const STATUS_SIZE: usize = 20;
+const STEPS_PER_SECTOR: u8 = 3;
+
+/// Describes the flash areas used for swapping.
+#[derive(Debug, Clone, Copy)]
+pub struct SwapLayout
+{
+    /// Slot the application is linked for and launched from.
+    pub primary: usize,
+    /// Slot the update is staged in.
+    pub secondary: usize,
+    /// A single sector used as temporary storage.
+    pub scratch: usize,
+    /// Addresses of the two status records. They have to be located in
+    /// different erase units.
+    pub status: [usize; 2],
+    pub sector_size: usize,
+    /// Number of sectors per slot.
+    pub sector_count: usize
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SwapState
+{
+    /// No swap was ever done.
+    None,
+    /// The update is being swapped into the primary slot.
+    Swapping,
+    /// The update was swapped in and will be launched for the first time.
+    TestPending,
+    /// The update was launched, but did not confirm itself yet.
+    Testing,
+    /// The update confirmed itself, the swap is permanent.
+    Confirmed,
+    /// The previous image is being swapped back into the primary slot.
+    Reverting,
+    /// The previous image was restored.
+    Reverted
+}
+
+impl SwapState
+{
+    fn from_u8(value: u8) -> Option<Self>
+    {
+        match value
+        {
+            0 => Some(SwapState::None),
+            1 => Some(SwapState::Swapping),
+            2 => Some(SwapState::TestPending),
+            3 => Some(SwapState::Testing),
+            4 => Some(SwapState::Confirmed),
+            5 => Some(SwapState::Reverting),
+            6 => Some(SwapState::Reverted),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SwapStatus
+{
+    pub state: SwapState,
+    /// Sector and step to be done next while swapping or reverting.
+    pub sector: u32,
+    pub step: u8,
+    /// Checksum of the update that was swapped in.
+    pub update_checksum: u32,
+    sequence: u32
+}
+
+impl SwapStatus
+{
+    fn to_bytes(self) -> [u8; STATUS_SIZE]
+    {
+        let mut bytes = [0u8; STATUS_SIZE];
+        bytes[0..4].copy_from_slice(&STATUS_MAGIC);
+        bytes[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8] = self.state as u8;
+        bytes[9] = self.step;
+        bytes[10..12].copy_from_slice(&[0, 0]);
+        bytes[12..16].copy_from_slice(&self.sector.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.update_checksum.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; STATUS_SIZE]) -> Option<Self>
+    {
+        if bytes[0..4] != STATUS_MAGIC
+        {
+            return None;
+        }
+
+        Some(Self
+        {
+            sequence: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            state: SwapState::from_u8(bytes[8])?,
+            step: bytes[9],
+            sector: u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]),
+            update_checksum: u32::from_le_bytes([bytes[16], bytes[17], bytes[18], bytes[19]])
+        })
+    }
+}
+
+fn read_record<T>(address: usize, flasher: &T) -> Option<SwapStatus>
+    where T: Flasher
+{
+    status_record::read(address, flasher).and_then(|bytes| SwapStatus::from_bytes(&bytes))
+}
+
+/// Yields the most recent valid status record.
+pub fn read_status<T>(layout: &SwapLayout, flasher: &T) -> SwapStatus
+    where T: Flasher
+{
+    let records = [read_record(layout.status[0], flasher), read_record(layout.status[1], flasher)];
+    status_record::latest(records, |status| status.sequence)
+        .unwrap_or(SwapStatus { state: SwapState::None, sector: 0, step: 0, update_checksum: 0, sequence: 0 })
+}
+
+fn write_status<T>(layout: &SwapLayout, status: &mut SwapStatus, flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    status.sequence = status.sequence.wrapping_add(1);
+    status_record::write(&layout.status, status.sequence, &status.to_bytes(), flasher, options)
+}
+
+fn copy_sector<T>(source: usize, destination: usize, layout: &SwapLayout, flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    let mut buf = [0u8; 64];
+    let mut offset = 0;
+    while offset < layout.sector_size
+    {
+        let chunk_len = core::cmp::min(buf.len(), layout.sector_size - offset);
+        match flasher.read(source + offset, &mut buf[..chunk_len])
+        {
+            Ok(num_bytes) if num_bytes == chunk_len => {},
+            _ => return Err(InstallError::ReadFailed { address: source + offset })
+        }
+
+        match super::write_chunk(flasher, destination + offset, &buf[..chunk_len], options)
+        {
+            Ok(()) => {},
+            Err(super::WriteError::VerifyFailed { address }) => return Err(InstallError::VerifyFailed { address }),
+            Err(_) => return Err(InstallError::WriteFailed { address: destination + offset })
+        }
+        offset += chunk_len;
+    }
+    flasher.flush();
+    Ok(())
+}
+
+/// Continues the swap described by `status` until all sectors are exchanged.
+fn run_swap<T>(layout: &SwapLayout, status: &mut SwapStatus, flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    while (status.sector as usize) < layout.sector_count
+    {
+        let offset = status.sector as usize * layout.sector_size;
+        let primary = layout.primary + offset;
+        let secondary = layout.secondary + offset;
+
+        match status.step
+        {
+            0 => copy_sector(primary, layout.scratch, layout, flasher, options)?,
+            1 => copy_sector(secondary, primary, layout, flasher, options)?,
+            _ => copy_sector(layout.scratch, secondary, layout, flasher, options)?
+        }
+
+        status.step += 1;
+        if status.step == STEPS_PER_SECTOR
+        {
+            status.step = 0;
+            status.sector += 1;
+        }
+        write_status(layout, status, flasher, options)?;
+    }
+    Ok(())
+}
+
+/// Swaps the update in the secondary slot into the primary slot.
+pub fn start_swap<T>(layout: &SwapLayout, update_checksum: u32, flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    let mut status = read_status(layout, flasher);
+    status.state = SwapState::Swapping;
+    status.sector = 0;
+    status.step = 0;
+    status.update_checksum = update_checksum;
+    write_status(layout, &mut status, flasher, options)?;
+
+    run_swap(layout, &mut status, flasher, options)?;
+    status.state = SwapState::TestPending;
+    write_status(layout, &mut status, flasher, options)
+}
+
+/// Swaps the previous image back into the primary slot.
+pub fn revert<T>(layout: &SwapLayout, flasher: &mut T, options: &WriteOptions) -> Result<(), InstallError>
+    where T: Flasher
+{
+    let mut status = read_status(layout, flasher);
+    status.state = SwapState::Reverting;
+    status.sector = 0;
+    status.step = 0;
+    write_status(layout, &mut status, flasher, options)?;
+
+    run_swap(layout, &mut status, flasher, options)?;
+    status.state = SwapState::Reverted;
+    write_status(layout, &mut status, flasher, options)
+}
+
+/// Finishes a swap or revert that was interrupted by a reset.
+pub fn resume<T>(layout: &SwapLayout, flasher: &mut T, options: &WriteOptions) -> Result<SwapState, InstallError>
+    where T: Flasher
+{
+    let mut status = read_status(layout, flasher);
+    let final_state = match status.state
+    {
+        SwapState::Swapping => SwapState::TestPending,
+        SwapState::Reverting => SwapState::Reverted,
+        state => return Ok(state)
+    };
+
+    run_swap(layout, &mut status, flasher, options)?;
+    status.state = final_state;
+    write_status(layout, &mut status, flasher, options)?;
+    Ok(final_state)
+}
+
+/// Called by the application once it is sure it works, makes the swap
+/// permanent. Without confirmation the loader reverts to the previous
+/// image on the next boot.
+pub fn confirm_image<T>(layout: &SwapLayout, flasher: &mut T) -> bool
+    where T: Flasher
+{
+    let mut status = read_status(layout, flasher);
+    match status.state
+    {
+        SwapState::Confirmed => true,
+        SwapState::TestPending | SwapState::Testing =>
+        {
+            status.state = SwapState::Confirmed;
+            write_status(layout, &mut status, flasher, &WriteOptions::default()).is_ok()
+        }
+        _ => false
+    }
+}
+
+/// Brings the slots into a launchable state, called by the loader at boot:
+/// interrupted swaps are finished, unconfirmed updates are reverted and a
+/// newly staged update is swapped in. `update_info` was read from
+/// `update_info_address`, which may differ from the config if there is a
+/// partition table.
+pub fn prepare_boot<T, S, C>(layout: &SwapLayout, update_info: Option<&update_info>, update_info_address: usize, config: &LoaderConfig, flasher: &mut T, counter: &mut S, engine: &mut C) -> Result<SwapState, InstallError>
+    where T: Flasher, S: SecurityCounter, C: Checksum
+{
+    let options = &config.write_options;
+
+    let mut state = resume(layout, flasher, options)?;
+    let status = read_status(layout, flasher);
+
+    if state == SwapState::Testing
+    {
+        // The update was launched, but rebooted without confirming itself.
+        revert(layout, flasher, options)?;
+        state = SwapState::Reverted;
+    }
+
+    let swapped_checksum = status.update_checksum as usize;
+    if let Some(update) = update_info
+    {
+        if state == SwapState::Confirmed && update.checksum == swapped_checksum
+        {
+            // Only now the update is known to be good.
+            image_installer::commit_version(update_info_address, flasher, counter);
+        }
+
+        let is_new = state == SwapState::None || update.checksum != swapped_checksum;
+        if matches!(state, SwapState::None | SwapState::Confirmed | SwapState::Reverted) &&
+           is_new &&
+           update.update_start == layout.secondary &&
+           update.target_adress == layout.primary &&
+           update.update_len <= layout.sector_count * layout.sector_size &&
+           image_installer::update_encoding(update_info_address, flasher) == Some(UpdateEncoding::Raw) &&
+           image_installer::check_update(update, update_info_address, config.board_id, flasher, counter, engine)
+        {
+            start_swap(layout, update.checksum as u32, flasher, options)?;
+            state = SwapState::TestPending;
+        }
+    }
+
+    if state == SwapState::TestPending
+    {
+        // From now on a reset without confirmation means the update failed.
+        let mut status = read_status(layout, flasher);
+        status.state = SwapState::Testing;
+        write_status(layout, &mut status, flasher, options)?;
+        state = SwapState::Testing;
+    }
+    Ok(state)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::crc::Crc32Table;
+    use crate::{update_info, partition_table, tlv, LoaderConfig, WriteOptions};
+    use super::*;
+
+    const LAYOUT: SwapLayout = SwapLayout
+    {
+        primary: 0x1000,
+        secondary: 0x2000,
+        scratch: 0x3000,
+        status: [0x3400, 0x3800],
+        sector_size: 0x400,
+        sector_count: 4
+    };
+
+    const CONFIG: LoaderConfig = LoaderConfig
+    {
+        update_info_address: 0,
+        bin_info_address: 0x100,
+        board_id: Some(TEST_BOARD_ID),
+        write_options: WriteOptions { verify: false, verify_retries: 0, io_retries: 0 },
+        swap: Some(LAYOUT),
+        loader: None,
+        components: None,
+        data_partitions: &[],
+        partition_table: None,
+        delta: None,
+        suit_identity: None,
+        protocol: crate::Protocol::Native
+    };
+
+    fn fill_slots(fl: &mut FakeFlasher)
+    {
+        for sector in 0..4
+        {
+            fl.memory[0x1000 + sector * 0x400..0x1400 + sector * 0x400].fill(0xA0 + sector as u8);
+            fl.memory[0x2000 + sector * 0x400..0x2400 + sector * 0x400].fill(0xB0 + sector as u8);
+        }
+    }
+
+    fn slots_are_swapped(fl: &FakeFlasher) -> bool
+    {
+        (0..4).all(|sector| fl.memory[0x1000 + sector * 0x400..0x1400 + sector * 0x400].iter().all(|b| *b == 0xB0 + sector as u8) &&
+                            fl.memory[0x2000 + sector * 0x400..0x2400 + sector * 0x400].iter().all(|b| *b == 0xA0 + sector as u8))
+    }
+
+    #[test]
+    fn swap_exchanges_slots_and_revert_restores_them()
+    {
+        let mut fl = FakeFlasher::new();
+        fill_slots(&mut fl);
+
+        assert_eq!(start_swap(&LAYOUT, 0x1234, &mut fl, &WriteOptions::default()), Ok(()));
+        assert!(slots_are_swapped(&fl));
+        assert_eq!(read_status(&LAYOUT, &fl).state, SwapState::TestPending);
+
+        assert_eq!(revert(&LAYOUT, &mut fl, &WriteOptions::default()), Ok(()));
+        assert_eq!(fl.memory[0x1000], 0xA0);
+        assert_eq!(fl.memory[0x2FFF], 0xB3);
+        assert_eq!(read_status(&LAYOUT, &fl).state, SwapState::Reverted);
+    }
+
+    #[test]
+    fn interrupted_swap_can_be_resumed()
+    {
+        let mut fl = FakeFlasher::new();
+        fill_slots(&mut fl);
+
+        // Power loss in the middle of step 2 of the second sector: one status
+        // write at the start, 16 chunk writes and a status write per step.
+        fl.fail_after_writes = Some(1 + 3 * 17 + 17 + 5);
+        assert!(start_swap(&LAYOUT, 0x1234, &mut fl, &WriteOptions::default()).is_err());
+        assert_eq!(read_status(&LAYOUT, &fl).state, SwapState::Swapping);
+
+        fl.fail_after_writes = None;
+        assert_eq!(resume(&LAYOUT, &mut fl, &WriteOptions::default()), Ok(SwapState::TestPending));
+        assert!(slots_are_swapped(&fl));
+    }
+
+    #[test]
+    fn unconfirmed_update_is_reverted_on_next_boot()
+    {
+        let mut fl = FakeFlasher::new();
+        fill_slots(&mut fl);
+        write_metadata(&mut fl, core::mem::size_of::<update_info>(), &[(tlv::TAG_BOARD_ID, &TEST_BOARD_ID.to_le_bytes())]);
+
+        let mut engine = Crc32Table::new();
+        engine.update(&fl.memory[0x2000..0x3000]);
+        let update = update_info {
+            magic: *b"MUUPD",
+            struct_ver: 1,
+            update_start: 0x2000,
+            update_len: 0x1000,
+            target_adress: 0x1000,
+            checksum: engine.finish() as usize
+        };
+        let mut counter = FakeSecurityCounter::new(0);
+
+        // First boot: the update is swapped in and launched.
+        assert_eq!(prepare_boot(&LAYOUT, Some(&update), 0, &CONFIG, &mut fl, &mut counter, &mut engine), Ok(SwapState::Testing));
+        assert!(slots_are_swapped(&fl));
+
+        // Second boot without confirmation: the old image is back and the
+        // update is not swapped in again.
+        assert_eq!(prepare_boot(&LAYOUT, Some(&update), 0, &CONFIG, &mut fl, &mut counter, &mut engine), Ok(SwapState::Reverted));
+        assert_eq!(fl.memory[0x1000], 0xA0);
+        assert_eq!(prepare_boot(&LAYOUT, Some(&update), 0, &CONFIG, &mut fl, &mut counter, &mut engine), Ok(SwapState::Reverted));
+        assert_eq!(fl.memory[0x1000], 0xA0);
+    }
+
+    #[test]
+    fn confirmed_update_stays()
+    {
+        let mut fl = FakeFlasher::new();
+        fill_slots(&mut fl);
+
+        assert!(!confirm_image(&LAYOUT, &mut fl));
+        assert_eq!(start_swap(&LAYOUT, 0x1234, &mut fl, &WriteOptions::default()), Ok(()));
+        assert!(confirm_image(&LAYOUT, &mut fl));
+
+        let mut counter = FakeSecurityCounter::new(0);
+        assert_eq!(prepare_boot(&LAYOUT, None, 0, &CONFIG, &mut fl, &mut counter, &mut Crc32Table::new()), Ok(SwapState::Confirmed));
+        assert!(slots_are_swapped(&fl));
+    }
+
+    #[test]
+    fn swap_uses_update_info_from_partition_table()
+    {
+        let mut fl = FakeFlasher::new();
+        fill_slots(&mut fl);
+        let config = LoaderConfig { partition_table: Some([0x7000, 0x7400]), ..CONFIG };
+        write_partition_table_entries(&mut fl, 0x7000, 1, &[(b"update_info", partition_table::KIND_UPDATE_INFO, 0x600, 0x100, 0),
+                                                            (b"bin_info", partition_table::KIND_BIN_INFO, 0x700, 0x100, 0)]);
+        write_metadata(&mut fl, 0x600 + core::mem::size_of::<update_info>(), &[(tlv::TAG_BOARD_ID, &TEST_BOARD_ID.to_le_bytes()),
+                                                                                (tlv::TAG_IMAGE_VERSION, &tlv::image_version(2, 0, 0).to_le_bytes())]);
+
+        let table = partition_table::read_table(&[0x7000, 0x7400], &fl).unwrap();
+        let (update_info_address, bin_info_address) = crate::info_addresses(&config, Some(&table), &fl);
+        assert_eq!((update_info_address, bin_info_address), (0x600, 0x700));
+
+        let mut engine = Crc32Table::new();
+        engine.update(&fl.memory[0x2000..0x3000]);
+        let update = update_info {
+            magic: *b"MUUPD",
+            struct_ver: 1,
+            update_start: 0x2000,
+            update_len: 0x1000,
+            target_adress: 0x1000,
+            checksum: engine.finish() as usize
+        };
+        let mut counter = FakeSecurityCounter::new(0);
+
+        assert_eq!(prepare_boot(&LAYOUT, Some(&update), update_info_address, &config, &mut fl, &mut counter, &mut engine), Ok(SwapState::Testing));
+        assert!(slots_are_swapped(&fl));
+
+        // The update confirms itself, its version is committed on the next boot.
+        assert!(confirm_image(&LAYOUT, &mut fl));
+        assert_eq!(prepare_boot(&LAYOUT, Some(&update), update_info_address, &config, &mut fl, &mut counter, &mut engine), Ok(SwapState::Confirmed));
+        assert_eq!(counter.min_version(), tlv::image_version(2, 0, 0));
+    }
+}
@@ -1,12 +1,16 @@
+use core::cell::Cell;
 use embedded_hal::serial::{Read, Write};
-use nb;
-use crate::Flasher;
+use crate::{Flasher, SecurityCounter, mcuboot};
+use crate::partition_table::{ENTRY_SIZE, KIND_APPLICATION, KIND_DATA, MAX_TABLE_SIZE, TABLE_FORMAT, TABLE_HEADER_SIZE, TABLE_MAGIC};
+use crate::crc::{Checksum, Crc32Table};
+use crate::sha256::Sha256;
+use crate::transport::Transport;
 
 pub enum SomeEnum { }
 
 pub struct FakeUart
 {
-    pub memory: [u8; 512],
+    pub memory: [u8; 2048],
     pub out_buf: [u8; 512],
     pub read_index: usize,
     pub write_index: usize,
@@ -19,7 +23,7 @@ impl FakeUart
     {
         Self
         {
-            memory: [0; 512],
+            memory: [0; 2048],
             out_buf: [0; 512],
             read_index: 0,
             write_index: 0,
@@ -32,7 +36,7 @@ impl Read::<u8> for FakeUart
 {
     type Error = SomeEnum;
     fn read(&mut self) -> nb::Result<u8, Self::Error> {
-        if self.read_index > 256
+        if self.read_index >= self.memory.len()
         {
             return Err(nb::Error::WouldBlock)
         }
@@ -53,15 +57,36 @@ impl Write::<u8> for FakeUart
     }
     fn flush(&mut self) -> nb::Result<(), Self::Error> 
     {
-        todo!()
+        Ok(())
     }
     
 }
 
+impl Transport for FakeUart
+{
+    fn read_byte(&mut self) -> Option<u8>
+    {
+        Read::read(self).ok()
+    }
+
+    fn write_byte(&mut self, byte: u8)
+    {
+        let _ = Write::write(self, byte);
+    }
+}
+
 pub struct FakeFlasher
 {
     pub memory: [u8; 0x8000],
-    pub flush_called: bool
+    pub flush_called: bool,
+    /// Writes to this address have no effect, simulating a broken cell.
+    pub stuck_address: Option<usize>,
+    pub write_count: usize,
+    /// Number of upcoming reads/writes that fail.
+    pub failing_reads: Cell<usize>,
+    pub failing_writes: usize,
+    /// All writes after this many writes fail, simulating a power loss.
+    pub fail_after_writes: Option<usize>
 }
 
 impl FakeFlasher
@@ -71,7 +96,12 @@ impl FakeFlasher
         Self
         {
             memory: [0x00; 0x8000],
-            flush_called: false
+            flush_called: false,
+            stuck_address: None,
+            write_count: 0,
+            failing_reads: Cell::new(0),
+            failing_writes: 0,
+            fail_after_writes: None
         }
     }
 }
@@ -79,20 +109,33 @@ impl FakeFlasher
 impl Flasher for FakeFlasher
 {
     fn write(&mut self, destination: usize, data: &[u8]) -> Result<(), crate::WriteError> {
-        for (index, byte) in data.iter().enumerate()
+        if self.failing_writes > 0
+        {
+            self.failing_writes -= 1;
+            return Err(crate::WriteError::AddressOutOfRange);
+        }
+        if self.fail_after_writes.map(|limit| self.write_count >= limit).unwrap_or(false)
+        {
+            return Err(crate::WriteError::AddressOutOfRange);
+        }
+        let old_value = self.stuck_address.map(|address| self.memory[address]);
+        self.memory[destination..destination + data.len()].copy_from_slice(data);
+        if let (Some(address), Some(value)) = (self.stuck_address, old_value)
         {
-            self.memory[destination + index as usize] = *byte;
+            self.memory[address] = value;
         }
-        return Ok(());
+        self.write_count += 1;
+        Ok(())
     }
 
     fn read(&self, source_address: usize, destination: &mut[u8]) -> Result<usize, crate::ReadError> 
     {
-
-        for index in 0..destination.len()
+        if self.failing_reads.get() > 0
         {
-            destination[index] = self.memory[source_address + index];
+            self.failing_reads.set(self.failing_reads.get() - 1);
+            return Err(crate::ReadError::ReadFailed);
         }
+        destination.copy_from_slice(&self.memory[source_address..source_address + destination.len()]);
         Ok(destination.len())
     }
 
@@ -101,6 +144,61 @@ impl Flasher for FakeFlasher
     }
 }
 
+pub struct FakeSecurityCounter
+{
+    pub min_version: u32,
+    pub accepted_signature: Option<[u8; 2]>,
+    /// The hash the accepted signature of an update_info is made over.
+    pub signed_hash: Option<[u8; 32]>
+}
+
+impl FakeSecurityCounter
+{
+    pub fn new(min_version: u32) -> Self
+    {
+        Self
+        {
+            min_version,
+            accepted_signature: None,
+            signed_hash: None
+        }
+    }
+}
+
+impl SecurityCounter for FakeSecurityCounter
+{
+    fn min_version(&self) -> u32
+    {
+        self.min_version
+    }
+
+    fn set_min_version(&mut self, version: u32)
+    {
+        self.min_version = version;
+    }
+
+    fn verify_downgrade(&self, hash: &[u8; 32], signature: &[u8]) -> bool
+    {
+        self.signed_hash == Some(*hash) && self.accepted_signature.map(|s| s == signature).unwrap_or(false)
+    }
+
+    fn verify_manifest(&self, to_be_signed: &[u8], signature: &[u8]) -> bool
+    {
+        // A COSE Sig_structure always starts with ["Signature1", ...
+        to_be_signed.starts_with(b"\x84\x6ASignature1") && self.accepted_signature.map(|s| s == signature).unwrap_or(false)
+    }
+
+    fn verify_privileged(&self, hash: &[u8; 32], signature: &[u8]) -> bool
+    {
+        self.signed_hash == Some(*hash) && self.accepted_signature.map(|s| s == signature).unwrap_or(false)
+    }
+
+    fn verify_mcuboot_signature(&self, _hash: &[u8; 32], _signature_type: u16, signature: &[u8]) -> bool
+    {
+        self.accepted_signature.map(|s| s == signature).unwrap_or(false)
+    }
+}
+
 pub fn copy_to_uart( uart: &mut FakeUart, data: &[u8])
 {
     for byte in data.iter()
@@ -133,4 +231,172 @@ pub fn make_packet(uart: &mut FakeUart, data: &[u8])
     uart.memory[uart.mem_use ] = bcc;
     uart.mem_use += 1;
 
-}
\ No newline at end of file
+}
+
+pub fn make_data_packet(uart: &mut FakeUart, payload: &[u8; 128])
+{
+    make_payload_packet(uart, 0x01, payload);
+}
+
+pub fn make_payload_packet(uart: &mut FakeUart, packet_type: u8, payload: &[u8; 128])
+{
+    let start_index = uart.mem_use;
+    copy_to_uart(uart, &[0x02, packet_type]);
+    copy_to_uart(uart, payload);
+    copy_to_uart(uart, &[0x03]);
+
+    let mut bcc: u8 = 0;
+    for i in start_index.. uart.mem_use
+    {
+        bcc ^= uart.memory[i];
+    }
+    copy_to_uart(uart, &[bcc]);
+}
+
+/// 128 byte test image, its CRC-32 is 0x2279EFE7.
+pub fn test_image() -> [u8; 128]
+{
+    let mut image = [0u8; 128];
+    for (index, byte) in image.iter_mut().enumerate()
+    {
+        *byte = if index % 8 == 7 { (index + 1) as u8 } else { (index % 8 + 1) as u8 };
+    }
+    image
+}
+
+pub const TEST_BOARD_ID: u32 = 0xB0A2D;
+
+/// Writes a metadata area holding the given entries to `address`.
+pub fn write_metadata(flasher: &mut FakeFlasher, address: usize, entries: &[(u16, &[u8])])
+{
+    let mut offset = address + 4;
+    for (tag, value) in entries.iter()
+    {
+        copy_to_flasher(flasher, offset, &tag.to_le_bytes());
+        copy_to_flasher(flasher, offset + 2, &(value.len() as u16).to_le_bytes());
+        copy_to_flasher(flasher, offset + 4, value);
+        offset += 4 + value.len();
+    }
+    copy_to_flasher(flasher, address, b"MT");
+    copy_to_flasher(flasher, address + 2, &((offset - address - 4) as u16).to_le_bytes());
+}
+
+/// Value of a SHA-256 TAG_DIGEST entry for `data`.
+pub fn sha256_digest_value(data: &[u8]) -> [u8; 33]
+{
+    let mut sha = Sha256::new();
+    sha.update(data);
+    let mut value = [0u8; 33];
+    value[0] = crate::digest::DIGEST_SHA256;
+    value[1..].copy_from_slice(&sha.finish());
+    value
+}
+
+/// The hash a signature of an update_info with the given target, length and
+/// metadata entries is made over, ignoring TAG_SIGNATURE.
+pub fn update_signed_hash(target: usize, len: usize, entries: &[(u16, &[u8])]) -> [u8; 32]
+{
+    let mut sha = Sha256::new();
+    sha.update(&(target as u64).to_le_bytes());
+    sha.update(&(len as u64).to_le_bytes());
+    for (tag, value) in entries.iter().filter(|(tag, _)| *tag != crate::tlv::TAG_SIGNATURE)
+    {
+        sha.update(&tag.to_le_bytes());
+        sha.update(&(value.len() as u16).to_le_bytes());
+        sha.update(value);
+    }
+    sha.finish()
+}
+
+/// Writes a partition table with an application and a data partition to
+/// `address`, yields its size and CRC.
+pub fn write_partition_table(fl: &mut FakeFlasher, address: usize, revision: u32) -> (usize, u32)
+{
+    write_partition_table_entries(fl, address, revision, &[(b"app", KIND_APPLICATION, 0x4000, 0x1000, 0), (b"fonts", KIND_DATA, 0x5100, 0x1000, 0x5000)])
+}
+
+/// Writes a partition table with the given entries (name, kind, start, size,
+/// aux) to `address`, yields its size and CRC.
+pub fn write_partition_table_entries(fl: &mut FakeFlasher, address: usize, revision: u32, entries: &[(&[u8], u8, u32, u32, u32)]) -> (usize, u32)
+{
+    let mut table = [0u8; MAX_TABLE_SIZE];
+    table[0..4].copy_from_slice(&TABLE_MAGIC);
+    table[4..6].copy_from_slice(&TABLE_FORMAT.to_le_bytes());
+    table[6..8].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+    table[8..12].copy_from_slice(&revision.to_le_bytes());
+
+    for (index, (name, kind, start, size, aux)) in entries.iter().enumerate()
+    {
+        let entry = &mut table[TABLE_HEADER_SIZE + index * ENTRY_SIZE..][..ENTRY_SIZE];
+        entry[..name.len()].copy_from_slice(name);
+        entry[16] = *kind;
+        entry[20..24].copy_from_slice(&start.to_le_bytes());
+        entry[24..28].copy_from_slice(&size.to_le_bytes());
+        entry[28..32].copy_from_slice(&aux.to_le_bytes());
+    }
+
+    let len = TABLE_HEADER_SIZE + entries.len() * ENTRY_SIZE + 4;
+    let mut engine = Crc32Table::new();
+    engine.update(&table[..len - 4]);
+    let crc = engine.finish();
+    table[len - 4..len].copy_from_slice(&crc.to_le_bytes());
+    copy_to_flasher(fl, address, &table[..len]);
+
+    let mut engine = Crc32Table::new();
+    engine.update(&table[..len]);
+    (len, engine.finish())
+}
+
+/// Signature TLV of the image written by `write_mcuboot_image`.
+pub const TEST_MCUBOOT_SIGNATURE: [u8; 2] = [0x5A, 0x5A];
+
+/// Writes the test image as MCUboot image with the given version to
+/// `address`, with a protected TLV and a signature. Yields its size.
+pub fn write_mcuboot_image(fl: &mut FakeFlasher, address: usize, version: (u8, u8, u16)) -> usize
+{
+    write_mcuboot_image_for_board(fl, address, version, None)
+}
+
+/// Like `write_mcuboot_image`, with a board id TLV in the protected area if
+/// `board_id` is set.
+pub fn write_mcuboot_image_for_board(fl: &mut FakeFlasher, address: usize, version: (u8, u8, u16), board_id: Option<u32>) -> usize
+{
+    // Protected area holding a security counter TLV and the board id
+    let mut protected = [0u8; 16];
+    protected[0..8].copy_from_slice(&[0x08, 0x69, 0x08, 0x00, 0x50, 0x00, 0x00, 0x00]);
+    let protected_len = match board_id
+    {
+        Some(board_id) =>
+        {
+            protected[2] = 16;
+            protected[8..12].copy_from_slice(&[mcuboot::TLV_BOARD_ID as u8, 0x00, 0x04, 0x00]);
+            protected[12..16].copy_from_slice(&board_id.to_le_bytes());
+            16
+        }
+        None => 8
+    };
+
+    let mut image = [0u8; 0x20 + 128 + 16 + 4 + 36 + 6];
+    image[0..4].copy_from_slice(&mcuboot::IMAGE_MAGIC.to_le_bytes());
+    image[8..10].copy_from_slice(&0x20u16.to_le_bytes());
+    image[10..12].copy_from_slice(&(protected_len as u16).to_le_bytes());
+    image[12..16].copy_from_slice(&128u32.to_le_bytes());
+    image[20] = version.0;
+    image[21] = version.1;
+    image[22..24].copy_from_slice(&version.2.to_le_bytes());
+    image[0x20..0xA0].copy_from_slice(&test_image());
+    image[0xA0..0xA0 + protected_len].copy_from_slice(&protected[..protected_len]);
+
+    let tlv = 0xA0 + protected_len;
+    let mut sha = Sha256::new();
+    sha.update(&image[..tlv]);
+    image[tlv..tlv + 4].copy_from_slice(&[0x07, 0x69, 4 + 36 + 6, 0x00]);
+    image[tlv + 4..tlv + 8].copy_from_slice(&[0x10, 0x00, 0x20, 0x00]);
+    image[tlv + 8..tlv + 40].copy_from_slice(&sha.finish());
+    image[tlv + 40..tlv + 44].copy_from_slice(&[0x22, 0x00, 0x02, 0x00]);
+    image[tlv + 44..tlv + 46].copy_from_slice(&TEST_MCUBOOT_SIGNATURE);
+
+    let len = tlv + 46;
+    copy_to_flasher(fl, address, &image[..len]);
+    len
+}
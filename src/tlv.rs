@@ -0,0 +1,335 @@
+//! Type-length-value metadata area.
+//!
+//! The metadata area directly follows the fixed `bin_info` or `update_info`
+//! header in flash (i.e. it starts at `info_address + size_of::<info>()`).
+//! Layout (little endian):
+//!
+//! ```text
+//! | 'M' | 'T' | area_len: u16 | tag: u16 | len: u16 | value[len] | tag ... |
+//! ```
+//!
+//! `area_len` counts the bytes of all entries following the area header.
+//! Readers must skip entries with tags they don't know, which allows new
+//! fields to be added without bumping `struct_ver`.
+
+use super::{Flasher, ReadError, bin_info, update_info};
+
+pub const TLV_MAGIC: [u8; 2] = *b"MT";
+pub const TLV_AREA_HEADER_SIZE: usize = 4;
+pub const TLV_ENTRY_HEADER_SIZE: usize = 4;
+
+/// Upper bound for the entries of a single area, protects against
+/// scanning the whole flash if the length field is garbage.
+pub const TLV_MAX_AREA_LEN: usize = 0x400;
+
+pub const TAG_IMAGE_VERSION: u16 = 0x0001;
+pub const TAG_BUILD_ID: u16 = 0x0002;
+pub const TAG_GIT_HASH: u16 = 0x0003;
+pub const TAG_BOARD_ID: u16 = 0x0004;
+pub const TAG_SIGNATURE: u16 = 0x0005;
+pub const TAG_DEPENDENCY: u16 = 0x0006;
+pub const TAG_FLAGS: u16 = 0x0007;
+pub const TAG_DIGEST: u16 = 0x0008;
+pub const TAG_ENCODING: u16 = 0x0009;
+pub const TAG_DELTA_BASE: u16 = 0x000A;
+pub const TAG_COMPONENT: u16 = 0x000B;
+pub const TAG_PARTITION_NAME: u16 = 0x000C;
+
+/// Values of TAG_ENCODING, see `UpdateEncoding`.
+pub const ENCODING_RAW: u32 = 0;
+pub const ENCODING_LZMA: u32 = 1;
+pub const ENCODING_DELTA: u32 = 2;
+pub const ENCODING_SUIT: u32 = 3;
+
+/// Set in the value of TAG_FLAGS if the image may be installed even though
+/// its version is below the persisted minimum version. Only honored for
+/// images that carry a valid signature.
+pub const FLAG_DOWNGRADE_PERMITTED: u32 = 0x0000_0001;
+/// Set in the value of TAG_FLAGS if the image is a loader, see `selfupdate`.
+pub const FLAG_LOADER_UPDATE: u32 = 0x0000_0002;
+/// Set in the value of TAG_FLAGS if the image is a partition table, see
+/// `partition_table`. Only honored for images that carry a valid signature.
+pub const FLAG_PARTITION_TABLE: u32 = 0x0000_0004;
+
+/// Packs a semantic version into the u32 stored with TAG_IMAGE_VERSION,
+/// such that newer versions always compare greater.
+pub const fn image_version(major: u8, minor: u8, patch: u16) -> u32
+{
+    (major as u32) << 24 | (minor as u32) << 16 | patch as u32
+}
+
+/// A single entry of a metadata area located in flash. The value is
+/// not copied, use `read_value` to fetch it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TlvEntry
+{
+    pub tag: u16,
+    pub value_address: usize,
+    pub len: usize
+}
+
+impl TlvEntry
+{
+    pub fn read_value<T>(&self, flasher: &T, destination: &mut [u8]) -> Result<usize, ReadError>
+        where T: Flasher
+    {
+        if destination.len() < self.len
+        {
+            return Err(ReadError::EndAddressOutOfRange);
+        }
+        flasher.read(self.value_address, &mut destination[..self.len])
+    }
+
+    /// Reads a value that consists of a single little endian u32.
+    pub fn read_u32<T>(&self, flasher: &T) -> Option<u32>
+        where T: Flasher
+    {
+        if self.len != 4
+        {
+            return None;
+        }
+        let mut buf = [0u8; 4];
+        match self.read_value(flasher, &mut buf)
+        {
+            Ok(4) => Some(u32::from_le_bytes(buf)),
+            _ => None
+        }
+    }
+}
+
+fn u16_from_le(data: &[u8]) -> u16
+{
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+/// Decodes a single little endian u32 value as stored in an entry.
+pub fn u32_value(value: &[u8]) -> Option<u32>
+{
+    if value.len() != 4
+    {
+        return None;
+    }
+    Some(u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+}
+
+/// Iterates the entries of a metadata area stored in flash.
+pub struct TlvIter<'a, T: Flasher>
+{
+    flasher: &'a T,
+    next: usize,
+    end: usize
+}
+
+impl <'a, T: Flasher> TlvIter<'a, T>
+{
+    /// Yields the first entry with the given tag, skipping all others.
+    pub fn find(mut self, tag: u16) -> Option<TlvEntry>
+    {
+        self.by_ref().find(|entry| entry.tag == tag)
+    }
+}
+
+impl <'a, T: Flasher> Iterator for TlvIter<'a, T>
+{
+    type Item = TlvEntry;
+
+    fn next(&mut self) -> Option<TlvEntry>
+    {
+        if self.next + TLV_ENTRY_HEADER_SIZE > self.end
+        {
+            return None;
+        }
+
+        let mut header = [0u8; TLV_ENTRY_HEADER_SIZE];
+        match self.flasher.read(self.next, &mut header)
+        {
+            Ok(TLV_ENTRY_HEADER_SIZE) => {},
+            _ =>
+            {
+                self.next = self.end;
+                return None;
+            }
+        }
+
+        let tag = u16_from_le(&header[0..2]);
+        let len = u16_from_le(&header[2..4]) as usize;
+        let value_address = self.next + TLV_ENTRY_HEADER_SIZE;
+
+        if value_address + len > self.end
+        {
+            // Entry exceeds the area, the rest of the area can't be trusted.
+            self.next = self.end;
+            return None;
+        }
+
+        self.next = value_address + len;
+        Some(TlvEntry { tag, value_address, len })
+    }
+}
+
+/// Opens the metadata area at the given address. Yields None if there is
+/// no (valid) area, which is the case for all images built without metadata.
+pub fn read_area<T>(address: usize, flasher: &T) -> Option<TlvIter<'_, T>>
+    where T: Flasher
+{
+    let mut header = [0u8; TLV_AREA_HEADER_SIZE];
+    match flasher.read(address, &mut header)
+    {
+        Ok(TLV_AREA_HEADER_SIZE) => {},
+        _ => return None
+    }
+
+    if header[0..2] != TLV_MAGIC
+    {
+        return None;
+    }
+
+    let area_len = u16_from_le(&header[2..4]) as usize;
+    if area_len > TLV_MAX_AREA_LEN
+    {
+        return None;
+    }
+
+    let start = address + TLV_AREA_HEADER_SIZE;
+    Some(TlvIter { flasher, next: start, end: start + area_len })
+}
+
+/// Yields the size (including the area header) of the metadata area at
+/// the given address.
+pub fn stored_area_size<T>(address: usize, flasher: &T) -> Option<usize>
+    where T: Flasher
+{
+    read_area(address, flasher).map(|area| area.end - address)
+}
+
+/// Opens the metadata area trailing the bin_info struct at `bin_info_address`.
+pub fn bin_info_metadata<T>(bin_info_address: usize, flasher: &T) -> Option<TlvIter<'_, T>>
+    where T: Flasher
+{
+    read_area(bin_info_address + core::mem::size_of::<bin_info>(), flasher)
+}
+
+/// Opens the metadata area trailing the update_info struct at `update_info_address`.
+pub fn update_info_metadata<T>(update_info_address: usize, flasher: &T) -> Option<TlvIter<'_, T>>
+    where T: Flasher
+{
+    read_area(update_info_address + core::mem::size_of::<update_info>(), flasher)
+}
+
+/// Iterates the entries of a metadata area held in RAM (e.g. received
+/// with an INIT packet or read from memory mapped flash by the application).
+pub struct TlvSliceIter<'a>
+{
+    data: &'a [u8]
+}
+
+impl <'a> TlvSliceIter<'a>
+{
+    pub fn find(mut self, tag: u16) -> Option<&'a [u8]>
+    {
+        self.by_ref().find(|(t, _)| *t == tag).map(|(_, value)| value)
+    }
+}
+
+impl <'a> Iterator for TlvSliceIter<'a>
+{
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        if self.data.len() < TLV_ENTRY_HEADER_SIZE
+        {
+            return None;
+        }
+
+        let tag = u16_from_le(&self.data[0..2]);
+        let len = u16_from_le(&self.data[2..4]) as usize;
+        let rest = &self.data[TLV_ENTRY_HEADER_SIZE..];
+
+        if len > rest.len()
+        {
+            self.data = &[];
+            return None;
+        }
+
+        self.data = &rest[len..];
+        Some((tag, &rest[..len]))
+    }
+}
+
+/// Returns the size of the area (including the area header) at the start of
+/// `data`, or None if `data` does not start with a complete area.
+pub fn area_size(data: &[u8]) -> Option<usize>
+{
+    if data.len() < TLV_AREA_HEADER_SIZE || data[0..2] != TLV_MAGIC
+    {
+        return None;
+    }
+
+    let area_len = u16_from_le(&data[2..4]) as usize;
+    if area_len > TLV_MAX_AREA_LEN || TLV_AREA_HEADER_SIZE + area_len > data.len()
+    {
+        return None;
+    }
+    Some(TLV_AREA_HEADER_SIZE + area_len)
+}
+
+/// Opens the metadata area at the start of `data`.
+pub fn parse_area(data: &[u8]) -> Option<TlvSliceIter<'_>>
+{
+    let size = area_size(data)?;
+    Some(TlvSliceIter { data: &data[TLV_AREA_HEADER_SIZE..size] })
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use super::*;
+
+    const AREA: [u8; 22] = [b'M', b'T', 18, 0,
+                            0x01, 0x00, 4, 0, 0x03, 0x02, 0x01, 0x00,  // image version
+                            0x77, 0x77, 2, 0, 0xAA, 0xBB,              // unknown tag
+                            0x04, 0x00, 0, 0];                         // empty board id
+
+    #[test]
+    fn can_iterate_area_in_flash()
+    {
+        let mut fl = FakeFlasher::new();
+        copy_to_flasher(&mut fl, 0x100, &AREA);
+
+        let entries: [Option<TlvEntry>; 4] = {
+            let mut it = read_area(0x100, &fl).unwrap();
+            [it.next(), it.next(), it.next(), it.next()]
+        };
+
+        assert_eq!(entries[0], Some(TlvEntry { tag: TAG_IMAGE_VERSION, value_address: 0x108, len: 4 }));
+        assert_eq!(entries[1], Some(TlvEntry { tag: 0x7777, value_address: 0x110, len: 2 }));
+        assert_eq!(entries[2], Some(TlvEntry { tag: TAG_BOARD_ID, value_address: 0x116, len: 0 }));
+        assert_eq!(entries[3], None);
+    }
+
+    #[test]
+    fn find_skips_unknown_tags()
+    {
+        let mut fl = FakeFlasher::new();
+        copy_to_flasher(&mut fl, 0x100, &AREA);
+
+        let version = read_area(0x100, &fl).unwrap().find(TAG_IMAGE_VERSION).unwrap();
+        assert_eq!(version.read_u32(&fl), Some(0x00010203));
+        assert!(read_area(0x100, &fl).unwrap().find(TAG_SIGNATURE).is_none());
+        assert_eq!(parse_area(&AREA).unwrap().find(TAG_BOARD_ID), Some(&[][..]));
+    }
+
+    #[test]
+    fn missing_or_truncated_area_yields_nothing()
+    {
+        let mut fl = FakeFlasher::new();
+        assert!(read_area(0x100, &fl).is_none());
+
+        // Entry claims more bytes than the area holds:
+        copy_to_flasher(&mut fl, 0x100, &[b'M', b'T', 6, 0, 0x01, 0x00, 20, 0, 0xAA, 0xBB]);
+        assert!(read_area(0x100, &fl).unwrap().next().is_none());
+        assert!(parse_area(&[b'M', b'T', 6, 0, 0x01, 0x00, 20, 0, 0xAA, 0xBB]).unwrap().next().is_none());
+    }
+}
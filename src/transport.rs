@@ -0,0 +1,279 @@
+//! The byte stream the receivers talk over.
+//!
+//! All receivers (and `muload_main`) are generic over `Transport`, so the
+//! download protocols run over anything that moves bytes: a UART, USB CDC,
+//! an SPI or I2C slave, RTT, or a socket in a host side test. Adapters are
+//! provided for `embedded_hal::serial` (`SerialTransport`) and, with the
+//! `embedded-io` feature, for `embedded_io` (`IoTransport`).
+//!
+//! Receivers call `flush` after each complete response. Stream transports
+//! can ignore it, frame based transports (e.g. USB bulk endpoints) send
+//! the buffered response as one frame.
+
+use embedded_hal::serial;
+
+pub trait Transport
+{
+    /// Yields the next received byte, None if nothing arrived (yet). Must
+    /// not block, the receivers poll.
+    fn read_byte(&mut self) -> Option<u8>;
+
+    fn write_byte(&mut self, byte: u8);
+
+    fn write_all(&mut self, bytes: &[u8])
+    {
+        for byte in bytes
+        {
+            self.write_byte(*byte);
+        }
+    }
+
+    /// Marks the end of a response.
+    fn flush(&mut self)
+    {
+    }
+}
+
+/// Runs the receivers over an `embedded_hal` serial port.
+pub struct SerialTransport<U>
+{
+    serial: U
+}
+
+impl<U> SerialTransport<U>
+    where U: serial::Read<u8> + serial::Write<u8>
+{
+    pub fn new(serial: U) -> Self
+    {
+        Self { serial }
+    }
+
+    pub fn release(self) -> U
+    {
+        self.serial
+    }
+}
+
+impl<U> Transport for SerialTransport<U>
+    where U: serial::Read<u8> + serial::Write<u8>
+{
+    fn read_byte(&mut self) -> Option<u8>
+    {
+        self.serial.read().ok()
+    }
+
+    fn write_byte(&mut self, byte: u8)
+    {
+        // A broken port can't be reported to anyone, the host times out.
+        let _ = nb::block!(self.serial.write(byte));
+    }
+
+    fn flush(&mut self)
+    {
+        let _ = nb::block!(self.serial.flush());
+    }
+}
+
+/// Runs the receivers over an `embedded_io` port, `ReadReady` keeps reads
+/// from blocking.
+#[cfg(feature = "embedded-io")]
+pub struct IoTransport<T>
+{
+    io: T
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T> IoTransport<T>
+    where T: embedded_io::Read + embedded_io::Write + embedded_io::ReadReady
+{
+    pub fn new(io: T) -> Self
+    {
+        Self { io }
+    }
+
+    pub fn release(self) -> T
+    {
+        self.io
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T> Transport for IoTransport<T>
+    where T: embedded_io::Read + embedded_io::Write + embedded_io::ReadReady
+{
+    fn read_byte(&mut self) -> Option<u8>
+    {
+        let mut byte = [0u8; 1];
+        match self.io.read_ready()
+        {
+            Ok(true) if matches!(self.io.read(&mut byte), Ok(1)) => Some(byte[0]),
+            _ => None
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8)
+    {
+        let _ = self.io.write_all(&[byte]);
+    }
+
+    fn write_all(&mut self, bytes: &[u8])
+    {
+        let _ = self.io.write_all(bytes);
+    }
+
+    fn flush(&mut self)
+    {
+        let _ = self.io.flush();
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use super::*;
+
+    #[test]
+    fn serial_transport_moves_bytes()
+    {
+        let mut uart = FakeUart::new();
+        copy_to_uart(&mut uart, &[0x02, 0x04]);
+        let mut transport = SerialTransport::new(uart);
+        assert_eq!(transport.read_byte(), Some(0x02));
+        assert_eq!(transport.read_byte(), Some(0x04));
+        transport.write_all(&[0x06, 0x15]);
+        transport.flush();
+
+        let uart = transport.release();
+        assert_eq!(uart.out_buf[..uart.write_index], [0x06, 0x15]);
+    }
+
+    /// In-memory `embedded_io` port, takes at most two bytes per write.
+    #[cfg(feature = "embedded-io")]
+    struct IoStub
+    {
+        input: [u8; 8],
+        input_len: usize,
+        read_index: usize,
+        /// Makes read_ready fail.
+        broken: bool,
+        output: [u8; 16],
+        write_index: usize,
+        failing_writes: bool,
+        flushes: usize
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl IoStub
+    {
+        fn new(input: &[u8]) -> Self
+        {
+            let mut stub = Self { input: [0; 8], input_len: input.len(), read_index: 0, broken: false, output: [0; 16], write_index: 0, failing_writes: false, flushes: 0 };
+            stub.input[..input.len()].copy_from_slice(input);
+            stub
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::ErrorType for IoStub
+    {
+        type Error = embedded_io::ErrorKind;
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::ReadReady for IoStub
+    {
+        fn read_ready(&mut self) -> Result<bool, Self::Error>
+        {
+            match self.broken
+            {
+                true => Err(embedded_io::ErrorKind::Other),
+                false => Ok(self.read_index < self.input_len)
+            }
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::Read for IoStub
+    {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>
+        {
+            // Blocks on a real port, the transport must not get here.
+            assert!(self.read_index < self.input_len);
+            buf[0] = self.input[self.read_index];
+            self.read_index += 1;
+            Ok(1)
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::Write for IoStub
+    {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error>
+        {
+            if self.failing_writes
+            {
+                return Err(embedded_io::ErrorKind::Other);
+            }
+            let len = core::cmp::min(buf.len(), 2);
+            self.output[self.write_index..self.write_index + len].copy_from_slice(&buf[..len]);
+            self.write_index += len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error>
+        {
+            self.flushes += 1;
+            match self.failing_writes
+            {
+                true => Err(embedded_io::ErrorKind::Other),
+                false => Ok(())
+            }
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    #[test]
+    fn io_transport_moves_bytes()
+    {
+        let mut transport = IoTransport::new(IoStub::new(&[0x02, 0x04, 0x03]));
+        assert_eq!(transport.read_byte(), Some(0x02));
+        assert_eq!(transport.read_byte(), Some(0x04));
+        assert_eq!(transport.read_byte(), Some(0x03));
+        // Nothing left, the read times out instead of blocking.
+        assert_eq!(transport.read_byte(), None);
+        assert_eq!(transport.read_byte(), None);
+
+        // Partial writes keep the order.
+        transport.write_byte(0x06);
+        transport.write_all(&[0x02, 0x11, 0x22, 0x33, 0x03]);
+        transport.flush();
+        let mut stub = transport.release();
+        assert_eq!(stub.output[..stub.write_index], [0x06, 0x02, 0x11, 0x22, 0x33, 0x03]);
+        assert_eq!(stub.flushes, 1);
+
+        // A broken port reads nothing.
+        stub.broken = true;
+        stub.read_index = 0;
+        let mut transport = IoTransport::new(stub);
+        assert_eq!(transport.read_byte(), None);
+
+        // Write errors are dropped, the host times out. Later writes go
+        // through again.
+        let mut stub = transport.release();
+        stub.failing_writes = true;
+        let mut transport = IoTransport::new(stub);
+        transport.write_byte(0x15);
+        transport.write_all(&[0x15, 0x15, 0x15]);
+        transport.flush();
+        let mut stub = transport.release();
+        assert_eq!(stub.write_index, 6);
+        assert_eq!(stub.flushes, 2);
+
+        stub.failing_writes = false;
+        let mut transport = IoTransport::new(stub);
+        transport.write_byte(0x06);
+        let stub = transport.release();
+        assert_eq!(stub.output[..stub.write_index], [0x06, 0x02, 0x11, 0x22, 0x33, 0x03, 0x06]);
+    }
+}
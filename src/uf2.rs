@@ -0,0 +1,396 @@
+//! UF2 files, as used for drag-and-drop flashing, received block by block
+//! over the UART.
+//!
+//! ```text
+//! | magic0: u32 | magic1: u32 | flags: u32 | target_addr: u32 | payload_size: u32 |
+//! | block_no: u32 | num_blocks: u32 | file_size or family_id: u32 | data (476 bytes) | magic_end: u32 |
+//! ```
+//!
+//! All fields are little endian, every block is 512 bytes. The receiver
+//! answers every block with ACK (0x06), or with NAK (0x15) if it was
+//! rejected: bad magic, a payload beyond the target slot or, if the loader
+//! has a family id, a block of another family. Blocks may arrive in any
+//! order and more than once. The payload is written to the staging area
+//! (target_addr is the address in the target slot). Once all blocks of
+//! the file arrived, the CRC-32 of the staged image is calculated and the
+//! update_info is written, which installs the image to the target slot.
+//! Gaps between blocks keep whatever the staging area held.
+//!
+//! The family id of the file is stored as board id of the update, so ports
+//! that check board ids use their family id as board id. All blocks of a
+//! file have to carry the same family id, files without one get no board
+//! id.
+
+use super::{Flasher, StagingLayout, WriteOptions, crc};
+use super::crc::Checksum;
+use super::transport::Transport;
+
+pub const BLOCK_SIZE: usize = 512;
+pub const MAGIC_START0: u32 = 0x0A32_4655;
+pub const MAGIC_START1: u32 = 0x9E5D_5157;
+pub const MAGIC_END: u32 = 0x0AB1_6F30;
+/// Largest payload of a block.
+pub const MAX_PAYLOAD_SIZE: usize = 476;
+
+/// The block is not meant for main flash, e.g. comments.
+pub const FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+pub const FLAG_FILE_CONTAINER: u32 = 0x0000_1000;
+pub const FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+
+/// Largest number of blocks in a file, 2 MiB of payload with the usual
+/// 256 bytes per block.
+pub const MAX_BLOCKS: usize = 8192;
+
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Block<'a>
+{
+    pub flags: u32,
+    pub target_addr: usize,
+    pub block_no: usize,
+    pub num_blocks: usize,
+    /// Family id if FLAG_FAMILY_ID_PRESENT is set, otherwise the file size
+    /// (or 0).
+    pub family_id: u32,
+    pub data: &'a [u8]
+}
+
+impl<'a> Block<'a>
+{
+    /// Checks the magic numbers and the payload size.
+    pub fn parse(bytes: &'a [u8; BLOCK_SIZE]) -> Option<Self>
+    {
+        let word = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        if word(0) != MAGIC_START0 || word(4) != MAGIC_START1 || word(BLOCK_SIZE - 4) != MAGIC_END
+        {
+            return None;
+        }
+        let payload_size = word(16) as usize;
+        let block = Self
+        {
+            flags: word(8),
+            target_addr: word(12) as usize,
+            block_no: word(20) as usize,
+            num_blocks: word(24) as usize,
+            family_id: word(28),
+            data: bytes.get(32..32 + payload_size)?
+        };
+        if payload_size > MAX_PAYLOAD_SIZE || block.block_no >= block.num_blocks
+        {
+            return None;
+        }
+        Some(block)
+    }
+
+    /// Whether the block belongs to `family_id`. Blocks without a family
+    /// id belong to every family.
+    pub fn is_family(&self, family_id: u32) -> bool
+    {
+        self.flags & FLAG_FAMILY_ID_PRESENT == 0 || self.family_id == family_id
+    }
+}
+
+/// Remembers which blocks of a file arrived.
+struct BlockTracker
+{
+    received: [u32; MAX_BLOCKS / 32],
+    num_blocks: usize,
+    count: usize
+}
+
+impl BlockTracker
+{
+    fn new() -> Self
+    {
+        Self { received: [0; MAX_BLOCKS / 32], num_blocks: 0, count: 0 }
+    }
+
+    fn reset(&mut self, num_blocks: usize)
+    {
+        self.received = [0; MAX_BLOCKS / 32];
+        self.num_blocks = num_blocks;
+        self.count = 0;
+    }
+
+    fn mark(&mut self, block_no: usize)
+    {
+        let (word, bit) = (block_no / 32, 1 << (block_no % 32));
+        if self.received[word] & bit == 0
+        {
+            self.received[word] |= bit;
+            self.count += 1;
+        }
+    }
+
+    fn is_complete(&self) -> bool
+    {
+        self.num_blocks > 0 && self.count == self.num_blocks
+    }
+}
+
+pub struct Uf2Receiver<'a, T: Flasher, U: Transport, C: Checksum>
+{
+    flasher: &'a mut T,
+    uart: &'a mut U,
+    checksum: &'a mut C,
+    layout: StagingLayout,
+    family_id: Option<u32>,
+    write_options: WriteOptions,
+    blocks: BlockTracker,
+    /// Family id of the file being received.
+    file_family_id: Option<u32>,
+    /// End of the highest payload written, relative to the staging area.
+    len: usize
+}
+
+impl <'a, T: Flasher, U: Transport, C: Checksum> Uf2Receiver<'a, T, U, C>
+{
+    pub fn new(flasher: &'a mut T, uart: &'a mut U, checksum: &'a mut C, layout: StagingLayout) -> Self
+    {
+        Self
+        {
+            flasher,
+            uart,
+            checksum,
+            layout,
+            family_id: None,
+            write_options: WriteOptions::default(),
+            blocks: BlockTracker::new(),
+            file_family_id: None,
+            len: 0
+        }
+    }
+
+    /// Blocks of other families are rejected.
+    pub fn with_family_id(mut self, family_id: u32) -> Self
+    {
+        self.family_id = Some(family_id);
+        self
+    }
+
+    pub fn with_write_options(mut self, write_options: WriteOptions) -> Self
+    {
+        self.write_options = write_options;
+        self
+    }
+
+    /// Receives blocks until a file is complete and its update_info was
+    /// written.
+    pub fn execute(mut self, update_info_address: usize)
+    {
+        let mut bytes = [0u8; BLOCK_SIZE];
+        loop
+        {
+            self.receive_block(&mut bytes);
+            let accepted = Block::parse(&bytes).map(|block| self.handle_block(&block)).unwrap_or(false);
+            if accepted && self.blocks.is_complete()
+            {
+                if self.finish(update_info_address)
+                {
+                    self.respond(ACK);
+                    return;
+                }
+                // Staging failed, the host has to send the file again.
+                self.blocks.reset(0);
+                self.respond(NAK);
+                continue;
+            }
+            self.respond(if accepted { ACK } else { NAK });
+        }
+    }
+
+    fn respond(&mut self, response: u8)
+    {
+        self.uart.write_byte(response);
+        self.uart.flush();
+    }
+
+    fn get_byte(&mut self) -> u8
+    {
+        loop
+        {
+            if let Some(byte) = self.uart.read_byte()
+            {
+                return byte;
+            }
+        }
+    }
+
+    /// Receives the next 512 bytes starting with the first magic number,
+    /// anything in front of it is dropped.
+    fn receive_block(&mut self, bytes: &mut [u8; BLOCK_SIZE])
+    {
+        let magic = MAGIC_START0.to_le_bytes();
+        let mut len = 0;
+        while len < BLOCK_SIZE
+        {
+            let byte = self.get_byte();
+            if len < magic.len() && byte != magic[len]
+            {
+                len = if byte == magic[0] { 1 } else { 0 };
+                continue;
+            }
+            bytes[len] = byte;
+            len += 1;
+        }
+    }
+
+    /// Yields whether the block was accepted.
+    fn handle_block(&mut self, block: &Block) -> bool
+    {
+        if self.family_id.map(|family_id| !block.is_family(family_id)).unwrap_or(false) || block.num_blocks > MAX_BLOCKS
+        {
+            return false;
+        }
+
+        let offset = block.target_addr.wrapping_sub(self.layout.target);
+        if block.flags & FLAG_NOT_MAIN_FLASH == 0 && (block.target_addr < self.layout.target || offset + block.data.len() > self.layout.slot_size)
+        {
+            return false;
+        }
+
+        if block.num_blocks != self.blocks.num_blocks
+        {
+            // A new file.
+            self.blocks.reset(block.num_blocks);
+            self.len = 0;
+            self.file_family_id = None;
+        }
+
+        if block.flags & FLAG_FAMILY_ID_PRESENT != 0
+        {
+            if self.file_family_id.is_some_and(|family_id| family_id != block.family_id)
+            {
+                return false;
+            }
+            self.file_family_id = Some(block.family_id);
+        }
+
+        if block.flags & FLAG_NOT_MAIN_FLASH == 0
+        {
+            if super::write_chunk(self.flasher, self.layout.staging + offset, block.data, &self.write_options).is_err()
+            {
+                return false;
+            }
+            self.len = core::cmp::max(self.len, offset + block.data.len());
+        }
+        self.blocks.mark(block.block_no);
+        true
+    }
+
+    fn finish(&mut self, update_info_address: usize) -> bool
+    {
+        self.flasher.flush();
+        if self.len == 0
+        {
+            return false;
+        }
+
+        self.checksum.reset();
+        let checksum = &mut self.checksum;
+        if !crc::read_chunks(self.layout.staging, self.len, self.flasher, |chunk| checksum.update(chunk))
+        {
+            return false;
+        }
+        self.layout.write_update_info(update_info_address, self.len, self.checksum.finish(), self.file_family_id, self.flasher).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use crate::crc::Crc32Table;
+    use super::*;
+
+    const FAMILY_ID: u32 = 0xE48B_FF56;
+
+    fn make_block(flags: u32, target_addr: u32, block_no: u32, num_blocks: u32, family_id: u32, data: &[u8]) -> [u8; BLOCK_SIZE]
+    {
+        let mut block = [0u8; BLOCK_SIZE];
+        for (index, word) in [MAGIC_START0, MAGIC_START1, flags, target_addr, data.len() as u32, block_no, num_blocks, family_id].iter().enumerate()
+        {
+            block[index * 4..index * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        block[32..32 + data.len()].copy_from_slice(data);
+        block[BLOCK_SIZE - 4..].copy_from_slice(&MAGIC_END.to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn can_parse_blocks()
+    {
+        let image = test_image();
+        let bytes = make_block(FLAG_FAMILY_ID_PRESENT, 0x4000, 1, 2, FAMILY_ID, &image);
+        let block = Block::parse(&bytes).unwrap();
+        assert_eq!((block.target_addr, block.block_no, block.num_blocks), (0x4000, 1, 2));
+        assert!(block.data == image);
+        assert!(block.is_family(FAMILY_ID));
+        assert!(!block.is_family(0x1234_5678));
+
+        // Block number beyond the file
+        assert_eq!(Block::parse(&make_block(0, 0x4000, 2, 2, 0, &image)), None);
+        let mut bytes = make_block(0, 0x4000, 0, 1, 0, &image);
+        bytes[BLOCK_SIZE - 1] ^= 0x01;
+        assert_eq!(Block::parse(&bytes), None);
+    }
+
+    #[test]
+    fn can_receive_blocks_out_of_order()
+    {
+        let layout = StagingLayout { staging: 0x2000, target: 0x4000, slot_size: 0x1000 };
+        let image = test_image();
+        let mut fl = FakeFlasher::new();
+        let mut uart = FakeUart::new();
+        // Garbage in front of the first block is dropped.
+        copy_to_uart(&mut uart, &[0x55, 0x46]);
+        copy_to_uart(&mut uart, &make_block(FLAG_FAMILY_ID_PRESENT, 0x4000 + 128, 1, 2, FAMILY_ID, &image[..64]));
+        copy_to_uart(&mut uart, &make_block(FLAG_FAMILY_ID_PRESENT, 0x4000, 0, 2, 0x1234_5678, &image));
+        copy_to_uart(&mut uart, &make_block(FLAG_FAMILY_ID_PRESENT, 0x4000, 0, 2, FAMILY_ID, &image));
+
+        let mut crc = Crc32Table::new();
+        Uf2Receiver::new(&mut fl, &mut uart, &mut crc, layout).with_family_id(FAMILY_ID).execute(0x1000);
+
+        assert_eq!(&uart.out_buf[..uart.write_index], &[ACK, NAK, ACK]);
+        assert!(fl.memory[0x2000..0x2080] == image);
+        assert!(fl.memory[0x2080..0x20C0] == image[..64]);
+
+        let info: crate::update_info = crate::load_info_struct_from_address(0x1000, &fl).unwrap();
+        assert_eq!((info.update_start, info.update_len, info.target_adress), (0x2000, 0xC0, 0x4000));
+        let mut engine = Crc32Table::new();
+        engine.update(&fl.memory[0x2000..0x20C0]);
+        assert_eq!(info.checksum, engine.finish() as usize);
+        let board_id = crate::tlv::update_info_metadata(0x1000, &fl).unwrap().find(crate::tlv::TAG_BOARD_ID).unwrap();
+        assert_eq!(board_id.read_u32(&fl), Some(FAMILY_ID));
+    }
+
+    #[test]
+    fn files_keep_to_one_family()
+    {
+        let layout = StagingLayout { staging: 0x2000, target: 0x4000, slot_size: 0x1000 };
+        let image = test_image();
+        let mut fl = FakeFlasher::new();
+        let mut uart = FakeUart::new();
+        copy_to_uart(&mut uart, &make_block(FLAG_FAMILY_ID_PRESENT, 0x4000, 0, 2, FAMILY_ID, &image));
+        copy_to_uart(&mut uart, &make_block(FLAG_FAMILY_ID_PRESENT, 0x4080, 1, 2, 0x1234_5678, &image));
+        copy_to_uart(&mut uart, &make_block(FLAG_FAMILY_ID_PRESENT, 0x4080, 1, 2, FAMILY_ID, &image));
+        // Without family ids there is no board id.
+        copy_to_uart(&mut uart, &make_block(0, 0x4000, 0, 1, 0, &image));
+
+        let mut crc = Crc32Table::new();
+        Uf2Receiver::new(&mut fl, &mut uart, &mut crc, layout).execute(0x1000);
+        assert_eq!(&uart.out_buf[..uart.write_index], &[ACK, NAK, ACK]);
+        let board_id = crate::tlv::update_info_metadata(0x1000, &fl).unwrap().find(crate::tlv::TAG_BOARD_ID).unwrap();
+        assert_eq!(board_id.read_u32(&fl), Some(FAMILY_ID));
+
+        let mut fl = FakeFlasher::new();
+        let mut crc = Crc32Table::new();
+        uart.write_index = 0;
+        Uf2Receiver::new(&mut fl, &mut uart, &mut crc, layout).execute(0x1000);
+        assert_eq!(&uart.out_buf[..uart.write_index], &[ACK]);
+        assert!(fl.memory[0x1000..0x1005] == *b"MUUPD");
+        assert!(crate::tlv::update_info_metadata(0x1000, &fl).is_none());
+    }
+}
@@ -0,0 +1,134 @@
+//! XMODEM-CRC, XMODEM-1K and YMODEM framing, for hosts that only have a
+//! terminal program.
+//!
+//! ```text
+//! | SOH (128 bytes) or STX (1024 bytes) | block: u8 | !block: u8 | data | crc: u16 |
+//! ```
+//!
+//! crc is the CRC-16/XMODEM of the data (big endian). The receiver asks for
+//! CRC mode by sending 'C', the sender starts with block 1 (XMODEM) or with
+//! the YMODEM header in block 0 (file name, NUL, size in decimal). EOT ends
+//! a file, YMODEM ends the batch with an empty block 0.
+//!
+//! This module only receives blocks, `ImageReceiver::execute_xmodem` turns
+//! them into an update.
+
+use super::crc;
+use super::transport::Transport;
+
+pub const SOH: u8 = 0x01;
+pub const STX: u8 = 0x02;
+pub const EOT: u8 = 0x04;
+pub const ACK: u8 = 0x06;
+pub const NAK: u8 = 0x15;
+pub const CAN: u8 = 0x18;
+/// Sent by the receiver to request a transfer in CRC mode.
+pub const CRC_REQUEST: u8 = b'C';
+
+pub const BLOCK_SIZE: usize = 128;
+pub const BLOCK_SIZE_1K: usize = 1024;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Block
+{
+    /// A valid block, its data is at the start of the buffer.
+    Data { number: u8, len: usize },
+    /// EOT, the file is complete.
+    End,
+    /// The sender aborted the transfer.
+    Cancel,
+    /// A block with a bad CRC or block number, or garbage.
+    Invalid
+}
+
+fn get_byte<U>(uart: &mut U) -> u8
+    where U: Transport
+{
+    loop
+    {
+        if let Some(byte) = uart.read_byte()
+        {
+            return byte;
+        }
+    }
+}
+
+/// Receives the next block into `buf`. Blocks until the sender sends
+/// something.
+pub fn receive_block<U>(uart: &mut U, buf: &mut [u8; BLOCK_SIZE_1K]) -> Block
+    where U: Transport
+{
+    let len = match get_byte(uart)
+    {
+        SOH => BLOCK_SIZE,
+        STX => BLOCK_SIZE_1K,
+        EOT => return Block::End,
+        CAN => return Block::Cancel,
+        _ => return Block::Invalid
+    };
+
+    let number = get_byte(uart);
+    let complement = get_byte(uart);
+    for byte in buf[..len].iter_mut()
+    {
+        *byte = get_byte(uart);
+    }
+    let received_crc = u16::from_be_bytes([get_byte(uart), get_byte(uart)]);
+
+    if number != !complement || crc::crc16_xmodem(0, &buf[..len]) != received_crc
+    {
+        return Block::Invalid;
+    }
+    Block::Data { number, len }
+}
+
+/// Decodes the YMODEM header in block 0. Yields the file name (empty at
+/// the end of a batch) and the file size, if the sender sent one.
+pub fn parse_header(data: &[u8]) -> (&[u8], Option<usize>)
+{
+    let name_len = data.iter().position(|byte| *byte == 0).unwrap_or(data.len());
+    let size = data.get(name_len + 1..).and_then(|rest| {
+        let digits = rest.iter().take_while(|byte| byte.is_ascii_digit());
+        let mut count = 0;
+        let value = digits.fold(0usize, |value, digit| { count += 1; value.saturating_mul(10).saturating_add((digit - b'0') as usize) });
+        if count > 0 { Some(value) } else { None }
+    });
+    (&data[..name_len], size)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::testhelpers::*;
+    use super::*;
+
+    #[test]
+    fn can_receive_blocks()
+    {
+        let mut uart = FakeUart::new();
+        let image = test_image();
+        let crc = crate::crc::crc16_xmodem(0, &image).to_be_bytes();
+        copy_to_uart(&mut uart, &[SOH, 1, 0xFE]);
+        copy_to_uart(&mut uart, &image);
+        copy_to_uart(&mut uart, &crc);
+        // Bad complement
+        copy_to_uart(&mut uart, &[SOH, 2, 0xFE]);
+        copy_to_uart(&mut uart, &image);
+        copy_to_uart(&mut uart, &crc);
+        copy_to_uart(&mut uart, &[EOT]);
+
+        let mut buf = [0u8; BLOCK_SIZE_1K];
+        assert_eq!(receive_block(&mut uart, &mut buf), Block::Data { number: 1, len: BLOCK_SIZE });
+        assert!(buf[..BLOCK_SIZE] == image);
+        assert_eq!(receive_block(&mut uart, &mut buf), Block::Invalid);
+        assert_eq!(receive_block(&mut uart, &mut buf), Block::End);
+    }
+
+    #[test]
+    fn can_parse_ymodem_header()
+    {
+        assert_eq!(parse_header(b"app.mub\x00350 13750674416 100644\x00\x00"), (&b"app.mub"[..], Some(350)));
+        assert_eq!(parse_header(b"app.mub\x00\x00"), (&b"app.mub"[..], None));
+        assert_eq!(parse_header(&[0u8; 128]), (&b""[..], None));
+    }
+}